@@ -1,6 +1,7 @@
 use core::ops::Deref;
 use std::iter;
 
+use anyhow::ensure;
 use plonky2::field::extension::Extendable;
 use plonky2::field::types::Field;
 use plonky2::hash::hash_types::RichField;
@@ -46,6 +47,9 @@ pub struct AllStark<F: RichField + Extendable<D>, const D: usize> {
     #[cfg(feature = "cdk_erigon")]
     pub(crate) poseidon_stark: PoseidonStark<F, D>,
     pub(crate) cross_table_lookups: Vec<CrossTableLookup<F>>,
+    /// Whether the verifier rejects proofs whose tables have different trace
+    /// cap heights, see [`check_equal_cap_heights`].
+    pub(crate) require_equal_cap_heights: bool,
 }
 
 impl<F: RichField + Extendable<D>, const D: usize> Default for AllStark<F, D> {
@@ -65,11 +69,19 @@ impl<F: RichField + Extendable<D>, const D: usize> Default for AllStark<F, D> {
             #[cfg(feature = "cdk_erigon")]
             poseidon_stark: PoseidonStark::default(),
             cross_table_lookups: all_cross_table_lookups(),
+            require_equal_cap_heights: false,
         }
     }
 }
 
 impl<F: RichField + Extendable<D>, const D: usize> AllStark<F, D> {
+    /// Sets whether proofs must have the same trace cap height for all
+    /// tables to be verified, see [`check_equal_cap_heights`].
+    pub fn with_equal_cap_heights(mut self, required: bool) -> Self {
+        self.require_equal_cap_heights = required;
+        self
+    }
+
     pub(crate) fn num_lookups_helper_columns(&self, config: &StarkConfig) -> [usize; NUM_TABLES] {
         [
             self.arithmetic_stark.num_lookup_helper_columns(config),
@@ -85,6 +97,33 @@ impl<F: RichField + Extendable<D>, const D: usize> AllStark<F, D> {
             self.poseidon_stark.num_lookup_helper_columns(config),
         ]
     }
+
+    /// Returns the number of CTL `Z` polynomials of each table, i.e. the
+    /// number of `ctl_zs_first` values its recursive proofs expose.
+    pub(crate) fn num_ctl_zs(&self, config: &StarkConfig) -> [usize; NUM_TABLES] {
+        let constraint_degrees = [
+            self.arithmetic_stark.constraint_degree(),
+            self.byte_packing_stark.constraint_degree(),
+            self.cpu_stark.constraint_degree(),
+            self.keccak_stark.constraint_degree(),
+            self.keccak_sponge_stark.constraint_degree(),
+            self.logic_stark.constraint_degree(),
+            self.memory_stark.constraint_degree(),
+            self.mem_before_stark.constraint_degree(),
+            self.mem_after_stark.constraint_degree(),
+            #[cfg(feature = "cdk_erigon")]
+            self.poseidon_stark.constraint_degree(),
+        ];
+        core::array::from_fn(|i| {
+            CrossTableLookup::num_ctl_helpers_zs_all(
+                &self.cross_table_lookups,
+                i,
+                config.num_challenges,
+                constraint_degrees[i],
+            )
+            .1
+        })
+    }
 }
 
 pub type EvmStarkFrame<T, U, const N: usize> = StarkFrame<T, U, N, 0>;
@@ -128,7 +167,7 @@ pub const NUM_TABLES: usize = if cfg!(feature = "cdk_erigon") {
 
 impl Table {
     /// Returns all STARK table indices.
-    pub(crate) const fn all() -> [Self; NUM_TABLES] {
+    pub const fn all() -> [Self; NUM_TABLES] {
         [
             Self::Arithmetic,
             Self::BytePacking,
@@ -143,6 +182,38 @@ impl Table {
             Self::Poseidon,
         ]
     }
+
+    /// Orders per-table items, e.g. table proofs received from distributed
+    /// provers as they complete, by table index.
+    ///
+    /// Fails if a table is missing or appears more than once.
+    pub fn sort_by_table<T>(
+        items: impl IntoIterator<Item = (Self, T)>,
+    ) -> anyhow::Result<[T; NUM_TABLES]> {
+        let mut slots: [Option<T>; NUM_TABLES] = core::array::from_fn(|_| None);
+        for (table, item) in items {
+            ensure!(
+                slots[*table].replace(item).is_none(),
+                "duplicate item for {:?} table",
+                table
+            );
+        }
+        let missing = Self::all()
+            .into_iter()
+            .filter(|table| slots[**table].is_none())
+            .collect::<Vec<_>>();
+        ensure!(missing.is_empty(), "missing items for tables {:?}", missing);
+        Ok(slots.map(|slot| slot.expect("all tables are present")))
+    }
+
+    /// Returns all tables ordered by `degree_bits`, so that tables of equal
+    /// size are adjacent. Tables with equal degree bits keep their index
+    /// order.
+    pub fn grouped_by_degree_bits(degree_bits: &[usize; NUM_TABLES]) -> [Self; NUM_TABLES] {
+        let mut tables = Self::all();
+        tables.sort_by_key(|table| degree_bits[**table]);
+        tables
+    }
 }
 
 /// Returns all the `CrossTableLookups` used for proving the EVM.
@@ -167,6 +238,36 @@ pub(crate) fn all_cross_table_lookups<F: Field>() -> Vec<CrossTableLookup<F>> {
     ]
 }
 
+/// Raised by [`check_equal_cap_heights`], with the cap height of each table.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("tables must have the same trace cap height, but have {tables:?}")]
+pub struct CapHeightMismatch {
+    pub tables: Vec<(Table, usize)>,
+}
+
+/// Checks that all tables have the same trace cap height, as given by e.g.
+/// [`AllProof::trace_cap_heights`](crate::proof::AllProof::trace_cap_heights),
+/// if `required` is set.
+///
+/// Observing the trace caps into the challenger works for any cap heights,
+/// but configurations whose cross-table lookup soundness argument assumes
+/// uniform caps should set `required`, which the verifier takes from
+/// [`AllStark::with_equal_cap_heights`].
+pub fn check_equal_cap_heights(
+    cap_heights: &[usize; NUM_TABLES],
+    required: bool,
+) -> Result<(), CapHeightMismatch> {
+    match !required || cap_heights.iter().all(|height| *height == cap_heights[0]) {
+        true => Ok(()),
+        false => Err(CapHeightMismatch {
+            tables: Table::all()
+                .into_iter()
+                .map(|table| (table, cap_heights[*table]))
+                .collect(),
+        }),
+    }
+}
+
 /// `CrossTableLookup` for `ArithmeticStark`, to connect it with the `Cpu`
 /// module.
 fn ctl_arithmetic<F: Field>() -> CrossTableLookup<F> {
@@ -435,3 +536,64 @@ fn ctl_poseidon_general_output<F: Field>() -> CrossTableLookup<F> {
         poseidon_stark::ctl_looked_general_output(),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sort_by_table() {
+        let mut items = Table::all().map(|table| (table, *table));
+        items.reverse();
+        items.swap(0, NUM_TABLES / 2);
+        assert_eq!(
+            Table::sort_by_table(items).unwrap(),
+            core::array::from_fn(|i| i)
+        );
+
+        let mut items = Table::all().map(|table| (table, *table));
+        items[Table::Cpu as usize].0 = Table::Logic;
+        let e = Table::sort_by_table(items).unwrap_err().to_string();
+        assert!(e.contains("duplicate item for Logic table"), "{e}");
+
+        let items = Table::all()
+            .into_iter()
+            .filter(|table| *table != Table::Memory)
+            .map(|table| (table, *table));
+        let e = Table::sort_by_table(items).unwrap_err().to_string();
+        assert!(e.contains("missing items for tables [Memory]"), "{e}");
+    }
+
+    #[test]
+    fn grouped_by_degree_bits() {
+        let mut degree_bits = [12; NUM_TABLES];
+        degree_bits[*Table::Cpu] = 10;
+        degree_bits[*Table::Memory] = 14;
+        degree_bits[*Table::Arithmetic] = 14;
+
+        let order = Table::grouped_by_degree_bits(&degree_bits);
+        assert_eq!(order[0], Table::Cpu);
+        assert_eq!(&order[NUM_TABLES - 2..], [Table::Arithmetic, Table::Memory]);
+        assert!(order
+            .windows(2)
+            .all(|w| degree_bits[*w[0]] <= degree_bits[*w[1]]));
+        Table::sort_by_table(order.map(|table| (table, ()))).unwrap();
+
+        assert_eq!(
+            Table::grouped_by_degree_bits(&[0; NUM_TABLES]),
+            Table::all()
+        );
+    }
+
+    #[test]
+    fn equal_cap_heights() {
+        let mut cap_heights = [4; NUM_TABLES];
+        check_equal_cap_heights(&cap_heights, true).unwrap();
+
+        cap_heights[*Table::Keccak] = 2;
+        let e = check_equal_cap_heights(&cap_heights, true).unwrap_err();
+        assert!(e.tables.contains(&(Table::Keccak, 2)));
+        assert!(e.tables.contains(&(Table::Cpu, 4)));
+        check_equal_cap_heights(&cap_heights, false).unwrap();
+    }
+}
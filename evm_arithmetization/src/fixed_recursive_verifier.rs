@@ -1,15 +1,17 @@
+use core::iter;
 use core::mem::{self, MaybeUninit};
 use core::ops::Range;
 use std::collections::BTreeMap;
+use std::path::Path;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 
-use anyhow::anyhow;
+use anyhow::{anyhow, ensure, Context as _};
 use hashbrown::HashMap;
 use itertools::{zip_eq, Itertools};
 use mpt_trie::partial_trie::{HashedPartialTrie, Node, PartialTrie};
 use plonky2::field::extension::Extendable;
-use plonky2::fri::FriParams;
+use plonky2::fri::{FriConfig, FriParams};
 use plonky2::gates::constant::ConstantGate;
 use plonky2::gates::noop::NoopGate;
 use plonky2::hash::hash_types::{MerkleCapTarget, RichField, NUM_HASH_OUT_ELTS};
@@ -20,7 +22,7 @@ use plonky2::plonk::circuit_builder::CircuitBuilder;
 use plonky2::plonk::circuit_data::{
     CircuitConfig, CircuitData, CommonCircuitData, VerifierCircuitData, VerifierCircuitTarget,
 };
-use plonky2::plonk::config::{AlgebraicHasher, GenericConfig, GenericHashOut};
+use plonky2::plonk::config::{AlgebraicHasher, GenericConfig, GenericHashOut, Hasher};
 use plonky2::plonk::proof::{ProofWithPublicInputs, ProofWithPublicInputsTarget};
 use plonky2::recursion::cyclic_recursion::check_cyclic_proof_verifier_data;
 use plonky2::recursion::dummy_circuit::cyclic_base_proof;
@@ -41,17 +43,17 @@ use crate::generation::segments::{GenerationSegmentData, SegmentDataIterator};
 use crate::generation::{GenerationInputs, TrimmedGenerationInputs};
 use crate::get_challenges::observe_public_values_target;
 use crate::proof::{
-    AllProof, BlockHashesTarget, BlockMetadataTarget, BurnAddrTarget, ExtraBlockData,
-    ExtraBlockDataTarget, FinalPublicValues, FinalPublicValuesTarget, MemCapTarget, PublicValues,
-    PublicValuesTarget, RegistersDataTarget, TrieRoots, TrieRootsTarget, DEFAULT_CAP_LEN,
-    TARGET_HASH_SIZE,
+    AggregatedPublicValues, AllProof, BlockHashesTarget, BlockMetadataTarget, BurnAddrTarget,
+    ExtraBlockData, ExtraBlockDataTarget, FinalPublicValues, FinalPublicValuesTarget, MemCapTarget,
+    PublicValues, PublicValuesTarget, RegistersDataTarget, TrieRoots, TrieRootsTarget,
+    DEFAULT_CAP_LEN, TARGET_HASH_SIZE,
 };
 use crate::prover::{check_abort_signal, features_check, prove};
 use crate::recursive_verifier::{
     add_common_recursion_gates, add_virtual_final_public_values_public_input,
     add_virtual_public_values_public_input, get_memory_extra_looking_sum_circuit,
     recursive_stark_circuit, set_final_public_value_targets, set_public_value_targets,
-    PlonkWrapperCircuit, PublicInputs, StarkWrapperCircuit,
+    PlonkWrapperCircuit, PublicInputs, RecursionOptions, StarkWrapperCircuit,
 };
 use crate::util::h256_limbs;
 use crate::verifier::initial_memory_merkle_cap;
@@ -72,6 +74,23 @@ where
     pub public_values: PublicValues<F>,
 }
 
+/// Optional constraints on the public values of block proofs, see
+/// [`AllRecursiveCircuits::new_with_block_constraints`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BlockConstraints {
+    /// If `Some`, constrains `block_chain_id` to equal it, so that block
+    /// proofs for one network cannot be passed off as proofs for another.
+    pub expected_chain_id: Option<u64>,
+    /// If `true`, constrains the state root after the block to be non-zero.
+    ///
+    /// Even an empty state trie has a non-zero hash, so an all-zero state
+    /// root is almost always a sign of a degenerate proof.
+    pub non_zero_state_root: bool,
+    /// If `true`, doesn't constrain each block's timestamp to be at least its
+    /// parent's, for test chains whose timestamps aren't ordered.
+    pub unordered_timestamps: bool,
+}
+
 /// Contains all recursive circuits used in the system. For each STARK and each
 /// initial `degree_bits`, this contains a chain of recursive circuits for
 /// shrinking that STARK from `degree_bits` to a constant
@@ -395,6 +414,9 @@ where
     agg_root_proof: ProofWithPublicInputsTarget<D>,
     public_values: PublicValuesTarget,
     cyclic_vk: VerifierCircuitTarget,
+    /// The chain id pinned by [`BlockConstraints::expected_chain_id`], if
+    /// any, so that mismatching blocks are rejected before proving.
+    expected_chain_id: Option<u64>,
 }
 
 impl<F, C, const D: usize> BlockCircuitData<F, C, D>
@@ -414,6 +436,12 @@ where
         buffer.write_target_proof_with_public_inputs(&self.agg_root_proof)?;
         self.public_values.to_buffer(buffer)?;
         buffer.write_target_verifier_circuit(&self.cyclic_vk)?;
+        // Chain ids are `u64`s, which may not fit in a `usize`, so they are
+        // written as two 32-bit limbs.
+        let expected_chain_id = self.expected_chain_id.unwrap_or_default();
+        buffer.write_bool(self.expected_chain_id.is_some())?;
+        buffer.write_u32(expected_chain_id as u32)?;
+        buffer.write_u32((expected_chain_id >> 32) as u32)?;
         Ok(())
     }
 
@@ -428,6 +456,10 @@ where
         let agg_root_proof = buffer.read_target_proof_with_public_inputs()?;
         let public_values = PublicValuesTarget::from_buffer(buffer)?;
         let cyclic_vk = buffer.read_target_verifier_circuit()?;
+        let has_expected_chain_id = buffer.read_bool()?;
+        let low = buffer.read_u32()? as u64;
+        let high = buffer.read_u32()? as u64;
+        let expected_chain_id = (high << 32) | low;
         Ok(Self {
             circuit,
             has_parent_block,
@@ -435,6 +467,7 @@ where
             agg_root_proof,
             public_values,
             cyclic_vk,
+            expected_chain_id: has_expected_chain_id.then_some(expected_chain_id),
         })
     }
 }
@@ -539,6 +572,286 @@ where
     }
 }
 
+/// Data for a circuit merging two proofs covering adjacent block ranges into a
+/// single proof of the whole range. Each proof can either be a block proof or a
+/// proof of this circuit, so that ranges can be merged recursively.
+///
+/// The public inputs of the merged proof follow the layout of block proofs: its
+/// `trie_roots_before`, checkpoint and first block number are those of the
+/// first range, and all other values are those of the second one.
+#[derive(Eq, PartialEq, Debug)]
+pub struct BlockRangeMergeCircuitData<F, C, const D: usize>
+where
+    F: RichField + Extendable<D>,
+    C: GenericConfig<D, F = F>,
+{
+    pub circuit: CircuitData<F, C, D>,
+    lhs: AggregationChildTarget<D>,
+    rhs: AggregationChildTarget<D>,
+    cyclic_vk: VerifierCircuitTarget,
+}
+
+impl<F, C, const D: usize> BlockRangeMergeCircuitData<F, C, D>
+where
+    F: RichField + Extendable<D>,
+    C: GenericConfig<D, F = F> + 'static,
+    C::Hasher: AlgebraicHasher<F>,
+{
+    /// Builds a circuit verifying two proofs of either `block_circuit` or
+    /// itself.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this circuit can't share the common data of `block_circuit`,
+    /// e.g. if the latter has too few gates to verify two of its own proofs.
+    pub fn new(block_circuit: &CircuitData<F, C, D>) -> Self {
+        let mut builder = CircuitBuilder::<F, D>::new(block_circuit.common.config.clone());
+        let public_values = add_virtual_public_values_public_input(&mut builder);
+        let first_block_number = builder.add_virtual_public_input();
+        let cyclic_vk = builder.add_verifier_data_public_inputs();
+        assert_eq!(
+            builder.num_public_inputs(),
+            block_circuit.common.num_public_inputs,
+            "block proofs must have the public inputs of merged proofs"
+        );
+
+        let lhs = AllRecursiveCircuits::<F, C, D>::add_agg_child(&mut builder, block_circuit);
+        let rhs = AllRecursiveCircuits::<F, C, D>::add_agg_child(&mut builder, block_circuit);
+
+        let lhs_public_inputs = lhs.public_inputs(&mut builder);
+        let rhs_public_inputs = rhs.public_inputs(&mut builder);
+        let lhs_pv = PublicValuesTarget::from_public_inputs(&lhs_public_inputs);
+        let rhs_pv = PublicValuesTarget::from_public_inputs(&rhs_public_inputs);
+
+        // Connect the merged `public_values` with those of the two ranges.
+        TrieRootsTarget::connect(
+            &mut builder,
+            public_values.trie_roots_before,
+            lhs_pv.trie_roots_before,
+        );
+        TrieRootsTarget::connect(
+            &mut builder,
+            public_values.trie_roots_after,
+            rhs_pv.trie_roots_after,
+        );
+        #[cfg(feature = "cdk_erigon")]
+        {
+            BurnAddrTarget::connect(
+                &mut builder,
+                lhs_pv.burn_addr.clone(),
+                rhs_pv.burn_addr.clone(),
+            );
+            BurnAddrTarget::connect(
+                &mut builder,
+                public_values.burn_addr.clone(),
+                rhs_pv.burn_addr.clone(),
+            );
+        }
+        BlockMetadataTarget::connect(
+            &mut builder,
+            public_values.block_metadata,
+            rhs_pv.block_metadata,
+        );
+        BlockHashesTarget::connect(
+            &mut builder,
+            public_values.block_hashes,
+            rhs_pv.block_hashes,
+        );
+        Self::connect_extra_block_data(
+            &mut builder,
+            &public_values.extra_block_data,
+            &lhs_pv,
+            &rhs_pv,
+        );
+        RegistersDataTarget::connect(
+            &mut builder,
+            public_values.registers_before.clone(),
+            rhs_pv.registers_before.clone(),
+        );
+        RegistersDataTarget::connect(
+            &mut builder,
+            public_values.registers_after.clone(),
+            rhs_pv.registers_after.clone(),
+        );
+        MemCapTarget::connect(
+            &mut builder,
+            public_values.mem_before.clone(),
+            rhs_pv.mem_before.clone(),
+        );
+        MemCapTarget::connect(
+            &mut builder,
+            public_values.mem_after.clone(),
+            rhs_pv.mem_after.clone(),
+        );
+        builder.connect(
+            first_block_number,
+            lhs_public_inputs[PublicValuesTarget::SIZE],
+        );
+
+        // Connect lhs `trie_roots_after` with rhs `trie_roots_before`. As between
+        // blocks, we only connect state tries.
+        for (&limb0, limb1) in lhs_pv
+            .trie_roots_after
+            .state_root
+            .iter()
+            .zip(rhs_pv.trie_roots_before.state_root)
+        {
+            builder.connect(limb0, limb1);
+        }
+
+        builder.connect(
+            lhs_pv.block_metadata.block_chain_id,
+            rhs_pv.block_metadata.block_chain_id,
+        );
+
+        // The rhs range must start right after the lhs one.
+        let one = builder.one();
+        let next_block_number = builder.add(lhs_pv.block_metadata.block_number, one);
+        builder.connect(
+            rhs_public_inputs[PublicValuesTarget::SIZE],
+            next_block_number,
+        );
+
+        AllRecursiveCircuits::<F, C, D>::connect_block_range_hashes(&mut builder, &lhs_pv, &rhs_pv);
+
+        // Cyclic verification requires this circuit to have the exact common data
+        // of the block circuit, so use its gates and pad to its degree.
+        for gate in &block_circuit.common.gates {
+            builder.add_gate_to_gate_set(gate.clone());
+        }
+        assert!(
+            log2_ceil(builder.num_gates()) <= block_circuit.common.degree_bits(),
+            "the block range merge circuit needs {} gates, more than the 2^{} of the block circuit",
+            builder.num_gates(),
+            block_circuit.common.degree_bits(),
+        );
+        while log2_ceil(builder.num_gates()) < block_circuit.common.degree_bits() {
+            builder.add_gate(NoopGate, vec![]);
+        }
+
+        let circuit = builder.build::<C>();
+        Self {
+            circuit,
+            lhs,
+            rhs,
+            cyclic_vk,
+        }
+    }
+
+    /// Connects the merged `extra_block_data`. The merged range keeps the
+    /// checkpoint of the lhs one, while the rhs range must have the state
+    /// reached by the lhs range as checkpoint, which is the case of block
+    /// ranges starting without a parent block.
+    fn connect_extra_block_data(
+        builder: &mut CircuitBuilder<F, D>,
+        pvs: &ExtraBlockDataTarget,
+        lhs: &PublicValuesTarget,
+        rhs: &PublicValuesTarget,
+    ) {
+        for (&limb0, &limb1) in pvs
+            .checkpoint_state_trie_root
+            .iter()
+            .zip(&lhs.extra_block_data.checkpoint_state_trie_root)
+        {
+            builder.connect(limb0, limb1);
+        }
+        for (&limb0, &limb1) in pvs
+            .checkpoint_consolidated_hash
+            .iter()
+            .zip(&lhs.extra_block_data.checkpoint_consolidated_hash)
+        {
+            builder.connect(limb0, limb1);
+        }
+        for (&limb0, &limb1) in rhs
+            .extra_block_data
+            .checkpoint_state_trie_root
+            .iter()
+            .zip(&lhs.trie_roots_after.state_root)
+        {
+            builder.connect(limb0, limb1);
+        }
+
+        builder.connect(
+            pvs.txn_number_before,
+            rhs.extra_block_data.txn_number_before,
+        );
+        builder.connect(pvs.txn_number_after, rhs.extra_block_data.txn_number_after);
+        builder.connect(pvs.gas_used_before, rhs.extra_block_data.gas_used_before);
+        builder.connect(pvs.gas_used_after, rhs.extra_block_data.gas_used_after);
+    }
+
+    /// Merges the proofs of two adjacent block ranges, `rhs` starting from
+    /// the state and the block `lhs` ends with. Each proof can either be a
+    /// block proof or a proof generated with this method.
+    ///
+    /// # Outputs
+    ///
+    /// Returns a proof whose public inputs expose the boundaries of the
+    /// merged range, see [`AggregatedPublicValues`].
+    pub fn merge_adjacent(
+        &self,
+        lhs: &ProofWithPublicInputs<F, C, D>,
+        rhs: &ProofWithPublicInputs<F, C, D>,
+    ) -> anyhow::Result<ProofWithPublicInputs<F, C, D>> {
+        for (side, proof) in [("lhs", lhs), ("rhs", rhs)] {
+            ensure!(
+                proof.public_inputs.len() == self.circuit.common.num_public_inputs,
+                "{side} proof has {} public inputs, but block proofs have {}",
+                proof.public_inputs.len(),
+                self.circuit.common.num_public_inputs,
+            );
+        }
+        let lhs_pv = AggregatedPublicValues::from_public_inputs(&lhs.public_inputs);
+        let rhs_pv = AggregatedPublicValues::from_public_inputs(&rhs.public_inputs);
+        ensure!(
+            lhs_pv.trie_roots_after.state_root == rhs_pv.trie_roots_before.state_root,
+            "block range ending at block {} is not followed by the range ending at block {}: \
+             state root mismatch",
+            lhs_pv.block_number,
+            rhs_pv.block_number,
+        );
+        ensure!(
+            lhs_pv.block_number + 1 == rhs_pv.first_block_number,
+            "block range starting at block {} cannot follow the range ending at block {}",
+            rhs_pv.first_block_number,
+            lhs_pv.block_number,
+        );
+
+        let mut witness = PartialWitness::new();
+        AllRecursiveCircuits::<F, C, D>::set_dummy_if_necessary(
+            &self.lhs,
+            self.is_merged(lhs),
+            &self.circuit,
+            &mut witness,
+            lhs,
+        );
+        AllRecursiveCircuits::<F, C, D>::set_dummy_if_necessary(
+            &self.rhs,
+            self.is_merged(rhs),
+            &self.circuit,
+            &mut witness,
+            rhs,
+        );
+        witness.set_verifier_data_target(&self.cyclic_vk, &self.circuit.verifier_only);
+
+        self.circuit.prove(witness)
+    }
+
+    /// Whether `proof` was generated with [`Self::merge_adjacent`] rather than
+    /// by the block circuit.
+    fn is_merged(&self, proof: &ProofWithPublicInputs<F, C, D>) -> bool {
+        check_cyclic_proof_verifier_data(proof, &self.circuit.verifier_only, &self.circuit.common)
+            .is_ok()
+    }
+
+    /// Verifies a proof generated with [`Self::merge_adjacent`].
+    pub fn verify(&self, proof: &ProofWithPublicInputs<F, C, D>) -> anyhow::Result<()> {
+        check_circuit_config(proof, &self.circuit.common)?;
+        self.circuit.verify(proof.clone())?;
+        check_cyclic_proof_verifier_data(proof, &self.circuit.verifier_only, &self.circuit.common)
+    }
+}
+
 impl<F, C, const D: usize> AllRecursiveCircuits<F, C, D>
 where
     F: RichField + Extendable<D>,
@@ -693,6 +1006,70 @@ where
         all_stark: &AllStark<F, D>,
         degree_bits_ranges: &[Range<usize>; NUM_TABLES],
         stark_config: &StarkConfig,
+    ) -> Self {
+        Self::new_with_block_constraints(
+            all_stark,
+            degree_bits_ranges,
+            stark_config,
+            BlockConstraints::default(),
+        )
+    }
+
+    /// Like [`AllRecursiveCircuits::new`], but the block circuit additionally
+    /// enforces the given [`BlockConstraints`] on the public values of block
+    /// proofs.
+    pub fn new_with_block_constraints(
+        all_stark: &AllStark<F, D>,
+        degree_bits_ranges: &[Range<usize>; NUM_TABLES],
+        stark_config: &StarkConfig,
+        block_constraints: BlockConstraints,
+    ) -> Self {
+        Self::new_with_table_order(
+            all_stark,
+            degree_bits_ranges,
+            stark_config,
+            block_constraints,
+            None,
+        )
+    }
+
+    /// Like [`AllRecursiveCircuits::new_with_block_constraints`], but the root
+    /// circuit verifies the table proofs in the given `table_order`, which
+    /// must list every table exactly once.
+    ///
+    /// The order doesn't change which proofs are accepted. If `None`, tables
+    /// are grouped by the degree bits of their final recursion circuits, as
+    /// given by [`Table::grouped_by_degree_bits`], so that proofs of the same
+    /// shape are verified one after the other.
+    pub fn new_with_table_order(
+        all_stark: &AllStark<F, D>,
+        degree_bits_ranges: &[Range<usize>; NUM_TABLES],
+        stark_config: &StarkConfig,
+        block_constraints: BlockConstraints,
+        table_order: Option<[Table; NUM_TABLES]>,
+    ) -> Self {
+        Self::new_with_recursion_options(
+            all_stark,
+            degree_bits_ranges,
+            stark_config,
+            block_constraints,
+            table_order,
+            RecursionOptions::default(),
+        )
+    }
+
+    /// Like [`AllRecursiveCircuits::new_with_table_order`], but the recursive
+    /// circuits of each table are built with the given [`RecursionOptions`].
+    ///
+    /// A `cap_height` override must be valid for circuits of degree
+    /// `2^THRESHOLD_DEGREE_BITS`, the smallest the shrinking chains go.
+    pub fn new_with_recursion_options(
+        all_stark: &AllStark<F, D>,
+        degree_bits_ranges: &[Range<usize>; NUM_TABLES],
+        stark_config: &StarkConfig,
+        block_constraints: BlockConstraints,
+        table_order: Option<[Table; NUM_TABLES]>,
+        recursion_options: RecursionOptions,
     ) -> Self {
         // Sanity check on the provided config
         assert_eq!(DEFAULT_CAP_LEN, 1 << stark_config.fri_config.cap_height);
@@ -705,6 +1082,7 @@ where
                     degree_bits_ranges[*$table_enum].clone(),
                     &all_stark.cross_table_lookups,
                     stark_config,
+                    recursion_options,
                 )
             };
         }
@@ -736,11 +1114,28 @@ where
             poseidon,
         ];
 
-        let root = Self::create_segment_circuit(&by_table, stark_config);
+        let table_order = table_order.unwrap_or_else(|| {
+            Table::grouped_by_degree_bits(
+                &by_table
+                    .each_ref()
+                    .map(|table| table.final_circuits()[0].common.degree_bits()),
+            )
+        });
+        if let Err(e) = Table::sort_by_table(table_order.map(|table| (table, ()))) {
+            panic!("invalid table order: {e}");
+        }
+
+        let root = Self::create_segment_circuit(
+            &by_table,
+            stark_config,
+            recursion_options.with_trace_cap_hash,
+            &all_stark.num_ctl_zs(stark_config),
+            &table_order,
+        );
         let segment_aggregation = Self::create_segment_aggregation_circuit(&root);
         let txn_aggregation =
             Self::create_txn_aggregation_circuit(&segment_aggregation, stark_config);
-        let block = Self::create_block_circuit(&txn_aggregation);
+        let block = Self::create_block_circuit(&txn_aggregation, block_constraints);
         let block_wrapper = Self::create_block_wrapper_circuit(&block);
         let two_to_one_block = Self::create_two_to_one_block_circuit(&block_wrapper);
 
@@ -778,6 +1173,9 @@ where
     fn create_segment_circuit(
         by_table: &[RecursiveCircuitsForTable<F, C, D>; NUM_TABLES],
         stark_config: &StarkConfig,
+        with_trace_cap_hash: bool,
+        num_ctl_zs: &[usize; NUM_TABLES],
+        table_order: &[Table; NUM_TABLES],
     ) -> RootCircuitData<F, C, D> {
         let inner_common_data: [_; NUM_TABLES] =
             core::array::from_fn(|i| &by_table[i].final_circuits()[0].common);
@@ -792,6 +1190,8 @@ where
             PublicInputs::<Target, <C::Hasher as AlgebraicHasher<F>>::AlgebraicPermutation>::from_vec(
                 &recursive_proofs[i].public_inputs,
                 stark_config,
+                with_trace_cap_hash,
+                num_ctl_zs[i],
             )
         });
         let index_verifier_data = core::array::from_fn(|_i| builder.add_virtual_target());
@@ -803,6 +1203,8 @@ where
             }
         }
 
+        // Like the host-side prover, bind the public values into the CTL
+        // challenges, and through the challenger states into every table proof.
         observe_public_values_target::<F, C, D>(&mut challenger, &public_values);
 
         let ctl_challenges = get_grand_product_challenge_set_target(
@@ -812,16 +1214,7 @@ where
         );
         // Check that the correct CTL challenges are used in every proof.
         for pi in &pis {
-            for i in 0..stark_config.num_challenges {
-                builder.connect(
-                    ctl_challenges.challenges[i].beta,
-                    pi.ctl_challenges.challenges[i].beta,
-                );
-                builder.connect(
-                    ctl_challenges.challenges[i].gamma,
-                    pi.ctl_challenges.challenges[i].gamma,
-                );
-            }
+            Self::connect_ctl_challenges(&mut builder, &ctl_challenges, &pi.ctl_challenges);
         }
 
         let state = challenger.compact(&mut builder);
@@ -863,8 +1256,9 @@ where
             stark_config,
         );
 
-        for (i, table_circuits) in by_table.iter().enumerate() {
-            let final_circuits = table_circuits.final_circuits();
+        for &table in table_order {
+            let i = *table;
+            let final_circuits = by_table[i].final_circuits();
             for final_circuit in &final_circuits {
                 assert_eq!(
                     &final_circuit.common, inner_common_data[i],
@@ -1284,15 +1678,20 @@ where
         builder.connect(x.registers_before.program_counter, main_label);
     }
 
-    fn create_block_circuit(agg: &TxnAggregationCircuitData<F, C, D>) -> BlockCircuitData<F, C, D> {
+    fn create_block_circuit(
+        agg: &TxnAggregationCircuitData<F, C, D>,
+        block_constraints: BlockConstraints,
+    ) -> BlockCircuitData<F, C, D> {
         // Here, we have two block proofs and we aggregate them together.
         // The block circuit is similar to the agg circuit; both verify two inner
-        // proofs.
+        // proofs. On top of the agg public inputs, block proofs expose the first
+        // block number of their range.
         let expected_common_data = CommonCircuitData {
             fri_params: FriParams {
                 degree_bits: 14,
                 ..agg.circuit.common.fri_params.clone()
             },
+            num_public_inputs: agg.circuit.common.num_public_inputs + 1,
             ..agg.circuit.common.clone()
         };
 
@@ -1333,12 +1732,31 @@ where
             agg_pv.extra_block_data,
         );
 
-        // Check that the paent block's timestamp is less than the current block's.
-        Self::check_block_timestamp(
-            &mut builder,
-            parent_pv.block_metadata.block_timestamp,
-            agg_pv.block_metadata.block_timestamp,
-        );
+        // Pin the chain id, if required.
+        if let Some(expected_chain_id) = block_constraints.expected_chain_id {
+            let expected_chain_id = builder.constant(F::from_canonical_u64(expected_chain_id));
+            builder.connect(
+                public_values.block_metadata.block_chain_id,
+                expected_chain_id,
+            );
+        }
+
+        // Only the lowest 32 bits of each limb would make it into the
+        // beneficiary address, so don't let the limbs encode more than that.
+        Self::check_beneficiary_limbs(&mut builder, public_values.block_metadata.block_beneficiary);
+
+        if block_constraints.non_zero_state_root {
+            Self::check_non_zero_hash(&mut builder, public_values.trie_roots_after.state_root);
+        }
+
+        // Check that the parent block's timestamp is at most the current block's.
+        if !block_constraints.unordered_timestamps {
+            Self::check_block_timestamp(
+                &mut builder,
+                parent_pv.block_metadata.block_timestamp,
+                agg_pv.block_metadata.block_timestamp,
+            );
+        }
 
         // Connect the burn address targets.
         #[cfg(feature = "cdk_erigon")]
@@ -1359,6 +1777,16 @@ where
         // values.
         Self::connect_block_proof(&mut builder, has_parent_block, &parent_pv, &agg_pv);
 
+        // Expose the first block of the range, after the public values and before
+        // the cyclic verifier data. It is inherited from the parent proof, if any.
+        let parent_first_block_number = parent_block_proof.public_inputs[PublicValuesTarget::SIZE];
+        let first_block_number = builder.select(
+            has_parent_block,
+            parent_first_block_number,
+            agg_pv.block_metadata.block_number,
+        );
+        builder.register_public_input(first_block_number);
+
         let cyclic_vk = builder.add_verifier_data_public_inputs();
         builder
             .conditionally_verify_cyclic_proof_or_dummy::<C>(
@@ -1379,6 +1807,37 @@ where
             agg_root_proof,
             public_values,
             cyclic_vk,
+            expected_chain_id: block_constraints.expected_chain_id,
+        }
+    }
+
+    /// Checks that the hash given by `limbs` is not all zeroes, which is
+    /// almost always a sign of a degenerate proof.
+    fn check_non_zero_hash(builder: &mut CircuitBuilder<F, D>, limbs: [Target; TARGET_HASH_SIZE]) {
+        // The limbs are 32-bit, so their sum can't wrap around to zero.
+        let sum = builder.add_many(limbs);
+        builder.inverse(sum);
+    }
+
+    /// Binds the CTL challenges used by an inner proof to the ones derived
+    /// by the aggregating circuit, so that table proofs generated with
+    /// inconsistent challenges can't be aggregated together.
+    fn connect_ctl_challenges(
+        builder: &mut CircuitBuilder<F, D>,
+        expected: &GrandProductChallengeSet<Target>,
+        actual: &GrandProductChallengeSet<Target>,
+    ) {
+        for (expected, actual) in zip_eq(&expected.challenges, &actual.challenges) {
+            builder.connect(expected.beta, actual.beta);
+            builder.connect(expected.gamma, actual.gamma);
+        }
+    }
+
+    /// Range-checks the limbs of the block beneficiary, so that they encode a
+    /// 20-byte address.
+    fn check_beneficiary_limbs(builder: &mut CircuitBuilder<F, D>, limbs: [Target; 5]) {
+        for limb in limbs {
+            builder.range_check(limb, 32);
         }
     }
 
@@ -1660,6 +2119,58 @@ where
         }
     }
 
+    /// Connect the block hashes of two adjacent block ranges: the last block of
+    /// the lhs range must be among the 256 previous blocks of the last block of
+    /// the rhs range, unless the rhs range spans more than 256 blocks. If the
+    /// rhs range is a single block, this matches
+    /// [`Self::connect_block_hashes`].
+    fn connect_block_range_hashes(
+        builder: &mut CircuitBuilder<F, D>,
+        lhs_public_values: &PublicValuesTarget,
+        rhs_public_values: &PublicValuesTarget,
+    ) {
+        let block_gap = builder.sub(
+            rhs_public_values.block_metadata.block_number,
+            lhs_public_values.block_metadata.block_number,
+        );
+
+        // Select the hash of the lhs last block among the rhs previous hashes.
+        let mut is_in_window = builder.zero();
+        let mut prev_block_hash = [builder.zero(); TARGET_HASH_SIZE];
+        for gap in 1..=256 {
+            let gap_target = builder.constant(F::from_canonical_usize(gap));
+            let is_gap = builder.is_equal(block_gap, gap_target);
+            is_in_window = builder.add(is_in_window, is_gap.target);
+            let offset = 8 * (256 - gap);
+            for (i, limb) in prev_block_hash.iter_mut().enumerate() {
+                *limb = builder.mul_add(
+                    is_gap.target,
+                    rhs_public_values.block_hashes.prev_hashes[offset + i],
+                    *limb,
+                );
+            }
+
+            // A single block rhs range also shifts the lhs previous hashes.
+            if gap == 1 {
+                for i in 0..255 {
+                    for j in 0..TARGET_HASH_SIZE {
+                        builder.conditional_assert_eq(
+                            is_gap.target,
+                            lhs_public_values.block_hashes.prev_hashes[8 * (i + 1) + j],
+                            rhs_public_values.block_hashes.prev_hashes[8 * i + j],
+                        );
+                    }
+                }
+            }
+        }
+
+        let expected_hash = lhs_public_values.block_hashes.cur_hash;
+        for i in 0..TARGET_HASH_SIZE {
+            let expected_limb = builder.mul(is_in_window, expected_hash[i]);
+            builder.connect(expected_limb, prev_block_hash[i]);
+        }
+    }
+
     fn connect_block_proof(
         builder: &mut CircuitBuilder<F, D>,
         has_parent_block: BoolTarget,
@@ -1805,6 +2316,55 @@ where
         segment_data: &mut GenerationSegmentData,
         timing: &mut TimingTree,
         abort_signal: Option<Arc<AtomicBool>>,
+    ) -> anyhow::Result<ProverOutputData<F, C, D>> {
+        self.prove_segment_with_proof_dir(
+            all_stark,
+            config,
+            generation_inputs,
+            segment_data,
+            None,
+            timing,
+            abort_signal,
+        )
+    }
+
+    /// Like [`AllRecursiveCircuits::prove_segment`], but persists the shrunk
+    /// proof of each table to `proof_dir` as soon as it is generated.
+    ///
+    /// Tables whose shrunk proof already exists in `proof_dir`, e.g. from a
+    /// previous run which was interrupted, are not proven again: their proof
+    /// is read back and verified instead. As proof files are named after their
+    /// table, each segment should be given its own `proof_dir`.
+    pub fn prove_segment_resumable(
+        &self,
+        all_stark: &AllStark<F, D>,
+        config: &StarkConfig,
+        generation_inputs: TrimmedGenerationInputs<F>,
+        segment_data: &mut GenerationSegmentData,
+        proof_dir: &Path,
+        timing: &mut TimingTree,
+        abort_signal: Option<Arc<AtomicBool>>,
+    ) -> anyhow::Result<ProverOutputData<F, C, D>> {
+        self.prove_segment_with_proof_dir(
+            all_stark,
+            config,
+            generation_inputs,
+            segment_data,
+            Some(proof_dir),
+            timing,
+            abort_signal,
+        )
+    }
+
+    fn prove_segment_with_proof_dir(
+        &self,
+        all_stark: &AllStark<F, D>,
+        config: &StarkConfig,
+        generation_inputs: TrimmedGenerationInputs<F>,
+        segment_data: &mut GenerationSegmentData,
+        proof_dir: Option<&Path>,
+        timing: &mut TimingTree,
+        abort_signal: Option<Arc<AtomicBool>>,
     ) -> anyhow::Result<ProverOutputData<F, C, D>> {
         features_check(&generation_inputs);
 
@@ -1817,12 +2377,12 @@ where
             abort_signal.clone(),
         )?;
         let mut root_inputs = PartialWitness::new();
+        let degree_bits = all_proof.degree_bits(config);
 
-        for table in 0..NUM_TABLES {
+        for (table, &original_degree_bits) in degree_bits.iter().enumerate() {
             let stark_proof = &all_proof.multi_proof.stark_proofs[table];
-            let original_degree_bits = stark_proof.proof.recover_degree_bits(config);
             let table_circuits = &self.by_table[table];
-            let shrunk_proof = table_circuits
+            let table_circuit = table_circuits
                 .by_stark_size
                 .get(&original_degree_bits)
                 .ok_or_else(|| {
@@ -1831,8 +2391,15 @@ where
                         Table::all()[table],
                         original_degree_bits,
                     ))
-                })?
-                .shrink(stark_proof, &all_proof.multi_proof.ctl_challenges)?;
+                })?;
+            let shrunk_proof = match proof_dir {
+                Some(proof_dir) => table_circuit.shrink_resumable(
+                    stark_proof,
+                    &all_proof.multi_proof.ctl_challenges,
+                    &proof_dir.join(format!("{:?}.proof", Table::all()[table])),
+                )?,
+                None => table_circuit.shrink(stark_proof, &all_proof.multi_proof.ctl_challenges)?,
+            };
             let index_verifier_data = table_circuits
                 .by_stark_size
                 .keys()
@@ -2006,6 +2573,7 @@ where
     }
 
     pub fn verify_root(&self, agg_proof: ProofWithPublicInputs<F, C, D>) -> anyhow::Result<()> {
+        check_circuit_config(&agg_proof, &self.root.circuit.common)?;
         self.root.circuit.verify(agg_proof)
     }
 
@@ -2126,6 +2694,7 @@ where
         &self,
         agg_proof: &ProofWithPublicInputs<F, C, D>,
     ) -> anyhow::Result<()> {
+        check_circuit_config(agg_proof, &self.segment_aggregation.circuit.common)?;
         self.segment_aggregation.circuit.verify(agg_proof.clone())?;
         check_cyclic_proof_verifier_data(
             agg_proof,
@@ -2215,6 +2784,7 @@ where
         &self,
         txn_proof: &ProofWithPublicInputs<F, C, D>,
     ) -> anyhow::Result<()> {
+        check_circuit_config(txn_proof, &self.txn_aggregation.circuit.common)?;
         self.txn_aggregation.circuit.verify(txn_proof.clone())?;
         check_cyclic_proof_verifier_data(
             txn_proof,
@@ -2277,6 +2847,16 @@ where
         agg_root_proof: &ProofWithPublicInputs<F, C, D>,
         public_values: PublicValues<F>,
     ) -> anyhow::Result<(ProofWithPublicInputs<F, C, D>, PublicValues<F>)> {
+        if let Some(expected_chain_id) = self.block.expected_chain_id {
+            ensure!(
+                public_values.block_metadata.block_chain_id == expected_chain_id.into(),
+                "block {} has chain id {}, but the block circuit expects chain id {}",
+                public_values.block_metadata.block_number,
+                public_values.block_metadata.block_chain_id,
+                expected_chain_id,
+            );
+        }
+
         let mut block_inputs = PartialWitness::new();
 
         block_inputs.set_bool_target(
@@ -2437,6 +3017,7 @@ where
     }
 
     pub fn verify_block(&self, block_proof: &ProofWithPublicInputs<F, C, D>) -> anyhow::Result<()> {
+        check_circuit_config(block_proof, &self.block.circuit.common)?;
         self.block.circuit.verify(block_proof.clone())?;
         check_cyclic_proof_verifier_data(
             block_proof,
@@ -2445,6 +3026,33 @@ where
         )
     }
 
+    /// Builds a [`BlockRangeMergeCircuitData`] merging block proofs of
+    /// adjacent block ranges.
+    pub fn block_range_merge_circuit(&self) -> BlockRangeMergeCircuitData<F, C, D> {
+        BlockRangeMergeCircuitData::new(&self.block.circuit)
+    }
+
+    /// Verifies a block proof as production verifiers typically do: checks
+    /// that the block circuit has the expected digest, that the proof
+    /// verifies, and that it commits to the expected public values.
+    pub fn verify_block_light(
+        &self,
+        block_proof: &ProofWithPublicInputs<F, C, D>,
+        expected_digest: &<C::Hasher as Hasher<F>>::Hash,
+        expected_public_values: &PublicValues<F>,
+    ) -> anyhow::Result<()> {
+        ensure!(
+            self.block.circuit.verifier_only.circuit_digest == *expected_digest,
+            "block circuit digest mismatch"
+        );
+        self.verify_block(block_proof)?;
+        ensure!(
+            PublicValues::from_public_inputs(&block_proof.public_inputs) == *expected_public_values,
+            "block proof public values mismatch"
+        );
+        Ok(())
+    }
+
     /// Wrap a block proof, representing one or an aggregation of contiguous
     /// blocks, for easier aggregation with other chains' proofs.
     ///
@@ -2497,6 +3105,7 @@ where
         &self,
         wrapped_block_proof: &ProofWithPublicInputs<F, C, D>,
     ) -> anyhow::Result<()> {
+        check_circuit_config(wrapped_block_proof, &self.block_wrapper.circuit.common)?;
         self.block_wrapper
             .circuit
             .verify(wrapped_block_proof.clone())
@@ -2561,6 +3170,7 @@ where
         &self,
         proof: &ProofWithPublicInputs<F, C, D>,
     ) -> anyhow::Result<()> {
+        check_circuit_config(proof, &self.two_to_one_block.circuit.common)?;
         self.two_to_one_block.circuit.verify(proof.clone())?;
         let verifier_data = &self.two_to_one_block.circuit.verifier_data();
         check_cyclic_proof_verifier_data(proof, &verifier_data.verifier_only, &verifier_data.common)
@@ -2688,6 +3298,7 @@ where
         degree_bits_range: Range<usize>,
         all_ctls: &[CrossTableLookup<F>],
         stark_config: &StarkConfig,
+        recursion_options: RecursionOptions,
     ) -> Self {
         let by_stark_size = degree_bits_range
             .map(|degree_bits| {
@@ -2699,6 +3310,7 @@ where
                         degree_bits,
                         all_ctls,
                         stark_config,
+                        recursion_options,
                     ),
                 )
             })
@@ -2810,15 +3422,17 @@ where
         degree_bits: usize,
         all_ctls: &[CrossTableLookup<F>],
         stark_config: &StarkConfig,
+        recursion_options: RecursionOptions,
     ) -> Self {
         let initial_wrapper = recursive_stark_circuit(
-            table,
+            *table,
             stark,
             degree_bits,
             all_ctls,
             stark_config,
             &shrinking_config(),
             THRESHOLD_DEGREE_BITS,
+            recursion_options,
         );
         let mut shrinking_wrappers = vec![];
 
@@ -2860,6 +3474,31 @@ where
         }
     }
 
+    /// Returns the degree bits of each plonky2 wrapper circuit of the chain,
+    /// starting with the one verifying the STARK proof. The last one is always
+    /// `THRESHOLD_DEGREE_BITS`.
+    ///
+    /// These are not the degree bits of the STARK trace this chain was built
+    /// for, which are given by [`AllProof::degree_bits`].
+    pub fn wrapper_degree_bits(&self) -> Vec<usize> {
+        iter::once(&self.initial_wrapper.circuit)
+            .chain(
+                self.shrinking_wrappers
+                    .iter()
+                    .map(|wrapper| &wrapper.circuit),
+            )
+            .map(|circuit| circuit.common.degree_bits())
+            .collect()
+    }
+
+    /// Returns the circuit producing the final shrunk proof of the chain.
+    fn final_circuit(&self) -> &CircuitData<F, C, D> {
+        self.shrinking_wrappers
+            .last()
+            .map(|wrapper| &wrapper.circuit)
+            .unwrap_or(&self.initial_wrapper.circuit)
+    }
+
     pub fn shrink(
         &self,
         stark_proof_with_metadata: &StarkProofWithMetadata<F, C, D>,
@@ -2873,19 +3512,139 @@ where
         }
         Ok(proof)
     }
+
+    /// Like [`RecursiveCircuitsForTableSize::shrink`], but reuses the shrunk
+    /// proof stored at `path` if there is one, and stores it there otherwise.
+    ///
+    /// A stored proof is only reused if it verifies and commits to the same
+    /// trace as `stark_proof_with_metadata`: otherwise, e.g. if it is
+    /// corrupted, it is proven again and overwritten. Proofs are written to a
+    /// temporary file first and then moved to `path`, so that an interrupted
+    /// write doesn't leave a truncated proof behind.
+    pub fn shrink_resumable(
+        &self,
+        stark_proof_with_metadata: &StarkProofWithMetadata<F, C, D>,
+        ctl_challenges: &GrandProductChallengeSet<F>,
+        path: &Path,
+    ) -> anyhow::Result<ProofWithPublicInputs<F, C, D>> {
+        let circuit = self.final_circuit();
+
+        if path.exists() {
+            let bytes = std::fs::read(path)
+                .with_context(|| format!("failed to read shrunk proof at {}", path.display()))?;
+            let reuse = || -> anyhow::Result<_> {
+                let proof = ProofWithPublicInputs::from_bytes(bytes, &circuit.common)
+                    .context("failed to decode shrunk proof")?;
+                // The trace cap comes first in the public inputs of the shrunk proof.
+                anyhow::ensure!(
+                    proof
+                        .public_inputs
+                        .starts_with(&stark_proof_with_metadata.proof.trace_cap.flatten()),
+                    "shrunk proof is for a different STARK proof"
+                );
+                circuit
+                    .verify(proof.clone())
+                    .context("invalid shrunk proof")?;
+                Ok(proof)
+            };
+            match reuse() {
+                Ok(proof) => return Ok(proof),
+                Err(e) => log::warn!("proving again over {}: {e:#}", path.display()),
+            }
+        }
+
+        let proof = self.shrink(stark_proof_with_metadata, ctl_challenges)?;
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, proof.to_bytes())
+            .with_context(|| format!("failed to write shrunk proof to {}", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, path)
+            .with_context(|| format!("failed to move shrunk proof to {}", path.display()))?;
+        Ok(proof)
+    }
 }
 
 /// Our usual recursion threshold is 2^12 gates, but for these shrinking
 /// circuits, we use a few more gates for a constant inner VK and for public
 /// inputs. This pushes us over the threshold to 2^13. As long as we're at 2^13
 /// gates, we might as well use a narrower witness.
-fn shrinking_config() -> CircuitConfig {
+///
+/// This is the [`CircuitConfig`] used to recursively verify STARK proofs
+/// generated with [`StarkConfig::standard_fast_config`]. It inherits the ~100
+/// bits of conjectured security of
+/// [`CircuitConfig::standard_recursion_config`].
+pub fn shrinking_config() -> CircuitConfig {
     CircuitConfig {
         num_routed_wires: 40,
         ..CircuitConfig::standard_recursion_config()
     }
 }
 
+/// Returns [`StarkConfig::standard_fast_config`] with `num_query_rounds` FRI
+/// queries instead of the default 84.
+///
+/// The recursive circuits built from the returned config expect STARK proofs
+/// with exactly this many query rounds. Fewer rounds yield smaller and faster
+/// proofs, at the cost of fewer bits of conjectured security.
+pub fn recursion_stark_config_with_query_rounds(num_query_rounds: usize) -> StarkConfig {
+    let config = StarkConfig::standard_fast_config();
+    StarkConfig {
+        fri_config: FriConfig {
+            num_query_rounds,
+            ..config.fri_config
+        },
+        ..config
+    }
+}
+
+/// Builds the initial recursion circuit of each table, for STARK proofs of
+/// the given `degree_bits`, and returns their common data without proving
+/// anything.
+///
+/// This is what external tooling, e.g. on-chain verifier generators, needs to
+/// verify the wrapped table proofs produced by [`AllRecursiveCircuits`].
+pub fn recursion_common_data<F, C, const D: usize>(
+    all_stark: &AllStark<F, D>,
+    inner_config: &StarkConfig,
+    circuit_config: &CircuitConfig,
+    degree_bits: &[usize; NUM_TABLES],
+) -> [CommonCircuitData<F, D>; NUM_TABLES]
+where
+    F: RichField + Extendable<D>,
+    C: GenericConfig<D, F = F>,
+    C::Hasher: AlgebraicHasher<F>,
+{
+    macro_rules! common_data {
+        ($stark:ident, $table:expr) => {
+            recursive_stark_circuit::<F, C, _, D>(
+                *$table,
+                &all_stark.$stark,
+                degree_bits[*$table],
+                &all_stark.cross_table_lookups,
+                inner_config,
+                circuit_config,
+                THRESHOLD_DEGREE_BITS,
+                RecursionOptions::default(),
+            )
+            .circuit
+            .common
+        };
+    }
+
+    [
+        common_data!(arithmetic_stark, Table::Arithmetic),
+        common_data!(byte_packing_stark, Table::BytePacking),
+        common_data!(cpu_stark, Table::Cpu),
+        common_data!(keccak_stark, Table::Keccak),
+        common_data!(keccak_sponge_stark, Table::KeccakSponge),
+        common_data!(logic_stark, Table::Logic),
+        common_data!(memory_stark, Table::Memory),
+        common_data!(mem_before_stark, Table::MemBefore),
+        common_data!(mem_after_stark, Table::MemAfter),
+        #[cfg(feature = "cdk_erigon")]
+        common_data!(poseidon_stark, Table::Poseidon),
+    ]
+}
+
 /// Extracts the two-to-one block aggregation hash from a public inputs slice.
 ///
 /// # Arguments
@@ -2932,6 +3691,115 @@ pub fn extract_block_final_public_values<T>(
         .expect("Public inputs vector was malformed.")
 }
 
+/// Errors raised when checking a proof against a circuit before verifying it.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum RecursiveVerificationError {
+    #[error(
+        "proof was generated with a different circuit config: \
+         its {field} is {actual}, but the verifier expects {expected}"
+    )]
+    CircuitConfigMismatch {
+        field: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+}
+
+/// Checks that `proof` has the shape implied by the [`CircuitConfig`] of
+/// `common`.
+///
+/// Verifying a proof generated with a different config would fail anyway,
+/// but with an opaque error from deep within the verifier.
+pub fn check_circuit_config<F, C, const D: usize>(
+    proof: &ProofWithPublicInputs<F, C, D>,
+    common: &CommonCircuitData<F, D>,
+) -> Result<(), RecursiveVerificationError>
+where
+    F: RichField + Extendable<D>,
+    C: GenericConfig<D, F = F>,
+{
+    let config = &common.config;
+    let proof = &proof.proof;
+    for (field, expected, actual) in [
+        (
+            "cap height",
+            config.fri_config.cap_height,
+            log2_ceil(proof.wires_cap.0.len()),
+        ),
+        (
+            "number of wires",
+            config.num_wires,
+            proof.openings.wires.len(),
+        ),
+        (
+            "number of routed wires",
+            config.num_routed_wires,
+            proof.openings.plonk_sigmas.len(),
+        ),
+        (
+            "number of challenges",
+            config.num_challenges,
+            proof.openings.plonk_zs.len(),
+        ),
+        (
+            "number of FRI query rounds",
+            config.fri_config.num_query_rounds,
+            proof.opening_proof.query_round_proofs.len(),
+        ),
+    ] {
+        if expected != actual {
+            return Err(RecursiveVerificationError::CircuitConfigMismatch {
+                field,
+                expected,
+                actual,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Returns the block number claimed in the public values of a block proof.
+pub fn block_number<F, C, const D: usize>(block_proof: &ProofWithPublicInputs<F, C, D>) -> u64
+where
+    F: RichField + Extendable<D>,
+    C: GenericConfig<D, F = F>,
+{
+    PublicValues::from_public_inputs(&block_proof.public_inputs)
+        .block_metadata
+        .block_number
+        .low_u64()
+}
+
+/// Checks that the given block proofs cover consecutive blocks, in order.
+///
+/// # Outputs
+///
+/// - The first and last block numbers of the range, inclusive.
+pub fn verify_block_range<F, C, const D: usize>(
+    block_proofs: &[ProofWithPublicInputs<F, C, D>],
+) -> anyhow::Result<(u64, u64)>
+where
+    F: RichField + Extendable<D>,
+    C: GenericConfig<D, F = F>,
+{
+    check_consecutive_block_numbers(block_proofs.iter().map(block_number))
+}
+
+fn check_consecutive_block_numbers(
+    block_numbers: impl IntoIterator<Item = u64>,
+) -> anyhow::Result<(u64, u64)> {
+    let mut block_numbers = block_numbers.into_iter();
+    let first = block_numbers.next().context("empty block range")?;
+    let last = block_numbers.try_fold(first, |prev, next| {
+        ensure!(
+            prev.checked_add(1) == Some(next),
+            "block {next} does not follow block {prev}"
+        );
+        Ok(next)
+    })?;
+    Ok((first, last))
+}
+
 /// Computes the length added to the public inputs vector by
 /// [`CircuitBuilder::add_verifier_data_public_inputs`].
 pub const fn verification_key_len<F, C, const D: usize>(circuit: &CircuitData<F, C, D>) -> usize
@@ -2943,3 +3811,318 @@ where
     circuit.verifier_only.circuit_digest.elements.len()
         + (1 << circuit.common.config.fri_config.cap_height) * NUM_HASH_OUT_ELTS
 }
+
+#[cfg(test)]
+mod tests {
+    use plonky2::field::goldilocks_field::GoldilocksField;
+    use plonky2::field::types::Field;
+    use plonky2::plonk::config::PoseidonGoldilocksConfig;
+    use plonky2::recursion::dummy_circuit::dummy_circuit;
+    use starky::lookup::GrandProductChallenge;
+
+    use super::*;
+    use crate::util::h160_from_limbs;
+
+    type F = GoldilocksField;
+    type C = PoseidonGoldilocksConfig;
+    const D: usize = 2;
+
+    fn prove_non_zero_hash(limbs: [u32; TARGET_HASH_SIZE]) {
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let targets = builder.add_virtual_target_arr::<TARGET_HASH_SIZE>();
+        AllRecursiveCircuits::<F, C, D>::check_non_zero_hash(&mut builder, targets);
+
+        let mut inputs = PartialWitness::new();
+        for (target, limb) in zip_eq(targets, limbs) {
+            inputs.set_target(target, F::from_canonical_u32(limb));
+        }
+        let data = builder.build::<C>();
+        let proof = data.prove(inputs).unwrap();
+        data.verify(proof).unwrap();
+    }
+
+    fn prove_block_timestamp(prev_timestamp: u32, timestamp: u32) {
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let [prev_target, target] = builder.add_virtual_target_arr();
+        AllRecursiveCircuits::<F, C, D>::check_block_timestamp(&mut builder, prev_target, target);
+
+        let mut inputs = PartialWitness::new();
+        inputs.set_target(prev_target, F::from_canonical_u32(prev_timestamp));
+        inputs.set_target(target, F::from_canonical_u32(timestamp));
+        let data = builder.build::<C>();
+        let proof = data.prove(inputs).unwrap();
+        data.verify(proof).unwrap();
+    }
+
+    #[test]
+    fn block_timestamp() {
+        prove_block_timestamp(1_000, 1_000);
+        prove_block_timestamp(1_000, 1_012);
+    }
+
+    #[test]
+    #[should_panic]
+    fn decreasing_block_timestamp() {
+        prove_block_timestamp(1_012, 1_000);
+    }
+
+    #[test]
+    fn circuit_config_mismatch() {
+        let build = |config| {
+            let mut builder = CircuitBuilder::<F, D>::new(config);
+            let x = builder.add_virtual_public_input();
+            let y = builder.square(x);
+            builder.register_public_input(y);
+            (builder.build::<C>(), x)
+        };
+        let (data, x) = build(CircuitConfig::standard_recursion_config());
+        let mut inputs = PartialWitness::new();
+        inputs.set_target(x, F::from_canonical_u8(3));
+        let proof = data.prove(inputs).unwrap();
+        check_circuit_config(&proof, &data.common).unwrap();
+
+        let mut other_config = CircuitConfig::standard_recursion_config();
+        other_config.fri_config.num_query_rounds = 20;
+        let (other, _) = build(other_config);
+        assert_eq!(
+            check_circuit_config(&proof, &other.common),
+            Err(RecursiveVerificationError::CircuitConfigMismatch {
+                field: "number of FRI query rounds",
+                expected: 20,
+                actual: 28,
+            })
+        );
+    }
+
+    #[test]
+    fn consecutive_block_numbers() {
+        assert_eq!(check_consecutive_block_numbers([7]).unwrap(), (7, 7));
+        assert_eq!(check_consecutive_block_numbers([7, 8, 9]).unwrap(), (7, 9));
+        let err = check_consecutive_block_numbers([7, 8, 10]).unwrap_err();
+        assert_eq!(err.to_string(), "block 10 does not follow block 8");
+        assert!(check_consecutive_block_numbers([8, 7]).is_err());
+        assert!(check_consecutive_block_numbers([]).is_err());
+    }
+
+    /// The common data of a recursive circuit of the block circuit's degree,
+    /// with the public inputs of block proofs, as a stand-in for the block
+    /// circuit.
+    fn block_like_common_data() -> CommonCircuitData<F, D> {
+        let config = CircuitConfig::standard_recursion_config();
+        let verifier_common_data = |common: &CommonCircuitData<F, D>, degree_bits: usize| {
+            let mut builder = CircuitBuilder::<F, D>::new(config.clone());
+            let proof = builder.add_virtual_proof_with_pis(common);
+            let verifier_data = builder.add_virtual_verifier_data(config.fri_config.cap_height);
+            builder.verify_proof::<C>(&proof, &verifier_data, common);
+            while log2_ceil(builder.num_gates()) < degree_bits {
+                builder.add_gate(NoopGate, vec![]);
+            }
+            builder.build::<C>().common
+        };
+        let common = CircuitBuilder::<F, D>::new(config.clone())
+            .build::<C>()
+            .common;
+        let common = verifier_common_data(&verifier_common_data(&common, 0), 14);
+
+        let mut builder = CircuitBuilder::<F, D>::new(config);
+        add_virtual_public_values_public_input(&mut builder);
+        builder.add_virtual_public_input();
+        builder.add_verifier_data_public_inputs();
+        CommonCircuitData {
+            num_public_inputs: builder.num_public_inputs(),
+            ..common
+        }
+    }
+
+    #[test]
+    fn block_range_merge_circuit_common_data() {
+        let block_circuit = dummy_circuit::<F, C, D>(&block_like_common_data());
+        let merge_circuit = BlockRangeMergeCircuitData::new(&block_circuit);
+        assert_eq!(merge_circuit.circuit.common, block_circuit.common);
+
+        // proofs of other circuits are rejected before proving
+        let proof = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config())
+            .build::<C>()
+            .prove(PartialWitness::new())
+            .unwrap();
+        let e = merge_circuit.merge_adjacent(&proof, &proof).unwrap_err();
+        assert!(
+            e.to_string()
+                .starts_with("lhs proof has 0 public inputs, but block proofs have"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn non_zero_hash() {
+        prove_non_zero_hash([0, 0, 0, 0, 0, 0, 0, 1]);
+        prove_non_zero_hash([u32::MAX; TARGET_HASH_SIZE]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_hash() {
+        prove_non_zero_hash([0; TARGET_HASH_SIZE]);
+    }
+
+    fn prove_ctl_challenges(expected: &[(u64, u64)], actual: &[(u64, u64)]) {
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let add_challenges = |builder: &mut CircuitBuilder<F, D>| GrandProductChallengeSet {
+            challenges: (0..expected.len())
+                .map(|_| GrandProductChallenge {
+                    beta: builder.add_virtual_target(),
+                    gamma: builder.add_virtual_target(),
+                })
+                .collect(),
+        };
+        let expected_targets = add_challenges(&mut builder);
+        let actual_targets = add_challenges(&mut builder);
+        AllRecursiveCircuits::<F, C, D>::connect_ctl_challenges(
+            &mut builder,
+            &expected_targets,
+            &actual_targets,
+        );
+
+        let mut inputs = PartialWitness::new();
+        for (targets, values) in [(&expected_targets, expected), (&actual_targets, actual)] {
+            for (challenge, &(beta, gamma)) in zip_eq(&targets.challenges, values) {
+                inputs.set_target(challenge.beta, F::from_canonical_u64(beta));
+                inputs.set_target(challenge.gamma, F::from_canonical_u64(gamma));
+            }
+        }
+        let data = builder.build::<C>();
+        let proof = data.prove(inputs).unwrap();
+        data.verify(proof).unwrap();
+    }
+
+    #[test]
+    fn consistent_ctl_challenges() {
+        prove_ctl_challenges(&[(1, 2), (3, 4)], &[(1, 2), (3, 4)]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn mismatched_ctl_challenges() {
+        prove_ctl_challenges(&[(1, 2), (3, 4)], &[(1, 2), (3, 5)]);
+    }
+
+    #[test]
+    fn recursion_common_data_matches_circuits() {
+        let all_stark = AllStark::<F, D>::default();
+        let config = StarkConfig::standard_fast_config();
+        let degree_bits = [8; NUM_TABLES];
+
+        let common_data = recursion_common_data::<F, C, D>(
+            &all_stark,
+            &config,
+            &shrinking_config(),
+            &degree_bits,
+        );
+        let logic = RecursiveCircuitsForTableSize::<F, C, D>::new(
+            Table::Logic,
+            &all_stark.logic_stark,
+            degree_bits[*Table::Logic],
+            &all_stark.cross_table_lookups,
+            &config,
+        );
+        assert_eq!(
+            common_data[*Table::Logic],
+            logic.initial_wrapper.circuit.common
+        );
+    }
+
+    #[cfg(feature = "eth_mainnet")]
+    #[test]
+    fn recursion_query_rounds() -> anyhow::Result<()> {
+        let all_stark = AllStark::<F, D>::default();
+        let prove = |num_query_rounds| -> anyhow::Result<_> {
+            let config = recursion_stark_config_with_query_rounds(num_query_rounds);
+            let all_proof =
+                crate::testing_utils::prove_empty_block::<F, C, D>(&all_stark, &config)?.remove(0);
+            let circuit = recursive_stark_circuit::<F, C, _, D>(
+                *Table::Logic,
+                &all_stark.logic_stark,
+                all_proof.degree_bits(&config)[*Table::Logic],
+                &all_stark.cross_table_lookups,
+                &config,
+                &shrinking_config(),
+                0,
+                RecursionOptions::default(),
+            );
+            let logic_proof = &all_proof.multi_proof.stark_proofs[*Table::Logic];
+            circuit
+                .circuit
+                .verify(circuit.prove(logic_proof, &all_proof.multi_proof.ctl_challenges)?)?;
+            Ok(logic_proof.proof.opening_proof.query_round_proofs.len())
+        };
+
+        assert_eq!(
+            prove(
+                StarkConfig::standard_fast_config()
+                    .fri_config
+                    .num_query_rounds
+            )?,
+            84
+        );
+        assert_eq!(prove(28)?, 28);
+        Ok(())
+    }
+
+    fn prove_beneficiary_limbs(limbs: [u64; 5]) {
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let targets = builder.add_virtual_target_arr::<5>();
+        AllRecursiveCircuits::<F, C, D>::check_beneficiary_limbs(&mut builder, targets);
+
+        let mut inputs = PartialWitness::new();
+        for (target, limb) in zip_eq(targets, limbs) {
+            inputs.set_target(target, F::from_canonical_u64(limb));
+        }
+        let data = builder.build::<C>();
+        let proof = data.prove(inputs).unwrap();
+        data.verify(proof).unwrap();
+    }
+
+    #[test]
+    fn beneficiary_limbs() {
+        let limbs = [0xdeadbeef, 0, 1, 2, u32::MAX as u64];
+        prove_beneficiary_limbs(limbs);
+        assert!(h160_from_limbs(&limbs.map(F::from_canonical_u64)).is_ok());
+    }
+
+    #[test]
+    fn over_range_beneficiary_limbs() {
+        let limbs = [0, 0, 0, 0, 1 << 32].map(F::from_canonical_u64);
+        assert_eq!(
+            h160_from_limbs(&limbs).unwrap_err().to_string(),
+            "address limb 4294967296 doesn't fit in 32 bits"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Integer too large to fit in given number of limbs")]
+    fn prove_over_range_beneficiary_limbs() {
+        prove_beneficiary_limbs([0, 0, 0, 0, 1 << 32]);
+    }
+
+    #[test]
+    fn table_size_wrapper_degree_bits() {
+        let all_stark = AllStark::<F, D>::default();
+        let circuits = RecursiveCircuitsForTableSize::<F, C, D>::new(
+            Table::Logic,
+            &all_stark.logic_stark,
+            6,
+            &all_stark.cross_table_lookups,
+            &StarkConfig::standard_fast_config(),
+        );
+
+        let degree_bits = circuits.wrapper_degree_bits();
+        assert_eq!(
+            degree_bits[0],
+            circuits.initial_wrapper.circuit.common.degree_bits()
+        );
+        for (bits, wrapper) in degree_bits[1..].iter().zip(&circuits.shrinking_wrappers) {
+            assert_eq!(*bits, wrapper.circuit.common.degree_bits());
+        }
+        assert_eq!(degree_bits.last(), Some(&THRESHOLD_DEGREE_BITS));
+    }
+}
@@ -249,7 +249,7 @@ pub(crate) fn observe_public_values_target<
 
 impl<F: RichField + Extendable<D>, C: GenericConfig<D, F = F>, const D: usize> AllProof<F, C, D> {
     /// Computes all Fiat-Shamir challenges used in the STARK proof.
-    pub(crate) fn get_challenges(
+    pub fn get_challenges(
         &self,
         config: &StarkConfig,
     ) -> Result<AllProofChallenges<F, D>, ProgramError> {
@@ -1,10 +1,13 @@
 use std::marker::PhantomData;
 
-use ethereum_types::{Address, H256, U256};
+use anyhow::ensure;
+use ethereum_types::{Address, BigEndianHash, H256, U256};
 use itertools::Itertools;
 use plonky2::field::extension::Extendable;
+use plonky2::field::types::Field;
 use plonky2::hash::hash_types::{HashOutTarget, MerkleCapTarget, RichField, NUM_HASH_OUT_ELTS};
 use plonky2::iop::target::{BoolTarget, Target};
+use plonky2::iop::witness::{PartialWitness, Witness};
 use plonky2::plonk::circuit_builder::CircuitBuilder;
 use plonky2::plonk::config::{GenericConfig, GenericHashOut, Hasher};
 use plonky2::util::serialization::{Buffer, IoResult, Read, Write};
@@ -13,8 +16,10 @@ use starky::config::StarkConfig;
 use starky::lookup::GrandProductChallengeSet;
 use starky::proof::{MultiProof, StarkProofChallenges};
 
-use crate::all_stark::NUM_TABLES;
+use crate::all_stark::{AllStark, Table, NUM_TABLES};
+use crate::recursive_verifier::set_public_value_targets;
 use crate::util::{get_h160, get_h256, get_u256, h256_limbs, h2u};
+use crate::witness::errors::ProgramError;
 use crate::witness::state::RegistersState;
 
 /// The default cap height used for our zkEVM STARK proofs.
@@ -38,10 +43,105 @@ impl<F: RichField + Extendable<D>, C: GenericConfig<D, F = F>, const D: usize> A
     pub fn degree_bits(&self, config: &StarkConfig) -> [usize; NUM_TABLES] {
         self.multi_proof.recover_degree_bits(config)
     }
+
+    /// Returns the height of the trace cap of each STARK.
+    pub fn trace_cap_heights(&self) -> [usize; NUM_TABLES] {
+        self.multi_proof
+            .stark_proofs
+            .each_ref()
+            .map(|p| p.proof.trace_cap.height())
+    }
+
+    /// Returns whether `self` and `other` have the same shape, i.e. the same
+    /// degree, trace width and number of CTL polynomials for each STARK, as
+    /// well as the same number of CTL challenges.
+    ///
+    /// The actual field elements of the proofs are ignored, which makes this
+    /// useful to detect configuration drifts between independently generated
+    /// proofs.
+    pub fn same_shape(&self, other: &Self, config: &StarkConfig) -> bool {
+        let num_ctl_zs = |proof: &Self| {
+            proof.multi_proof.stark_proofs.each_ref().map(|p| {
+                p.proof
+                    .openings
+                    .ctl_zs_first
+                    .as_ref()
+                    .map_or(0, |zs| zs.len())
+            })
+        };
+        let trace_widths = |proof: &Self| {
+            proof
+                .multi_proof
+                .stark_proofs
+                .each_ref()
+                .map(|p| p.proof.openings.local_values.len())
+        };
+
+        self.degree_bits(config) == other.degree_bits(config)
+            && num_ctl_zs(self) == num_ctl_zs(other)
+            && trace_widths(self) == trace_widths(other)
+            && self.multi_proof.ctl_challenges.challenges.len()
+                == other.multi_proof.ctl_challenges.challenges.len()
+    }
+
+    /// Returns a human-readable, multi-line summary of this proof, with the
+    /// block it proves, its trie roots and the shape of each STARK proof.
+    ///
+    /// The number of cross-table lookups is the one of `all_stark`, which this
+    /// proof is assumed to be generated with.
+    ///
+    /// This is meant for operators inspecting saved proofs, for which the
+    /// [`Debug`] output is too verbose.
+    pub fn summary(&self, all_stark: &AllStark<F, D>, config: &StarkConfig) -> String {
+        let PublicValues {
+            trie_roots_before,
+            trie_roots_after,
+            block_metadata,
+            ..
+        } = &self.public_values;
+        let mut lines = vec![
+            format!("block number: {}", block_metadata.block_number),
+            format!(
+                "state root: {:?} -> {:?}",
+                trie_roots_before.state_root, trie_roots_after.state_root
+            ),
+            format!(
+                "transactions root: {:?} -> {:?}",
+                trie_roots_before.transactions_root, trie_roots_after.transactions_root
+            ),
+            format!(
+                "receipts root: {:?} -> {:?}",
+                trie_roots_before.receipts_root, trie_roots_after.receipts_root
+            ),
+            format!(
+                "cross-table lookups: {}, with {} challenges",
+                all_stark.cross_table_lookups.len(),
+                self.multi_proof.ctl_challenges.challenges.len()
+            ),
+        ];
+        for ((table, degree_bits), stark_proof) in Table::all()
+            .into_iter()
+            .zip(self.degree_bits(config))
+            .zip(&self.multi_proof.stark_proofs)
+        {
+            lines.push(format!(
+                "{:?}: degree bits {}, {} CTL polynomials",
+                table,
+                degree_bits,
+                stark_proof
+                    .proof
+                    .openings
+                    .ctl_zs_first
+                    .as_ref()
+                    .map_or(0, |zs| zs.len())
+            ));
+        }
+        lines.join("\n")
+    }
 }
 
 /// Randomness for all STARKs.
-pub(crate) struct AllProofChallenges<F: RichField + Extendable<D>, const D: usize> {
+pub struct AllProofChallenges<F: RichField + Extendable<D>, const D: usize> {
     /// Randomness used in each STARK proof.
     pub stark_challenges: [StarkProofChallenges<F, D>; NUM_TABLES],
     /// Randomness used for cross-table lookups. It is shared by all STARKs.
@@ -130,6 +230,157 @@ impl<F: RichField> PublicValues<F> {
             mem_after,
         }
     }
+
+    /// Lists the fields in which `self` and `other` differ, one line each, e.g.
+    /// to check that regenerated proofs still commit to the same block data.
+    ///
+    /// Fields of the block metadata are compared individually, and all other
+    /// groups of fields as a whole.
+    pub fn diff(&self, other: &Self) -> Vec<String> {
+        fn compare<T: PartialEq + core::fmt::Debug>(
+            diff: &mut Vec<String>,
+            field: &str,
+            lhs: &T,
+            rhs: &T,
+        ) {
+            if lhs != rhs {
+                diff.push(format!("{field}: {lhs:?} != {rhs:?}"))
+            }
+        }
+
+        let mut diff = vec![];
+        let (lhs, rhs) = (self, other);
+        compare(
+            &mut diff,
+            "trie_roots_before",
+            &lhs.trie_roots_before,
+            &rhs.trie_roots_before,
+        );
+        compare(
+            &mut diff,
+            "trie_roots_after",
+            &lhs.trie_roots_after,
+            &rhs.trie_roots_after,
+        );
+        compare(&mut diff, "burn_addr", &lhs.burn_addr, &rhs.burn_addr);
+
+        let (lhs_md, rhs_md) = (&lhs.block_metadata, &rhs.block_metadata);
+        macro_rules! compare_metadata {
+            ($($field:ident),* $(,)?) => {
+                $(compare(
+                    &mut diff,
+                    concat!("block_metadata.", stringify!($field)),
+                    &lhs_md.$field,
+                    &rhs_md.$field,
+                );)*
+            };
+        }
+        compare_metadata!(
+            block_beneficiary,
+            block_timestamp,
+            block_number,
+            block_difficulty,
+            block_random,
+            block_gaslimit,
+            block_chain_id,
+            block_base_fee,
+            block_gas_used,
+            block_blob_gas_used,
+            block_excess_blob_gas,
+            parent_beacon_block_root,
+            block_bloom,
+        );
+
+        compare(
+            &mut diff,
+            "block_hashes",
+            &lhs.block_hashes,
+            &rhs.block_hashes,
+        );
+        compare(
+            &mut diff,
+            "extra_block_data",
+            &lhs.extra_block_data,
+            &rhs.extra_block_data,
+        );
+        compare(
+            &mut diff,
+            "registers_before",
+            &lhs.registers_before,
+            &rhs.registers_before,
+        );
+        compare(
+            &mut diff,
+            "registers_after",
+            &lhs.registers_after,
+            &rhs.registers_after,
+        );
+        compare(&mut diff, "mem_before", &lhs.mem_before, &rhs.mem_before);
+        compare(&mut diff, "mem_after", &lhs.mem_after, &rhs.mem_after);
+        diff
+    }
+
+    /// Checks that these public values match the ones posted as `calldata`,
+    /// with the layout of [`PublicValuesCalldata`].
+    ///
+    /// The error lists every mismatching field.
+    pub fn verify_against_calldata(&self, calldata: &[u8]) -> anyhow::Result<()> {
+        let expected = PublicValuesCalldata::decode(calldata)?;
+        let actual = PublicValuesCalldata::new(self);
+        let mut mismatches = vec![];
+        macro_rules! compare {
+            ($($field:ident),* $(,)?) => {
+                $(if actual.$field != expected.$field {
+                    mismatches.push(format!(
+                        "{}: the proof has {:?}, but the calldata has {:?}",
+                        stringify!($field),
+                        actual.$field,
+                        expected.$field
+                    ));
+                })*
+            };
+        }
+        compare!(
+            trie_roots_before,
+            trie_roots_after,
+            block_number,
+            block_timestamp,
+            block_chain_id,
+            block_gas_used,
+        );
+        ensure!(
+            mismatches.is_empty(),
+            "public values don't match the calldata: {}",
+            mismatches.join("; ")
+        );
+        Ok(())
+    }
+
+    /// Flattens these public values into field elements, following the same
+    /// layout as the [`PublicValuesTarget`] registered as public inputs of our
+    /// circuits. This is the inverse of [`PublicValues::from_public_inputs`].
+    ///
+    /// The encoding is delegated to [`set_public_value_targets`], so that
+    /// the host and circuit representations cannot diverge.
+    pub fn to_public_inputs<const D: usize>(&self) -> Result<Vec<F>, ProgramError>
+    where
+        F: Extendable<D>,
+    {
+        let targets = (0..PublicValuesTarget::SIZE)
+            .map(|index| Target::VirtualTarget { index })
+            .collect_vec();
+        let public_values_target = PublicValuesTarget::from_public_inputs(&targets);
+
+        let mut witness = PartialWitness::new();
+        set_public_value_targets(&mut witness, &public_values_target, self)?;
+
+        // Targets which aren't set, e.g. because they are disabled by the
+        // active feature set, default to zero.
+        Ok(targets
+            .into_iter()
+            .map(|target| witness.try_get_target(target).unwrap_or(F::ZERO))
+            .collect())
+    }
 }
 
 /// Memory values which are public once a final block proof is generated.
@@ -196,6 +447,41 @@ impl<H: Hasher<F>, F: RichField> From<PublicValues<F>> for FinalPublicValues<F,
     }
 }
 
+/// The boundary values of a range of blocks aggregated into a single block
+/// proof.
+///
+/// The block circuit chains block proofs together, so that its
+/// `trie_roots_before` are those of the first block of the range, and its
+/// `trie_roots_after` and block number are those of the last one. The number
+/// of the first block is registered right after the [`PublicValues`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AggregatedPublicValues {
+    /// Trie hashes before the execution of the first block of the range.
+    pub trie_roots_before: TrieRoots,
+    /// Trie hashes after the execution of the last block of the range.
+    pub trie_roots_after: TrieRoots,
+    /// The number of the first block of the range.
+    pub first_block_number: U256,
+    /// The number of the last block of the range.
+    pub block_number: U256,
+}
+
+impl AggregatedPublicValues {
+    /// Extracts the boundary values of an aggregated block range from the
+    /// public inputs of a block proof.
+    pub fn from_public_inputs<F: RichField>(pis: &[F]) -> Self {
+        assert!(PublicValuesTarget::SIZE < pis.len());
+        let public_values = PublicValues::<F>::from_public_inputs(pis);
+
+        Self {
+            trie_roots_before: public_values.trie_roots_before,
+            trie_roots_after: public_values.trie_roots_after,
+            first_block_number: pis[PublicValuesTarget::SIZE].to_canonical_u64().into(),
+            block_number: public_values.block_metadata.block_number,
+        }
+    }
+}
+
 /// Memory values which are public once a final block proof is generated.
 /// Note: All the larger integers are encoded with 32-bit limbs in little-endian
 /// order.
@@ -568,6 +854,105 @@ impl From<RegistersState> for RegistersData {
     }
 }
 
+/// The public values of a block which a rollup posts to L1 calldata.
+///
+/// The calldata is a sequence of 32-byte big-endian words, in the order of
+/// the fields below, with the trie roots in the order of [`TrieRoots`]:
+///
+/// | words  | field                                  |
+/// |--------|----------------------------------------|
+/// | 0..3   | `trie_roots_before`                    |
+/// | 3..6   | `trie_roots_after`                     |
+/// | 6      | `block_number`                         |
+/// | 7      | `block_timestamp`                      |
+/// | 8      | `block_chain_id`                       |
+/// | 9      | `block_gas_used`                       |
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublicValuesCalldata {
+    /// Trie hashes before the execution of the block.
+    pub trie_roots_before: TrieRoots,
+    /// Trie hashes after the execution of the block.
+    pub trie_roots_after: TrieRoots,
+    /// The block number.
+    pub block_number: U256,
+    /// The block timestamp.
+    pub block_timestamp: U256,
+    /// The chain id of the block.
+    pub block_chain_id: U256,
+    /// The total gas used by the block.
+    pub block_gas_used: U256,
+}
+
+impl PublicValuesCalldata {
+    /// The length of the calldata, in bytes.
+    pub const LEN: usize = 10 * 32;
+
+    /// Extracts the fields posted as calldata from `public_values`.
+    pub fn new<F: RichField>(public_values: &PublicValues<F>) -> Self {
+        Self {
+            trie_roots_before: public_values.trie_roots_before.clone(),
+            trie_roots_after: public_values.trie_roots_after.clone(),
+            block_number: public_values.block_metadata.block_number,
+            block_timestamp: public_values.block_metadata.block_timestamp,
+            block_chain_id: public_values.block_metadata.block_chain_id,
+            block_gas_used: public_values.block_metadata.block_gas_used,
+        }
+    }
+
+    /// Encodes these values as calldata.
+    pub fn encode(&self) -> Vec<u8> {
+        let roots = |roots: &TrieRoots| {
+            [
+                roots.state_root,
+                roots.transactions_root,
+                roots.receipts_root,
+            ]
+        };
+        roots(&self.trie_roots_before)
+            .into_iter()
+            .chain(roots(&self.trie_roots_after))
+            .chain(
+                [
+                    self.block_number,
+                    self.block_timestamp,
+                    self.block_chain_id,
+                    self.block_gas_used,
+                ]
+                .map(|word| H256::from_uint(&word)),
+            )
+            .flat_map(|word| word.0)
+            .collect()
+    }
+
+    /// Decodes values from `calldata`, which must be exactly [`Self::LEN`]
+    /// bytes long.
+    pub fn decode(calldata: &[u8]) -> anyhow::Result<Self> {
+        ensure!(
+            calldata.len() == Self::LEN,
+            "expected {} bytes of calldata, got {}",
+            Self::LEN,
+            calldata.len()
+        );
+        let words = calldata
+            .chunks_exact(32)
+            .map(H256::from_slice)
+            .collect_vec();
+        let roots = |words: &[H256]| TrieRoots {
+            state_root: words[0],
+            transactions_root: words[1],
+            receipts_root: words[2],
+        };
+        Ok(Self {
+            trie_roots_before: roots(&words[0..3]),
+            trie_roots_after: roots(&words[3..6]),
+            block_number: words[6].into_uint(),
+            block_timestamp: words[7].into_uint(),
+            block_chain_id: words[8].into_uint(),
+            block_gas_used: words[9].into_uint(),
+        })
+    }
+}
+
 /// Structure for a Merkle cap. It is used for `MemBefore` and `MemAfter`.
 #[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
 pub struct MemCap {
@@ -592,7 +977,7 @@ impl MemCap {
 /// Memory values which are public.
 /// Note: All the larger integers are encoded with 32-bit limbs in little-endian
 /// order.
-#[derive(Eq, PartialEq, Debug)]
+#[derive(Eq, PartialEq, Debug, Clone)]
 pub struct PublicValuesTarget {
     /// Trie hashes before the execution of the local state transition.
     pub trie_roots_before: TrieRootsTarget,
@@ -618,10 +1003,12 @@ pub struct PublicValuesTarget {
 
 impl PublicValuesTarget {
     pub(crate) const SIZE: usize = TrieRootsTarget::SIZE * 2
+        + BurnAddrTarget::get_size()
         + BlockMetadataTarget::SIZE
         + BlockHashesTarget::SIZE
         + ExtraBlockDataTarget::SIZE
-        + DEFAULT_CAP_HEIGHT * NUM_HASH_OUT_ELTS * 2;
+        + RegistersDataTarget::SIZE * 2
+        + MemCapTarget::SIZE * 2;
     /// Serializes public value targets.
     pub(crate) fn to_buffer(&self, buffer: &mut Vec<u8>) -> IoResult<()> {
         let TrieRootsTarget {
@@ -927,6 +1314,25 @@ impl PublicValuesTarget {
             mem_after: MemCapTarget::select(builder, condition, pv0.mem_after, pv1.mem_after),
         }
     }
+
+    /// Connects all the public values of `pv0` to those of `pv1`.
+    pub(crate) fn connect<F: RichField + Extendable<D>, const D: usize>(
+        builder: &mut CircuitBuilder<F, D>,
+        pv0: Self,
+        pv1: Self,
+    ) {
+        TrieRootsTarget::connect(builder, pv0.trie_roots_before, pv1.trie_roots_before);
+        TrieRootsTarget::connect(builder, pv0.trie_roots_after, pv1.trie_roots_after);
+        #[cfg(feature = "cdk_erigon")]
+        BurnAddrTarget::connect(builder, pv0.burn_addr, pv1.burn_addr);
+        BlockMetadataTarget::connect(builder, pv0.block_metadata, pv1.block_metadata);
+        BlockHashesTarget::connect(builder, pv0.block_hashes, pv1.block_hashes);
+        ExtraBlockDataTarget::connect(builder, pv0.extra_block_data, pv1.extra_block_data);
+        RegistersDataTarget::connect(builder, pv0.registers_before, pv1.registers_before);
+        RegistersDataTarget::connect(builder, pv0.registers_after, pv1.registers_after);
+        MemCapTarget::connect(builder, pv0.mem_before, pv1.mem_before);
+        MemCapTarget::connect(builder, pv0.mem_after, pv1.mem_after);
+    }
 }
 
 /// Circuit version of `TrieRoots`.
@@ -1731,3 +2137,48 @@ impl MemCapTarget {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use plonky2::field::goldilocks_field::GoldilocksField;
+
+    use super::*;
+
+    #[test]
+    fn public_values_diff() {
+        let public_values = PublicValues::<GoldilocksField>::default();
+        assert!(public_values.diff(&public_values.clone()).is_empty());
+
+        let mut other = public_values.clone();
+        other.block_metadata.block_base_fee = 0xa.into();
+        assert_eq!(
+            public_values.diff(&other),
+            ["block_metadata.block_base_fee: 0 != 10"]
+        );
+    }
+
+    #[test]
+    fn public_values_calldata() {
+        let mut public_values = PublicValues::<GoldilocksField>::default();
+        public_values.trie_roots_after.state_root = H256([0xAB; 32]);
+        public_values.block_metadata.block_number = 42.into();
+        public_values.block_metadata.block_chain_id = 1.into();
+
+        let calldata = PublicValuesCalldata::new(&public_values).encode();
+        assert_eq!(calldata.len(), PublicValuesCalldata::LEN);
+        assert_eq!(
+            PublicValuesCalldata::decode(&calldata).unwrap(),
+            PublicValuesCalldata::new(&public_values)
+        );
+        public_values.verify_against_calldata(&calldata).unwrap();
+
+        let mut other = public_values.clone();
+        other.block_metadata.block_number = 43.into();
+        let e = other.verify_against_calldata(&calldata).unwrap_err();
+        assert!(e.to_string().contains("block_number"), "{e}");
+        assert!(!e.to_string().contains("trie_roots"), "{e}");
+        assert!(public_values
+            .verify_against_calldata(&calldata[1..])
+            .is_err());
+    }
+}
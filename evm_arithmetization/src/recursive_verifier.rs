@@ -1,41 +1,53 @@
 use core::array::from_fn;
 use core::fmt::Debug;
+use core::iter;
 
-use anyhow::Result;
+use anyhow::{ensure, Context as _, Result};
 use ethereum_types::{BigEndianHash, U256};
 use plonky2::field::extension::Extendable;
+use plonky2::field::types::Field;
+use plonky2::fri::structure::{FriOpeningBatchTarget, FriOpeningsTarget};
 use plonky2::gates::exponentiation::ExponentiationGate;
 use plonky2::gates::gate::GateRef;
 use plonky2::gates::noop::NoopGate;
-use plonky2::hash::hash_types::{HashOut, MerkleCapTarget, RichField};
+use plonky2::hash::hash_types::{
+    HashOut, HashOutTarget, MerkleCapTarget, RichField, NUM_HASH_OUT_ELTS,
+};
 use plonky2::hash::hashing::PlonkyPermutation;
+use plonky2::hash::merkle_proofs::{verify_merkle_proof, MerkleProof};
+use plonky2::hash::merkle_tree::{MerkleCap, MerkleTree};
+use plonky2::hash::poseidon::PoseidonHash;
 use plonky2::iop::challenger::RecursiveChallenger;
 use plonky2::iop::target::Target;
 use plonky2::iop::witness::{PartialWitness, Witness, WitnessWrite};
 use plonky2::plonk::circuit_builder::CircuitBuilder;
-use plonky2::plonk::circuit_data::{CircuitConfig, CircuitData};
-use plonky2::plonk::config::{AlgebraicHasher, GenericConfig, Hasher};
+use plonky2::plonk::circuit_data::{
+    CircuitConfig, CircuitData, VerifierCircuitData, VerifierCircuitTarget, VerifierOnlyCircuitData,
+};
+use plonky2::plonk::config::{AlgebraicHasher, GenericConfig, GenericHashOut, Hasher};
 use plonky2::plonk::proof::{ProofWithPublicInputs, ProofWithPublicInputsTarget};
 use plonky2::util::serialization::{
     Buffer, GateSerializer, IoResult, Read, WitnessGeneratorSerializer, Write,
 };
-use plonky2_util::log2_ceil;
+use plonky2_util::{log2_ceil, log2_strict};
 use starky::config::StarkConfig;
-use starky::cross_table_lookup::{CrossTableLookup, CtlCheckVarsTarget};
+use starky::cross_table_lookup::{CrossTableLookup, CtlCheckVarsTarget, TableIdx};
 use starky::lookup::{GrandProductChallenge, GrandProductChallengeSet};
-use starky::proof::{StarkProofTarget, StarkProofWithMetadata};
+use starky::proof::{
+    StarkProof, StarkProofChallengesTarget, StarkProofTarget, StarkProofWithMetadata,
+};
 use starky::recursive_verifier::{
     add_virtual_stark_proof, set_stark_proof_target, verify_stark_proof_with_challenges_circuit,
 };
 use starky::stark::Stark;
 
-use crate::all_stark::Table;
+use crate::all_stark::{AllStark, Table, NUM_TABLES};
 use crate::cpu::kernel::aggregator::KERNEL;
 use crate::cpu::kernel::constants::global_metadata::GlobalMetadata;
 use crate::memory::segments::Segment;
 use crate::memory::VALUE_LIMBS;
 use crate::proof::{
-    BlockHashes, BlockHashesTarget, BlockMetadata, BlockMetadataTarget, BurnAddrTarget,
+    AllProof, BlockHashes, BlockHashesTarget, BlockMetadata, BlockMetadataTarget, BurnAddrTarget,
     ExtraBlockData, ExtraBlockDataTarget, FinalPublicValues, FinalPublicValuesTarget, MemCap,
     MemCapTarget, PublicValues, PublicValuesTarget, RegistersData, RegistersDataTarget, TrieRoots,
     TrieRootsTarget, DEFAULT_CAP_LEN,
@@ -50,13 +62,55 @@ pub(crate) struct PublicInputs<T: Copy + Default + Eq + PartialEq + Debug, P: Pl
     pub(crate) ctl_challenges: GrandProductChallengeSet<T>,
     pub(crate) challenger_state_before: P,
     pub(crate) challenger_state_after: P,
+    /// The hash of the flattened trace cap, if it was registered as public
+    /// inputs by the recursive STARK circuit.
+    pub(crate) trace_cap_hash: Option<Vec<T>>,
 }
 
 impl<T: Copy + Debug + Default + Eq + PartialEq, P: PlonkyPermutation<T>> PublicInputs<T, P> {
-    pub(crate) fn from_vec(v: &[T], config: &StarkConfig) -> Self {
+    /// Parses the public inputs of a recursive STARK proof.
+    ///
+    /// `num_ctl_zs` is the number of CTL `Z` polynomials of the table, as
+    /// derived from the cross-table lookups, and `with_trace_cap_hash` must
+    /// match the flag the recursive circuit was built with.
+    pub(crate) fn from_vec(
+        v: &[T],
+        config: &StarkConfig,
+        with_trace_cap_hash: bool,
+        num_ctl_zs: usize,
+    ) -> Self {
         // TODO: Document magic number 4; probably comes from
         // Ethereum 256 bits = 4 * Goldilocks 64 bits
         let nelts = config.fri_config.num_cap_elements();
+        let num_challenger_state_elts = 2 * P::WIDTH;
+        // The cap hash comes last, after the CTL values.
+        let (v, trace_cap_hash) = match with_trace_cap_hash {
+            true => {
+                assert!(
+                    v.len() >= NUM_HASH_OUT_ELTS,
+                    "public inputs are too short for a trace cap hash"
+                );
+                let (v, hash) = v.split_at(v.len() - NUM_HASH_OUT_ELTS);
+                (v, Some(hash.to_vec()))
+            }
+            false => (v, None),
+        };
+        // `P::new` pads missing elements, so we check the length upfront to
+        // catch a mismatch with the permutation width of the recursive circuit.
+        assert!(
+            v.len() >= 4 * nelts + 2 * config.num_challenges + num_challenger_state_elts,
+            "public inputs are too short for challenger states of width {}",
+            P::WIDTH
+        );
+        // Any leftover element would otherwise be parsed as an extra CTL value,
+        // and only fail cross-table verification much later.
+        let num_ctl_values =
+            v.len() - 4 * nelts - 2 * config.num_challenges - num_challenger_state_elts;
+        assert_eq!(
+            num_ctl_values, num_ctl_zs,
+            "public inputs have {} CTL values, but the cross-table lookups expect {}",
+            num_ctl_values, num_ctl_zs
+        );
         let mut trace_cap = Vec::with_capacity(nelts);
         for i in 0..nelts {
             trace_cap.push(v[4 * i..4 * (i + 1)].to_vec());
@@ -80,6 +134,7 @@ impl<T: Copy + Debug + Default + Eq + PartialEq, P: PlonkyPermutation<T>> Public
             ctl_challenges,
             challenger_state_before,
             challenger_state_after,
+            trace_cap_hash,
         }
     }
 }
@@ -148,6 +203,14 @@ where
         proof_with_metadata: &StarkProofWithMetadata<F, C, D>,
         ctl_challenges: &GrandProductChallengeSet<F>,
     ) -> Result<ProofWithPublicInputs<F, C, D>> {
+        check_stark_proof_shape(&self.stark_proof_target, &proof_with_metadata.proof)?;
+        ensure!(
+            self.ctl_challenges_target.challenges.len() == ctl_challenges.challenges.len(),
+            "the circuit expects {} CTL challenges, but {} were given",
+            self.ctl_challenges_target.challenges.len(),
+            ctl_challenges.challenges.len()
+        );
+
         let mut inputs = PartialWitness::new();
 
         set_stark_proof_target(
@@ -176,6 +239,122 @@ where
     }
 }
 
+/// Checks that `proof` has the shape `target` was built for, so that a proof
+/// of e.g. the wrong degree is reported as such, rather than as a panic when
+/// setting the witness.
+fn check_stark_proof_shape<F, C, const D: usize>(
+    target: &StarkProofTarget<D>,
+    proof: &StarkProof<F, C, D>,
+) -> Result<()>
+where
+    F: RichField + Extendable<D>,
+    C: GenericConfig<D, F = F>,
+{
+    fn check(what: &str, expected: Option<usize>, actual: Option<usize>) -> Result<()> {
+        ensure!(
+            expected == actual,
+            "mismatched {}: the circuit expects {:?}, but the proof has {:?}",
+            what,
+            expected,
+            actual
+        );
+        Ok(())
+    }
+
+    // The cap heights of the target come from the FRI parameters of the inner
+    // STARK config, which must agree with the ones the proof was generated with.
+    // A cap of height `h` holds exactly `1 << h` hashes.
+    fn check_cap(what: &str, expected: Option<usize>, actual: Option<usize>) -> Result<()> {
+        ensure!(
+            expected == actual,
+            "mismatched {}: the circuit expects a cap of height {:?}, but the proof has {:?} hashes",
+            what,
+            expected.map(log2_strict),
+            actual
+        );
+        Ok(())
+    }
+    let target_cap_len = |cap: &MerkleCapTarget| cap.0.len();
+    let proof_cap_len = |cap: &MerkleCap<F, C::Hasher>| cap.0.len();
+    check_cap(
+        "trace cap height",
+        Some(target_cap_len(&target.trace_cap)),
+        Some(proof_cap_len(&proof.trace_cap)),
+    )?;
+    check_cap(
+        "auxiliary polynomials cap height",
+        target.auxiliary_polys_cap.as_ref().map(target_cap_len),
+        proof.auxiliary_polys_cap.as_ref().map(proof_cap_len),
+    )?;
+    check_cap(
+        "quotient polynomials cap height",
+        target.quotient_polys_cap.as_ref().map(target_cap_len),
+        proof.quotient_polys_cap.as_ref().map(proof_cap_len),
+    )?;
+
+    let (expected, actual) = (&target.openings, &proof.openings);
+    check(
+        "number of local values",
+        Some(expected.local_values.len()),
+        Some(actual.local_values.len()),
+    )?;
+    check(
+        "number of next values",
+        Some(expected.next_values.len()),
+        Some(actual.next_values.len()),
+    )?;
+    check(
+        "number of auxiliary polynomials",
+        expected.auxiliary_polys.as_ref().map(Vec::len),
+        actual.auxiliary_polys.as_ref().map(Vec::len),
+    )?;
+    check(
+        "number of next auxiliary polynomials",
+        expected.auxiliary_polys_next.as_ref().map(Vec::len),
+        actual.auxiliary_polys_next.as_ref().map(Vec::len),
+    )?;
+    check(
+        "number of CTL polynomials",
+        expected.ctl_zs_first.as_ref().map(Vec::len),
+        actual.ctl_zs_first.as_ref().map(Vec::len),
+    )?;
+    check(
+        "number of quotient polynomials",
+        expected.quotient_polys.as_ref().map(Vec::len),
+        actual.quotient_polys.as_ref().map(Vec::len),
+    )?;
+
+    // These depend on the degree of the proof.
+    let (expected, actual) = (&target.opening_proof, &proof.opening_proof);
+    check(
+        "number of FRI commit phase caps, i.e. proof degree",
+        Some(expected.commit_phase_merkle_caps.len()),
+        Some(actual.commit_phase_merkle_caps.len()),
+    )?;
+    for (i, (expected, actual)) in iter::zip(
+        &expected.commit_phase_merkle_caps,
+        &actual.commit_phase_merkle_caps,
+    )
+    .enumerate()
+    {
+        check_cap(
+            &format!("FRI commit phase cap {i} height"),
+            Some(target_cap_len(expected)),
+            Some(proof_cap_len(actual)),
+        )?;
+    }
+    check(
+        "FRI final polynomial length, i.e. proof degree",
+        Some(expected.final_poly.0.len()),
+        Some(actual.final_poly.len()),
+    )?;
+    check(
+        "number of FRI query rounds",
+        Some(expected.query_round_proofs.len()),
+        Some(actual.query_round_proofs.len()),
+    )
+}
+
 /// Represents a circuit which recursively verifies a PLONK proof.
 #[derive(Eq, PartialEq, Debug)]
 pub(crate) struct PlonkWrapperCircuit<F, C, const D: usize>
@@ -203,32 +382,63 @@ where
     }
 }
 
-/// Returns the recursive STARK circuit.
+/// Optional features of the recursive circuits of each table, see
+/// [`AllRecursiveCircuits::new_with_recursion_options`](crate::fixed_recursive_verifier::AllRecursiveCircuits::new_with_recursion_options).
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct RecursionOptions {
+    /// If `true`, the hash of the flattened trace cap is also registered as the
+    /// last public inputs, so that an outer circuit can bind the trace cap with
+    /// a single hash rather than all of its elements.
+    pub with_trace_cap_hash: bool,
+    /// If provided, overrides the Merkle cap height of the circuit config,
+    /// independently of the cap height of the inner STARK proof.
+    pub cap_height: Option<usize>,
+}
+
+/// Returns the recursive STARK circuit, see [`RecursionOptions`].
 pub(crate) fn recursive_stark_circuit<
     F: RichField + Extendable<D>,
     C: GenericConfig<D, F = F>,
     S: Stark<F, D>,
     const D: usize,
 >(
-    table: Table,
+    table: TableIdx,
     stark: &S,
     degree_bits: usize,
     cross_table_lookups: &[CrossTableLookup<F>],
     inner_config: &StarkConfig,
     circuit_config: &CircuitConfig,
     min_degree_bits: usize,
+    options: RecursionOptions,
 ) -> StarkWrapperCircuit<F, C, D>
 where
     C::Hasher: AlgebraicHasher<F>,
 {
-    let mut builder = CircuitBuilder::<F, D>::new(circuit_config.clone());
+    let RecursionOptions {
+        with_trace_cap_hash,
+        cap_height,
+    } = options;
+    let mut circuit_config = circuit_config.clone();
+    if let Some(cap_height) = cap_height {
+        // The cap cannot be higher than the smallest Merkle tree committed to by
+        // the FRI prover, i.e. the LDE of a circuit of degree `min_degree_bits`.
+        assert!(
+            cap_height <= min_degree_bits + circuit_config.fri_config.rate_bits,
+            "cap height {} is too large for circuits of degree 2^{} with rate 2^{}",
+            cap_height,
+            min_degree_bits,
+            circuit_config.fri_config.rate_bits,
+        );
+        circuit_config.fri_config.cap_height = cap_height;
+    }
+    let mut builder = CircuitBuilder::<F, D>::new(circuit_config);
     let zero_target = builder.zero();
 
     let num_lookup_columns = stark.num_lookup_helper_columns(inner_config);
     let (total_num_helpers, num_ctl_zs, num_helpers_by_ctl) =
         CrossTableLookup::num_ctl_helpers_zs_all(
             cross_table_lookups,
-            *table,
+            table,
             inner_config.num_challenges,
             stark.constraint_degree(),
         );
@@ -242,7 +452,6 @@ where
         num_ctl_helper_zs,
         num_ctl_zs,
     );
-
     builder.register_public_inputs(
         &stark_proof_target
             .trace_cap
@@ -262,7 +471,7 @@ where
     };
 
     let ctl_vars = CtlCheckVarsTarget::from_proof(
-        *table,
+        table,
         &stark_proof_target,
         cross_table_lookups,
         &ctl_challenges_target,
@@ -277,6 +486,10 @@ where
         }));
     let mut challenger =
         RecursiveChallenger::<F, C::Hasher, D>::from_state(init_challenger_state_target);
+    // The lookup and STARK challenges are re-derived here from the transcript,
+    // rather than taken from the prover. The CTL challenges are shared by all
+    // tables, so they are public inputs instead, which the root circuit
+    // connects to the ones it derives from the trace caps of every table.
     let challenges = stark_proof_target.get_challenges::<F, C>(
         &mut builder,
         &mut challenger,
@@ -289,6 +502,18 @@ where
 
     builder.register_public_inputs(stark_proof_target.openings.ctl_zs_first.as_ref().unwrap());
 
+    if with_trace_cap_hash {
+        let trace_cap_hash = builder.hash_n_to_hash_no_pad::<C::Hasher>(
+            stark_proof_target
+                .trace_cap
+                .0
+                .iter()
+                .flat_map(|h| h.elements)
+                .collect(),
+        );
+        builder.register_public_inputs(&trace_cap_hash.elements);
+    }
+
     verify_stark_proof_with_challenges_circuit::<F, C, _, D>(
         &mut builder,
         stark,
@@ -316,6 +541,394 @@ where
     }
 }
 
+/// Adds a virtual STARK proof target for each table, shaped like the proofs of
+/// `all_proof`, so that they can be set with [`set_stark_proof_target`].
+///
+/// The degree of each table is recovered from `all_proof`, and its number of
+/// CTL polynomials from the cross-table lookups of `all_stark`.
+pub fn add_virtual_stark_proofs_like<
+    F: RichField + Extendable<D>,
+    C: GenericConfig<D, F = F>,
+    const D: usize,
+>(
+    builder: &mut CircuitBuilder<F, D>,
+    all_stark: &AllStark<F, D>,
+    config: &StarkConfig,
+    all_proof: &AllProof<F, C, D>,
+) -> [StarkProofTarget<D>; NUM_TABLES] {
+    let degree_bits = all_proof.degree_bits(config);
+
+    macro_rules! add_proof {
+        ($stark:ident, $table:expr) => {{
+            let (total_num_helpers, num_ctl_zs, _) = CrossTableLookup::num_ctl_helpers_zs_all(
+                &all_stark.cross_table_lookups,
+                *$table,
+                config.num_challenges,
+                all_stark.$stark.constraint_degree(),
+            );
+            add_virtual_stark_proof(
+                builder,
+                &all_stark.$stark,
+                config,
+                degree_bits[*$table],
+                num_ctl_zs + total_num_helpers,
+                num_ctl_zs,
+            )
+        }};
+    }
+
+    [
+        add_proof!(arithmetic_stark, Table::Arithmetic),
+        add_proof!(byte_packing_stark, Table::BytePacking),
+        add_proof!(cpu_stark, Table::Cpu),
+        add_proof!(keccak_stark, Table::Keccak),
+        add_proof!(keccak_sponge_stark, Table::KeccakSponge),
+        add_proof!(logic_stark, Table::Logic),
+        add_proof!(memory_stark, Table::Memory),
+        add_proof!(mem_before_stark, Table::MemBefore),
+        add_proof!(mem_after_stark, Table::MemAfter),
+        #[cfg(feature = "cdk_erigon")]
+        add_proof!(poseidon_stark, Table::Poseidon),
+    ]
+}
+
+/// Builds the recursive circuit of a single `table` and proves it for
+/// `proof_with_metadata`, e.g. to benchmark or debug the recursion cost of one
+/// table in isolation.
+///
+/// The resulting proof can only be checked against the returned verifier data:
+/// on its own, it says nothing about the cross-table lookups with the other
+/// tables, nor about the validity of `ctl_challenges` and of the initial
+/// challenger state, which are only checked when aggregating all tables.
+pub fn recursively_verify_table<F, C, const D: usize>(
+    all_stark: &AllStark<F, D>,
+    table: Table,
+    proof_with_metadata: &StarkProofWithMetadata<F, C, D>,
+    ctl_challenges: &GrandProductChallengeSet<F>,
+    inner_config: &StarkConfig,
+    circuit_config: &CircuitConfig,
+) -> Result<(ProofWithPublicInputs<F, C, D>, VerifierCircuitData<F, C, D>)>
+where
+    F: RichField + Extendable<D>,
+    C: GenericConfig<D, F = F>,
+    C::Hasher: AlgebraicHasher<F>,
+{
+    macro_rules! verify {
+        ($stark:ident) => {
+            recursively_verify_stark(
+                &all_stark.$stark,
+                *table,
+                proof_with_metadata,
+                &all_stark.cross_table_lookups,
+                ctl_challenges,
+                inner_config,
+                circuit_config,
+            )
+        };
+    }
+
+    match table {
+        Table::Arithmetic => verify!(arithmetic_stark),
+        Table::BytePacking => verify!(byte_packing_stark),
+        Table::Cpu => verify!(cpu_stark),
+        Table::Keccak => verify!(keccak_stark),
+        Table::KeccakSponge => verify!(keccak_sponge_stark),
+        Table::Logic => verify!(logic_stark),
+        Table::Memory => verify!(memory_stark),
+        Table::MemBefore => verify!(mem_before_stark),
+        Table::MemAfter => verify!(mem_after_stark),
+        #[cfg(feature = "cdk_erigon")]
+        Table::Poseidon => verify!(poseidon_stark),
+    }
+}
+
+/// Like [`recursively_verify_table`], but for any `stark`, given the
+/// `cross_table_lookups` it takes part in as table `table`, so that custom
+/// STARKs can be recursively verified without an [`AllStark`].
+///
+/// The challenger state the STARK proof started from is taken from
+/// `proof_with_metadata`. The same caveats as for [`recursively_verify_table`]
+/// apply.
+pub fn recursively_verify_stark<F, C, S, const D: usize>(
+    stark: &S,
+    table: TableIdx,
+    proof_with_metadata: &StarkProofWithMetadata<F, C, D>,
+    cross_table_lookups: &[CrossTableLookup<F>],
+    ctl_challenges: &GrandProductChallengeSet<F>,
+    inner_config: &StarkConfig,
+    circuit_config: &CircuitConfig,
+) -> Result<(ProofWithPublicInputs<F, C, D>, VerifierCircuitData<F, C, D>)>
+where
+    F: RichField + Extendable<D>,
+    C: GenericConfig<D, F = F>,
+    C::Hasher: AlgebraicHasher<F>,
+    S: Stark<F, D>,
+{
+    let circuit = recursive_stark_circuit(
+        table,
+        stark,
+        proof_with_metadata.proof.recover_degree_bits(inner_config),
+        cross_table_lookups,
+        inner_config,
+        circuit_config,
+        0,
+        RecursionOptions::default(),
+    );
+    let proof = circuit.prove(proof_with_metadata, ctl_challenges)?;
+
+    Ok((proof, circuit.circuit.verifier_data()))
+}
+
+/// Estimates the peak memory, in bytes, needed to prove the recursive circuit
+/// of a `stark` proof of degree `2^degree_bits`, with `num_ctl_zs` CTL `Z`
+/// polynomials, without building the circuit.
+///
+/// This is only an approximation: recursive STARK verifiers are dominated by
+/// the Merkle openings of the FRI queries, which take one Poseidon gate per
+/// hashed row, so we count those and double it to account for the constraint
+/// and FRI arithmetic. See [`recursion_proving_memory`] for how a number of
+/// circuit rows translates into memory.
+pub fn estimate_recursion_memory<F: RichField + Extendable<D>, S: Stark<F, D>, const D: usize>(
+    stark: &S,
+    degree_bits: usize,
+    num_ctl_zs: usize,
+    inner_config: &StarkConfig,
+    circuit_config: &CircuitConfig,
+) -> usize {
+    // Elements absorbed per Poseidon permutation.
+    const SPONGE_RATE: usize = 8;
+    let fri_config = &inner_config.fri_config;
+    let lde_bits = degree_bits + fri_config.rate_bits;
+    let merkle_path = lde_bits.saturating_sub(fri_config.cap_height);
+    let oracle_widths = [
+        S::COLUMNS,
+        stark.num_lookup_helper_columns(inner_config) + num_ctl_zs,
+        inner_config.num_challenges * stark.quotient_degree_factor(),
+    ];
+    let oracle_rows: usize = oracle_widths
+        .iter()
+        .map(|width| width.div_ceil(SPONGE_RATE) + merkle_path)
+        .sum();
+    // Successive FRI layers have shorter and shorter Merkle paths; assume the
+    // usual arity of 16.
+    let fri_rows = lde_bits * lde_bits / 8;
+    let rows = 2 * fri_config.num_query_rounds * (oracle_rows + fri_rows);
+    recursion_proving_memory(circuit_config, log2_ceil(rows))
+}
+
+/// Like [`estimate_recursion_memory`], for the STARK proof of degree
+/// `2^degree_bits` of `table`.
+pub fn estimate_table_recursion_memory<F: RichField + Extendable<D>, const D: usize>(
+    all_stark: &AllStark<F, D>,
+    table: Table,
+    degree_bits: usize,
+    inner_config: &StarkConfig,
+    circuit_config: &CircuitConfig,
+) -> usize {
+    let num_ctl_zs = all_stark.num_ctl_zs(inner_config)[*table];
+    macro_rules! estimate {
+        ($stark:ident) => {
+            estimate_recursion_memory(
+                &all_stark.$stark,
+                degree_bits,
+                num_ctl_zs,
+                inner_config,
+                circuit_config,
+            )
+        };
+    }
+
+    match table {
+        Table::Arithmetic => estimate!(arithmetic_stark),
+        Table::BytePacking => estimate!(byte_packing_stark),
+        Table::Cpu => estimate!(cpu_stark),
+        Table::Keccak => estimate!(keccak_stark),
+        Table::KeccakSponge => estimate!(keccak_sponge_stark),
+        Table::Logic => estimate!(logic_stark),
+        Table::Memory => estimate!(memory_stark),
+        Table::MemBefore => estimate!(mem_before_stark),
+        Table::MemAfter => estimate!(mem_after_stark),
+        #[cfg(feature = "cdk_erigon")]
+        Table::Poseidon => estimate!(poseidon_stark),
+    }
+}
+
+/// Approximates the peak memory, in bytes, needed to prove a circuit of
+/// `2^degree_bits` rows: the witness, the low-degree extensions of the
+/// wires, constants, permutation and quotient polynomials, and their Merkle
+/// trees.
+pub fn recursion_proving_memory(circuit_config: &CircuitConfig, degree_bits: usize) -> usize {
+    const ELEMENT_BYTES: usize = 8;
+    const DIGEST_BYTES: usize = 32;
+    const NUM_ORACLES: usize = 4;
+    let rows = 1 << degree_bits;
+    let lde_rows = rows << circuit_config.fri_config.rate_bits;
+    let num_partial_products = circuit_config
+        .num_routed_wires
+        .div_ceil(circuit_config.max_quotient_degree_factor - 1);
+    let lde_polys = circuit_config.num_wires
+        + circuit_config.num_constants
+        + circuit_config.num_routed_wires
+        + circuit_config.num_challenges
+            * (1 + num_partial_products + circuit_config.max_quotient_degree_factor);
+    ELEMENT_BYTES * (rows * circuit_config.num_wires + lde_rows * lde_polys)
+        // A Merkle tree has about twice as many digests as leaves.
+        + NUM_ORACLES * 2 * lde_rows * DIGEST_BYTES
+}
+
+/// Verifies the FRI opening proof of a single STARK proof, without checking its
+/// quotient polynomial identities.
+///
+/// `verify_stark_proof_with_challenges_circuit` does both at once, so this is
+/// meant to tell FRI failures apart from constraint failures.
+///
+/// The openings must be batched exactly as `starky` does it: the local values
+/// and auxiliary and quotient polynomials at `zeta`, the next values and
+/// auxiliary polynomials at `g * zeta`, and the CTL `Z` polynomials at 1.
+/// `starky` keeps `StarkOpeningSetTarget::to_fri_openings` private to the
+/// crate, and only calls it from within its full verifier, so this layout has
+/// to be mirrored here, and kept in sync with the FRI instance returned by
+/// [`Stark::fri_instance_target`]; `fri_for_table` checks it against real
+/// proofs.
+///
+/// `proof` must have the openings of `table` in the given
+/// `cross_table_lookups`, which is checked before adding any gates.
+pub fn verify_fri_for_table<F, C, S, const D: usize>(
+    builder: &mut CircuitBuilder<F, D>,
+    table: TableIdx,
+    stark: &S,
+    cross_table_lookups: &[CrossTableLookup<F>],
+    proof: &StarkProofTarget<D>,
+    challenges: &StarkProofChallengesTarget<D>,
+    degree_bits: usize,
+    config: &StarkConfig,
+) where
+    F: RichField + Extendable<D>,
+    C: GenericConfig<D, F = F>,
+    C::Hasher: AlgebraicHasher<F>,
+    S: Stark<F, D>,
+{
+    let (num_ctl_helpers, num_ctl_zs, _) = CrossTableLookup::num_ctl_helpers_zs_all(
+        cross_table_lookups,
+        table,
+        config.num_challenges,
+        stark.constraint_degree(),
+    );
+    check_auxiliary_openings_len(
+        proof,
+        stark.num_lookup_helper_columns(config),
+        num_ctl_zs + num_ctl_helpers,
+        num_ctl_zs,
+    );
+    check_quotient_openings_len(proof, stark.quotient_degree_factor(), config.num_challenges);
+
+    let openings = &proof.openings;
+
+    let zero = builder.zero();
+    let mut batches = vec![
+        FriOpeningBatchTarget {
+            values: openings
+                .local_values
+                .iter()
+                .chain(openings.auxiliary_polys.iter().flatten())
+                .chain(openings.quotient_polys.iter().flatten())
+                .copied()
+                .collect(),
+        },
+        FriOpeningBatchTarget {
+            values: openings
+                .next_values
+                .iter()
+                .chain(openings.auxiliary_polys_next.iter().flatten())
+                .copied()
+                .collect(),
+        },
+    ];
+    if num_ctl_zs > 0 {
+        batches.push(FriOpeningBatchTarget {
+            values: openings
+                .ctl_zs_first
+                .iter()
+                .flatten()
+                .map(|&z| z.to_ext_target(zero))
+                .collect(),
+        });
+    }
+
+    let fri_instance = stark.fri_instance_target(
+        builder,
+        challenges.stark_zeta,
+        F::primitive_root_of_unity(degree_bits),
+        num_ctl_helpers,
+        num_ctl_zs,
+        config,
+    );
+    let merkle_caps = iter::once(proof.trace_cap.clone())
+        .chain(proof.auxiliary_polys_cap.clone())
+        .chain(proof.quotient_polys_cap.clone())
+        .collect::<Vec<_>>();
+    builder.verify_fri_proof::<C>(
+        &fri_instance,
+        &FriOpeningsTarget { batches },
+        &challenges.fri_challenges,
+        &merkle_caps,
+        &proof.opening_proof,
+        &config.fri_params(degree_bits),
+    );
+}
+
+/// Checks that the auxiliary openings of `target` hold the lookup helper
+/// columns followed by the CTL helper and `Z` columns.
+///
+/// The recursive verifier slices the auxiliary openings assuming this layout,
+/// so a mismatch would otherwise silently misread columns.
+fn check_auxiliary_openings_len<const D: usize>(
+    target: &StarkProofTarget<D>,
+    num_lookup_columns: usize,
+    num_ctl_helper_zs: usize,
+    num_ctl_zs: usize,
+) {
+    let num_auxiliary_polys = target.openings.auxiliary_polys.as_ref().map_or(0, Vec::len);
+    assert_eq!(
+        num_auxiliary_polys,
+        num_lookup_columns + num_ctl_helper_zs,
+        "expected {} lookup and {} CTL auxiliary openings, but the proof target has {}",
+        num_lookup_columns,
+        num_ctl_helper_zs,
+        num_auxiliary_polys
+    );
+    let num_ctl_zs_first = target.openings.ctl_zs_first.as_ref().map_or(0, Vec::len);
+    assert_eq!(
+        num_ctl_zs_first, num_ctl_zs,
+        "expected {} CTL openings, but the proof target has {}",
+        num_ctl_zs, num_ctl_zs_first
+    );
+}
+
+/// Checks that `target` opens `quotient_degree_factor` quotient chunks for
+/// each of the `num_challenges` vanishing polynomials.
+///
+/// The recursive verifier recombines the quotient openings in chunks of
+/// `quotient_degree_factor`, stopping at the shortest of the chunks and the
+/// vanishing polynomials, so fewer openings would silently check fewer
+/// identities.
+fn check_quotient_openings_len<const D: usize>(
+    target: &StarkProofTarget<D>,
+    quotient_degree_factor: usize,
+    num_challenges: usize,
+) {
+    let num_quotient_polys = target.openings.quotient_polys.as_ref().map_or(0, Vec::len);
+    assert_eq!(
+        num_quotient_polys,
+        quotient_degree_factor * num_challenges,
+        "expected {} quotient chunks for each of {} challenges, but the proof target has {} \
+         quotient openings",
+        quotient_degree_factor,
+        num_challenges,
+        num_quotient_polys
+    );
+}
+
 /// Add gates that are sometimes used by recursive circuits, even if it's not
 /// actually used by this particular recursive circuit. This is done for
 /// uniformity. We sometimes want all recursion circuits to have the same gate
@@ -328,6 +941,105 @@ pub(crate) fn add_common_recursion_gates<F: RichField + Extendable<D>, const D:
     )));
 }
 
+/// Hashes the `constants_sigmas_cap`s of `verifier_data`, e.g. one for each
+/// table, into a single anchor, so that on-chain verifiers need to store only
+/// one circuit commitment.
+///
+/// See [`verifier_data_anchor`] for the native version.
+pub fn verifier_data_anchor_circuit<F: RichField + Extendable<D>, const D: usize>(
+    builder: &mut CircuitBuilder<F, D>,
+    verifier_data: &[VerifierCircuitTarget],
+) -> HashOutTarget {
+    let elements = verifier_data
+        .iter()
+        .flat_map(|vd| &vd.constants_sigmas_cap.0)
+        .flat_map(|h| h.elements)
+        .collect();
+    builder.hash_n_to_hash_no_pad::<PoseidonHash>(elements)
+}
+
+/// Native version of [`verifier_data_anchor_circuit`].
+pub fn verifier_data_anchor<F, C, const D: usize>(
+    verifier_data: &[VerifierOnlyCircuitData<C, D>],
+) -> HashOut<F>
+where
+    F: RichField + Extendable<D>,
+    C: GenericConfig<D, F = F>,
+{
+    let elements = verifier_data
+        .iter()
+        .flat_map(|vd| vd.constants_sigmas_cap.flatten())
+        .collect::<Vec<_>>();
+    PoseidonHash::hash_no_pad(&elements)
+}
+
+/// Builds a Merkle tree whose leaves are the circuit digests of
+/// `verifier_data`, e.g. the circuits allowed by an upgradable on-chain
+/// verifier, which only needs to store the root.
+///
+/// The number of circuits must be a power of two.
+/// See [`verify_with_verifier_merkle_proof`].
+pub fn verifier_data_merkle_tree<F, C, const D: usize>(
+    verifier_data: &[VerifierOnlyCircuitData<C, D>],
+) -> MerkleTree<F, C::Hasher>
+where
+    F: RichField + Extendable<D>,
+    C: GenericConfig<D, F = F>,
+{
+    let leaves = verifier_data
+        .iter()
+        .map(|vd| vd.circuit_digest.to_vec())
+        .collect();
+    MerkleTree::new(leaves, 0)
+}
+
+/// Checks that the circuit digest of `verifier_data` is the leaf at
+/// `leaf_index` of the Merkle tree with root `root`, as proven by
+/// `merkle_proof`, and then verifies `proof` against `verifier_data`.
+///
+/// The digest is recomputed from the constants and sigmas cap and the degree of
+/// `verifier_data` beforehand, so that a committed digest can't be paired with
+/// the cap of another circuit. This mirrors `CircuitBuilder::build`, without a
+/// domain separator.
+///
+/// See [`verifier_data_merkle_tree`].
+pub fn verify_with_verifier_merkle_proof<F, C, const D: usize>(
+    proof: ProofWithPublicInputs<F, C, D>,
+    verifier_data: &VerifierCircuitData<F, C, D>,
+    leaf_index: usize,
+    merkle_proof: &MerkleProof<F, C::Hasher>,
+    root: <C::Hasher as Hasher<F>>::Hash,
+) -> Result<()>
+where
+    F: RichField + Extendable<D>,
+    C: GenericConfig<D, F = F>,
+{
+    let VerifierCircuitData {
+        verifier_only,
+        common,
+    } = verifier_data;
+    let circuit_digest = C::Hasher::hash_no_pad(
+        &[
+            verifier_only.constants_sigmas_cap.flatten(),
+            C::Hasher::hash_pad(&[]).to_vec(),
+            vec![F::from_canonical_usize(common.degree_bits())],
+        ]
+        .concat(),
+    );
+    ensure!(
+        circuit_digest == verifier_only.circuit_digest,
+        "the circuit digest doesn't match the rest of the verifier data"
+    );
+    verify_merkle_proof(
+        verifier_only.circuit_digest.to_vec(),
+        leaf_index,
+        root,
+        merkle_proof,
+    )
+    .context("the verifier data isn't committed to by the Merkle root")?;
+    verifier_data.verify(proof)
+}
+
 /// Recursive version of `get_memory_extra_looking_sum`.
 pub(crate) fn get_memory_extra_looking_sum_circuit<F: RichField + Extendable<D>, const D: usize>(
     builder: &mut CircuitBuilder<F, D>,
@@ -690,6 +1402,23 @@ pub(crate) fn add_virtual_public_values_public_input<
     }
 }
 
+/// Connects the public values `outer` of an aggregation circuit to those of an
+/// inner proof, given by its public inputs.
+///
+/// Public values are always the first public inputs of our circuits, so any
+/// public inputs that follow, e.g. verifier data, are ignored.
+pub fn connect_public_values<F: RichField + Extendable<D>, const D: usize>(
+    builder: &mut CircuitBuilder<F, D>,
+    outer: &PublicValuesTarget,
+    inner_public_inputs: &[Target],
+) {
+    PublicValuesTarget::connect(
+        builder,
+        outer.clone(),
+        PublicValuesTarget::from_public_inputs(inner_public_inputs),
+    );
+}
+
 pub(crate) fn add_virtual_burn_addr<F: RichField + Extendable<D>, const D: usize>(
     builder: &mut CircuitBuilder<F, D>,
 ) -> BurnAddrTarget {
@@ -1195,3 +1924,901 @@ where
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use ethereum_types::H256;
+    use plonky2::field::goldilocks_field::GoldilocksField;
+    use plonky2::field::polynomial::PolynomialCoeffs;
+    use plonky2::field::types::Sample;
+    use plonky2::fri::proof::FriProof;
+    use plonky2::hash::poseidon::PoseidonPermutation;
+    use plonky2::iop::ext_target::ExtensionTarget;
+    use plonky2::plonk::config::PoseidonGoldilocksConfig;
+    use starky::proof::StarkOpeningSet;
+
+    use super::*;
+
+    type F = GoldilocksField;
+    type C = PoseidonGoldilocksConfig;
+    const D: usize = 2;
+
+    const NUM_CTL_ZS: usize = 3;
+
+    fn public_inputs<P: PlonkyPermutation<F>>(config: &StarkConfig) -> Vec<F> {
+        let len = 4 * config.fri_config.num_cap_elements()
+            + 2 * config.num_challenges
+            + 2 * P::WIDTH
+            + NUM_CTL_ZS;
+        F::rand_vec(len)
+    }
+
+    fn check_challenger_states<P: PlonkyPermutation<F>>(config: &StarkConfig) {
+        let v = public_inputs::<P>(config);
+        let pis = PublicInputs::<F, P>::from_vec(&v, config, false, NUM_CTL_ZS);
+
+        let states_start = 4 * config.fri_config.num_cap_elements() + 2 * config.num_challenges;
+        assert_eq!(
+            pis.challenger_state_before.as_ref(),
+            &v[states_start..states_start + P::WIDTH]
+        );
+        assert_eq!(
+            pis.challenger_state_after.as_ref(),
+            &v[states_start + P::WIDTH..states_start + 2 * P::WIDTH]
+        );
+        assert_eq!(pis.ctl_zs_first, &v[states_start + 2 * P::WIDTH..]);
+    }
+
+    #[test]
+    fn from_vec_with_challenger_states() {
+        check_challenger_states::<PoseidonPermutation<F>>(&StarkConfig::standard_fast_config());
+    }
+
+    /// A permutation with a different width than Poseidon's, only used to
+    /// check the parsing of challenger states.
+    ///
+    /// It rotates its state and increments its first element, which is a
+    /// permutation, although not a cryptographic one.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    struct NarrowPermutation([F; 8]);
+
+    impl AsRef<[F]> for NarrowPermutation {
+        fn as_ref(&self) -> &[F] {
+            &self.0
+        }
+    }
+
+    impl PlonkyPermutation<F> for NarrowPermutation {
+        const RATE: usize = 4;
+        const WIDTH: usize = 8;
+
+        fn new<I: IntoIterator<Item = F>>(elts: I) -> Self {
+            let mut perm = Self::default();
+            perm.set_from_iter(elts, 0);
+            perm
+        }
+
+        fn set_elt(&mut self, elt: F, idx: usize) {
+            self.0[idx] = elt;
+        }
+
+        fn set_from_slice(&mut self, elts: &[F], start_idx: usize) {
+            self.0[start_idx..start_idx + elts.len()].copy_from_slice(elts);
+        }
+
+        fn set_from_iter<I: IntoIterator<Item = F>>(&mut self, elts: I, start_idx: usize) {
+            for (s, e) in self.0[start_idx..].iter_mut().zip(elts) {
+                *s = e;
+            }
+        }
+
+        fn permute(&mut self) {
+            self.0.rotate_left(1);
+            self.0[0] += F::ONE;
+        }
+
+        fn squeeze(&self) -> &[F] {
+            &self.0[..Self::RATE]
+        }
+    }
+
+    #[test]
+    fn from_vec_with_narrow_challenger_states() {
+        check_challenger_states::<NarrowPermutation>(&StarkConfig::standard_fast_config());
+    }
+
+    #[test]
+    #[should_panic(expected = "too short")]
+    fn from_vec_too_short_for_challenger_states() {
+        let config = StarkConfig::standard_fast_config();
+        let v = public_inputs::<NarrowPermutation>(&config);
+        PublicInputs::<F, PoseidonPermutation<F>>::from_vec(
+            &v[..v.len() - 3],
+            &config,
+            false,
+            NUM_CTL_ZS,
+        );
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "public inputs have 4 CTL values, but the cross-table lookups expect 3"
+    )]
+    fn from_vec_too_long_for_ctl_zs() {
+        let config = StarkConfig::standard_fast_config();
+        let mut v = public_inputs::<PoseidonPermutation<F>>(&config);
+        v.push(F::ONE);
+        PublicInputs::<F, PoseidonPermutation<F>>::from_vec(&v, &config, false, NUM_CTL_ZS);
+    }
+
+    #[test]
+    fn from_vec_with_trace_cap_hash() {
+        let config = StarkConfig::standard_fast_config();
+        let mut v = public_inputs::<PoseidonPermutation<F>>(&config);
+        let hash = F::rand_vec(NUM_HASH_OUT_ELTS);
+        v.extend(&hash);
+        let pis =
+            PublicInputs::<F, PoseidonPermutation<F>>::from_vec(&v, &config, true, NUM_CTL_ZS);
+
+        assert_eq!(pis.trace_cap_hash, Some(hash));
+        assert_eq!(
+            pis.ctl_zs_first,
+            &v[v.len() - NUM_HASH_OUT_ELTS - NUM_CTL_ZS..][..NUM_CTL_ZS]
+        );
+    }
+
+    #[test]
+    fn recursive_stark_circuit_cap_height_override() {
+        let all_stark = AllStark::<F, D>::default();
+        let config = StarkConfig::standard_fast_config();
+        let build = |cap_height| {
+            recursive_stark_circuit::<F, C, _, D>(
+                *Table::Arithmetic,
+                &all_stark.arithmetic_stark,
+                8,
+                &all_stark.cross_table_lookups,
+                &config,
+                &CircuitConfig::standard_recursion_config(),
+                12,
+                RecursionOptions {
+                    cap_height,
+                    ..Default::default()
+                },
+            )
+        };
+
+        let default = build(None);
+        assert_eq!(
+            default.circuit.common.config.fri_config.cap_height,
+            CircuitConfig::standard_recursion_config()
+                .fri_config
+                .cap_height
+        );
+
+        let overridden = build(Some(2));
+        assert_eq!(overridden.circuit.common.config.fri_config.cap_height, 2);
+        assert_eq!(
+            overridden
+                .circuit
+                .verifier_only
+                .constants_sigmas_cap
+                .height(),
+            2
+        );
+    }
+
+    #[test]
+    fn recursion_memory_estimate() {
+        let all_stark = AllStark::<F, D>::default();
+        let config = StarkConfig::standard_fast_config();
+        let circuit_config = CircuitConfig::standard_recursion_config();
+        let (_, num_ctl_zs, _) = CrossTableLookup::num_ctl_helpers_zs_all(
+            &all_stark.cross_table_lookups,
+            *Table::Logic,
+            config.num_challenges,
+            all_stark.logic_stark.constraint_degree(),
+        );
+        let estimate = |degree_bits, config: &StarkConfig| {
+            estimate_recursion_memory(
+                &all_stark.logic_stark,
+                degree_bits,
+                num_ctl_zs,
+                config,
+                &circuit_config,
+            )
+        };
+
+        // The estimate is checked against measured usage in
+        // `tests/recursion_memory.rs`, as it needs to prove a block.
+        assert!(estimate(12, &config) <= estimate(20, &config));
+        let mut fewer_queries = StarkConfig::standard_fast_config();
+        fewer_queries.fri_config.num_query_rounds /= 4;
+        assert!(estimate(12, &fewer_queries) < estimate(12, &config));
+        assert!(
+            recursion_proving_memory(&circuit_config, 12)
+                < recursion_proving_memory(&circuit_config, 13)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "cap height")]
+    fn recursive_stark_circuit_cap_height_too_large() {
+        let all_stark = AllStark::<F, D>::default();
+        let config = StarkConfig::standard_fast_config();
+        recursive_stark_circuit::<F, C, _, D>(
+            *Table::Arithmetic,
+            &all_stark.arithmetic_stark,
+            8,
+            &all_stark.cross_table_lookups,
+            &config,
+            &CircuitConfig::standard_recursion_config(),
+            12,
+            RecursionOptions {
+                cap_height: Some(64),
+                ..Default::default()
+            },
+        );
+    }
+
+    fn dummy_public_values() -> PublicValues<F> {
+        PublicValues {
+            trie_roots_before: TrieRoots {
+                state_root: H256::repeat_byte(1),
+                transactions_root: H256::repeat_byte(2),
+                receipts_root: H256::repeat_byte(3),
+            },
+            trie_roots_after: TrieRoots {
+                state_root: H256::repeat_byte(4),
+                transactions_root: H256::repeat_byte(5),
+                receipts_root: H256::repeat_byte(6),
+            },
+            burn_addr: cfg!(feature = "cdk_erigon").then_some(U256::from(0xdead)),
+            block_metadata: BlockMetadata {
+                block_timestamp: 0x1234.into(),
+                block_number: 42.into(),
+                block_chain_id: 1.into(),
+                block_base_fee: 0xa.into(),
+                block_gaslimit: 0xff112233u32.into(),
+                ..Default::default()
+            },
+            extra_block_data: ExtraBlockData {
+                txn_number_after: 2.into(),
+                gas_used_after: 21000.into(),
+                ..Default::default()
+            },
+            mem_before: MemCap {
+                mem_cap: (0..DEFAULT_CAP_LEN)
+                    .map(|i| from_fn(|j| U256::from(4 * i + j)))
+                    .collect(),
+            },
+            mem_after: MemCap {
+                mem_cap: (0..DEFAULT_CAP_LEN)
+                    .map(|i| from_fn(|j| U256::from(4 * i + j + 1)))
+                    .collect(),
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn public_values_to_public_inputs_round_trip() {
+        let public_values = dummy_public_values();
+        let pis = public_values.to_public_inputs::<D>().unwrap();
+        assert_eq!(PublicValues::from_public_inputs(&pis), public_values);
+    }
+
+    #[test]
+    fn public_values_to_public_inputs_length() {
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        add_virtual_public_values_public_input(&mut builder);
+        let pis = dummy_public_values().to_public_inputs::<D>().unwrap();
+        assert_eq!(pis.len(), builder.num_public_inputs());
+    }
+
+    fn prove_connected_public_values(tamper: bool) {
+        let public_values = dummy_public_values();
+        let mut inner_values = public_values.to_public_inputs::<D>().unwrap();
+        if tamper {
+            inner_values[0] += F::ONE;
+        }
+
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let outer = add_virtual_public_values_public_input(&mut builder);
+        let inner = builder.add_virtual_targets(PublicValuesTarget::SIZE);
+        connect_public_values(&mut builder, &outer, &inner);
+
+        let mut inputs = PartialWitness::new();
+        set_public_value_targets::<F, _, D>(&mut inputs, &outer, &public_values).unwrap();
+        inputs.set_target_arr(&inner, &inner_values);
+        let data = builder.build::<C>();
+        let proof = data.prove(inputs).unwrap();
+        data.verify(proof).unwrap();
+    }
+
+    #[test]
+    fn connected_public_values() {
+        prove_connected_public_values(false);
+    }
+
+    #[test]
+    #[should_panic]
+    fn mismatched_connected_public_values() {
+        prove_connected_public_values(true);
+    }
+
+    /// Adds a STARK proof target of the Logic table for the given degree.
+    fn logic_stark_proof_target(degree_bits: usize) -> StarkProofTarget<D> {
+        let all_stark = AllStark::<F, D>::default();
+        let config = StarkConfig::standard_fast_config();
+        let (total_num_helpers, num_ctl_zs, _) = CrossTableLookup::num_ctl_helpers_zs_all(
+            &all_stark.cross_table_lookups,
+            *Table::Logic,
+            config.num_challenges,
+            all_stark.logic_stark.constraint_degree(),
+        );
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        add_virtual_stark_proof(
+            &mut builder,
+            &all_stark.logic_stark,
+            &config,
+            degree_bits,
+            num_ctl_zs + total_num_helpers,
+            num_ctl_zs,
+        )
+    }
+
+    /// Returns an all-zero STARK proof with the shape of `target`, up to the
+    /// FRI query rounds which are left empty.
+    fn dummy_stark_proof(target: &StarkProofTarget<D>) -> StarkProof<F, C, D> {
+        let cap = |cap: &MerkleCapTarget| MerkleCap(vec![HashOut::ZERO; cap.0.len()]);
+        let zeros = |values: &Vec<ExtensionTarget<D>>| {
+            vec![<F as Extendable<D>>::Extension::ZERO; values.len()]
+        };
+        StarkProof {
+            trace_cap: cap(&target.trace_cap),
+            auxiliary_polys_cap: target.auxiliary_polys_cap.as_ref().map(cap),
+            quotient_polys_cap: target.quotient_polys_cap.as_ref().map(cap),
+            openings: StarkOpeningSet {
+                local_values: zeros(&target.openings.local_values),
+                next_values: zeros(&target.openings.next_values),
+                auxiliary_polys: target.openings.auxiliary_polys.as_ref().map(zeros),
+                auxiliary_polys_next: target.openings.auxiliary_polys_next.as_ref().map(zeros),
+                ctl_zs_first: target
+                    .openings
+                    .ctl_zs_first
+                    .as_ref()
+                    .map(|zs| vec![F::ZERO; zs.len()]),
+                quotient_polys: target.openings.quotient_polys.as_ref().map(zeros),
+            },
+            opening_proof: FriProof {
+                commit_phase_merkle_caps: target
+                    .opening_proof
+                    .commit_phase_merkle_caps
+                    .iter()
+                    .map(cap)
+                    .collect(),
+                query_round_proofs: vec![],
+                final_poly: PolynomialCoeffs::new(zeros(&target.opening_proof.final_poly.0)),
+                pow_witness: F::ZERO,
+            },
+        }
+    }
+
+    #[test]
+    fn stark_proof_shape() {
+        let target = logic_stark_proof_target(8);
+
+        // Only the FRI query rounds are missing, and they are checked last.
+        let e = check_stark_proof_shape(&target, &dummy_stark_proof(&target)).unwrap_err();
+        assert!(e.to_string().contains("number of FRI query rounds"), "{e}");
+
+        let other_degree = dummy_stark_proof(&logic_stark_proof_target(10));
+        let e = check_stark_proof_shape(&target, &other_degree).unwrap_err();
+        assert!(e.to_string().contains("proof degree"), "{e}");
+    }
+
+    #[test]
+    fn mismatched_cap_heights() {
+        let target = logic_stark_proof_target(8);
+
+        let mut proof = dummy_stark_proof(&target);
+        proof.trace_cap.0.truncate(proof.trace_cap.0.len() / 2);
+        let e = check_stark_proof_shape(&target, &proof).unwrap_err();
+        assert!(e.to_string().contains("trace cap height"), "{e}");
+
+        // Not a power of two, but rounding up to the height of the target.
+        let mut proof = dummy_stark_proof(&target);
+        proof.trace_cap.0.pop();
+        let e = check_stark_proof_shape(&target, &proof).unwrap_err();
+        assert!(e.to_string().contains("trace cap height"), "{e}");
+
+        let mut proof = dummy_stark_proof(&target);
+        proof.opening_proof.commit_phase_merkle_caps[0]
+            .0
+            .push(HashOut::ZERO);
+        let e = check_stark_proof_shape(&target, &proof).unwrap_err();
+        assert!(
+            e.to_string().contains("FRI commit phase cap 0 height"),
+            "{e}"
+        );
+    }
+
+    /// The number of lookup and CTL auxiliary columns of the Logic table.
+    fn logic_auxiliary_columns() -> (usize, usize, usize) {
+        let all_stark = AllStark::<F, D>::default();
+        let config = StarkConfig::standard_fast_config();
+        let (total_num_helpers, num_ctl_zs, _) = CrossTableLookup::num_ctl_helpers_zs_all(
+            &all_stark.cross_table_lookups,
+            *Table::Logic,
+            config.num_challenges,
+            all_stark.logic_stark.constraint_degree(),
+        );
+        (
+            all_stark.logic_stark.num_lookup_helper_columns(&config),
+            num_ctl_zs + total_num_helpers,
+            num_ctl_zs,
+        )
+    }
+
+    #[test]
+    fn quotient_openings_len() {
+        let all_stark = AllStark::<F, D>::default();
+        let config = StarkConfig::standard_fast_config();
+        let quotient_degree_factor = all_stark.logic_stark.quotient_degree_factor();
+        let target = logic_stark_proof_target(8);
+        check_quotient_openings_len(&target, quotient_degree_factor, config.num_challenges);
+
+        // A proof with a truncated set of quotient openings is rejected before
+        // setting the witness.
+        let mut proof = dummy_stark_proof(&target);
+        proof.openings.quotient_polys.as_mut().unwrap().pop();
+        let e = check_stark_proof_shape(&target, &proof).unwrap_err();
+        assert!(
+            e.to_string().contains("number of quotient polynomials"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "quotient openings")]
+    fn mismatched_quotient_openings_len() {
+        let all_stark = AllStark::<F, D>::default();
+        let config = StarkConfig::standard_fast_config();
+        let (_, num_ctl_helper_zs, num_ctl_zs) = logic_auxiliary_columns();
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let mut target = add_virtual_stark_proof(
+            &mut builder,
+            &all_stark.logic_stark,
+            &config,
+            8,
+            num_ctl_helper_zs,
+            num_ctl_zs,
+        );
+        // One quotient chunk short.
+        target.openings.quotient_polys.as_mut().unwrap().pop();
+        let mut challenger = RecursiveChallenger::<F, PoseidonHash, D>::new(&mut builder);
+        let challenges =
+            target.get_challenges::<F, C>(&mut builder, &mut challenger, None, true, &config);
+        verify_fri_for_table::<F, C, _, D>(
+            &mut builder,
+            *Table::Logic,
+            &all_stark.logic_stark,
+            &all_stark.cross_table_lookups,
+            &target,
+            &challenges,
+            8,
+            &config,
+        );
+    }
+
+    #[test]
+    fn auxiliary_openings_len() {
+        let (num_lookup_columns, num_ctl_helper_zs, num_ctl_zs) = logic_auxiliary_columns();
+        check_auxiliary_openings_len(
+            &logic_stark_proof_target(8),
+            num_lookup_columns,
+            num_ctl_helper_zs,
+            num_ctl_zs,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "auxiliary openings, but the proof target has")]
+    fn mismatched_auxiliary_openings_len() {
+        let all_stark = AllStark::<F, D>::default();
+        let config = StarkConfig::standard_fast_config();
+        let (_, num_ctl_helper_zs, num_ctl_zs) = logic_auxiliary_columns();
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        // The target has one more CTL than the Logic table takes part in.
+        let target = add_virtual_stark_proof(
+            &mut builder,
+            &all_stark.logic_stark,
+            &config,
+            8,
+            num_ctl_helper_zs + 1,
+            num_ctl_zs + 1,
+        );
+        let mut challenger = RecursiveChallenger::<F, PoseidonHash, D>::new(&mut builder);
+        let challenges =
+            target.get_challenges::<F, C>(&mut builder, &mut challenger, None, true, &config);
+        verify_fri_for_table::<F, C, _, D>(
+            &mut builder,
+            *Table::Logic,
+            &all_stark.logic_stark,
+            &all_stark.cross_table_lookups,
+            &target,
+            &challenges,
+            8,
+            &config,
+        );
+    }
+
+    /// Proves the wrapper circuit of `table` for an empty block, with the beta
+    /// and gamma of each CTL challenge swapped if `swap_challenges` is set.
+    #[cfg(feature = "eth_mainnet")]
+    fn prove_table_wrapper<S: Stark<F, D>>(
+        table: Table,
+        stark: impl Fn(&AllStark<F, D>) -> &S,
+        swap_challenges: bool,
+    ) -> Result<()> {
+        let all_stark = AllStark::<F, D>::default();
+        let config = StarkConfig::standard_fast_config();
+        let all_proof =
+            crate::testing_utils::prove_empty_block::<F, C, D>(&all_stark, &config)?.remove(0);
+        let circuit = recursive_stark_circuit::<F, C, _, D>(
+            *table,
+            stark(&all_stark),
+            all_proof.degree_bits(&config)[*table],
+            &all_stark.cross_table_lookups,
+            &config,
+            &CircuitConfig::standard_recursion_config(),
+            0,
+            RecursionOptions::default(),
+        );
+
+        let mut ctl_challenges = all_proof.multi_proof.ctl_challenges.clone();
+        if swap_challenges {
+            for challenge in &mut ctl_challenges.challenges {
+                *challenge = GrandProductChallenge {
+                    beta: challenge.gamma,
+                    gamma: challenge.beta,
+                };
+            }
+        }
+        let proof = circuit.prove(&all_proof.multi_proof.stark_proofs[*table], &ctl_challenges)?;
+        circuit.circuit.verify(proof)
+    }
+
+    #[cfg(feature = "eth_mainnet")]
+    #[test]
+    fn table_wrapper_challenges() -> Result<()> {
+        prove_table_wrapper(Table::Logic, |all_stark| &all_stark.logic_stark, false)?;
+        prove_table_wrapper(
+            Table::Arithmetic,
+            |all_stark| &all_stark.arithmetic_stark,
+            false,
+        )
+    }
+
+    // The Logic table has CTLs, but no lookups.
+    #[cfg(feature = "eth_mainnet")]
+    #[test]
+    #[should_panic(expected = "was set twice with different values")]
+    fn swapped_ctl_challenges() {
+        let _ = prove_table_wrapper(Table::Logic, |all_stark| &all_stark.logic_stark, true);
+    }
+
+    // The lookups of a table, e.g. the range checks of the Arithmetic table,
+    // are checked against its CTL challenges, which the root circuit connects
+    // to the ones derived from the challenger.
+    #[cfg(feature = "eth_mainnet")]
+    #[test]
+    #[should_panic(expected = "was set twice with different values")]
+    fn swapped_permutation_challenges() {
+        let _ = prove_table_wrapper(
+            Table::Arithmetic,
+            |all_stark| &all_stark.arithmetic_stark,
+            true,
+        );
+    }
+
+    #[cfg(feature = "eth_mainnet")]
+    #[test]
+    fn trace_cap_hash_public_input() -> Result<()> {
+        use plonky2::plonk::config::Hasher as _;
+
+        let all_stark = AllStark::<F, D>::default();
+        let config = StarkConfig::standard_fast_config();
+        let all_proof =
+            crate::testing_utils::prove_empty_block::<F, C, D>(&all_stark, &config)?.remove(0);
+        let circuit = recursive_stark_circuit::<F, C, _, D>(
+            *Table::Logic,
+            &all_stark.logic_stark,
+            all_proof.degree_bits(&config)[*Table::Logic],
+            &all_stark.cross_table_lookups,
+            &config,
+            &CircuitConfig::standard_recursion_config(),
+            0,
+            RecursionOptions {
+                with_trace_cap_hash: true,
+                ..Default::default()
+            },
+        );
+        let logic_proof = &all_proof.multi_proof.stark_proofs[*Table::Logic];
+        let proof = circuit.prove(logic_proof, &all_proof.multi_proof.ctl_challenges)?;
+        circuit.circuit.verify(proof.clone())?;
+
+        let (_, num_ctl_zs, _) = CrossTableLookup::num_ctl_helpers_zs_all(
+            &all_stark.cross_table_lookups,
+            *Table::Logic,
+            config.num_challenges,
+            all_stark.logic_stark.constraint_degree(),
+        );
+        let pis = PublicInputs::<F, PoseidonPermutation<F>>::from_vec(
+            &proof.public_inputs,
+            &config,
+            true,
+            num_ctl_zs,
+        );
+        let trace_cap = logic_proof.proof.trace_cap.flatten();
+        assert_eq!(pis.trace_cap.concat(), trace_cap);
+        assert_eq!(
+            pis.trace_cap_hash.unwrap(),
+            PoseidonHash::hash_no_pad(&trace_cap).elements
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn standalone_stark_recursion() -> Result<()> {
+        use plonky2::fri::oracle::PolynomialBatch;
+        use plonky2::util::timing::TimingTree;
+        use starky::cross_table_lookup::{get_ctl_data, TableWithColumns};
+        use starky::lookup::{Column, Filter};
+
+        use crate::keccak::keccak_stark::{KeccakStark, NUM_INPUTS};
+        use crate::prover::prove_single_table;
+
+        // A single Keccak table, looking into itself with an always-off filter,
+        // so that it has CTL polynomials without any other table.
+        let stark = KeccakStark::<F, D>::default();
+        let config = StarkConfig::standard_fast_config();
+        let table = || {
+            TableWithColumns::new(
+                0,
+                vec![Column::single(0)],
+                Filter::new_simple(Column::constant(F::ZERO)),
+            )
+        };
+        let cross_table_lookups = [CrossTableLookup::new(vec![table()], table())];
+
+        let timing = &mut TimingTree::default();
+        let trace = stark.generate_trace(vec![([0u64; NUM_INPUTS], 0)], 8, timing);
+        let commitment = PolynomialBatch::<F, C, D>::from_values(
+            trace.clone(),
+            config.fri_config.rate_bits,
+            false,
+            config.fri_config.cap_height,
+            timing,
+            None,
+        );
+        let mut challenger = Challenger::new();
+        challenger.observe_cap(&commitment.merkle_tree.cap);
+        let (ctl_challenges, [ctl_data]) = get_ctl_data::<F, C, D, 1>(
+            &config,
+            &[trace.clone()],
+            &cross_table_lookups,
+            &mut challenger,
+            stark.constraint_degree(),
+        );
+        let (proof, _) = prove_single_table(
+            &stark,
+            &config,
+            &trace,
+            &commitment,
+            &ctl_data,
+            &ctl_challenges,
+            &mut challenger,
+            timing,
+            None,
+        )?;
+
+        let (recursive_proof, verifier_data) = recursively_verify_stark(
+            &stark,
+            0,
+            &proof,
+            &cross_table_lookups,
+            &ctl_challenges,
+            &config,
+            &CircuitConfig::standard_recursion_config(),
+        )?;
+        verifier_data.verify(recursive_proof)
+    }
+
+    #[cfg(feature = "eth_mainnet")]
+    #[test]
+    fn single_table_recursion() -> Result<()> {
+        let all_stark = AllStark::<F, D>::default();
+        let config = StarkConfig::standard_fast_config();
+        let all_proof =
+            crate::testing_utils::prove_empty_block::<F, C, D>(&all_stark, &config)?.remove(0);
+
+        let (proof, verifier_data) = recursively_verify_table(
+            &all_stark,
+            Table::Cpu,
+            &all_proof.multi_proof.stark_proofs[*Table::Cpu],
+            &all_proof.multi_proof.ctl_challenges,
+            &config,
+            &CircuitConfig::standard_recursion_config(),
+        )?;
+        verifier_data.verify(proof)
+    }
+
+    /// Proves the FRI opening proof of the Logic STARK proof of `all_proof`,
+    /// with its query rounds replaced by those of `logic_proof`.
+    #[cfg(feature = "eth_mainnet")]
+    fn prove_logic_fri(
+        all_stark: &AllStark<F, D>,
+        config: &StarkConfig,
+        all_proof: &AllProof<F, C, D>,
+        logic_proof: &StarkProof<F, C, D>,
+    ) -> Result<()> {
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let targets = add_virtual_stark_proofs_like(&mut builder, all_stark, config, all_proof);
+        let target = &targets[*Table::Logic];
+
+        let ctl_challenges = GrandProductChallengeSet {
+            challenges: all_proof
+                .multi_proof
+                .ctl_challenges
+                .challenges
+                .iter()
+                .map(|challenge| GrandProductChallenge {
+                    beta: builder.constant(challenge.beta),
+                    gamma: builder.constant(challenge.gamma),
+                })
+                .collect(),
+        };
+        let init_challenger_state = builder.constants(
+            all_proof.multi_proof.stark_proofs[*Table::Logic]
+                .init_challenger_state
+                .as_ref(),
+        );
+        let mut challenger = RecursiveChallenger::<F, PoseidonHash, D>::from_state(
+            PoseidonPermutation::new(init_challenger_state),
+        );
+        let challenges = target.get_challenges::<F, C>(
+            &mut builder,
+            &mut challenger,
+            Some(&ctl_challenges),
+            true,
+            config,
+        );
+        verify_fri_for_table::<F, C, _, D>(
+            &mut builder,
+            *Table::Logic,
+            &all_stark.logic_stark,
+            &all_stark.cross_table_lookups,
+            target,
+            &challenges,
+            all_proof.degree_bits(config)[*Table::Logic],
+            config,
+        );
+
+        let zero = builder.zero();
+        let mut inputs = PartialWitness::new();
+        set_stark_proof_target(&mut inputs, target, logic_proof, zero);
+        let data = builder.build::<C>();
+        data.verify(data.prove(inputs)?)
+    }
+
+    #[cfg(feature = "eth_mainnet")]
+    #[test]
+    fn fri_for_table() -> Result<()> {
+        let all_stark = AllStark::<F, D>::default();
+        let config = StarkConfig::standard_fast_config();
+        let all_proof =
+            crate::testing_utils::prove_empty_block::<F, C, D>(&all_stark, &config)?.remove(0);
+        let logic_proof = &all_proof.multi_proof.stark_proofs[*Table::Logic].proof;
+        prove_logic_fri(&all_stark, &config, &all_proof, logic_proof)
+    }
+
+    #[cfg(feature = "eth_mainnet")]
+    #[test]
+    #[should_panic(expected = "was set twice with different values")]
+    fn corrupted_fri_for_table() {
+        let all_stark = AllStark::<F, D>::default();
+        let config = StarkConfig::standard_fast_config();
+        let all_proof = crate::testing_utils::prove_empty_block::<F, C, D>(&all_stark, &config)
+            .unwrap()
+            .remove(0);
+        let mut corrupted = all_proof.multi_proof.stark_proofs[*Table::Logic]
+            .proof
+            .clone();
+        corrupted.opening_proof.query_round_proofs[0]
+            .initial_trees_proof
+            .evals_proofs[0]
+            .0[0] += F::ONE;
+        // The Merkle root recomputed from the corrupted leaf conflicts with the
+        // trace cap, so witness generation fails.
+        prove_logic_fri(&all_stark, &config, &all_proof, &corrupted).unwrap();
+    }
+
+    #[test]
+    fn verifier_data_anchor_matches_circuit() {
+        let verifier_data = [1, 2, 3].map(|num_inputs| {
+            let mut builder =
+                CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+            let inputs = builder.add_virtual_public_input_arr::<3>();
+            let sum = builder.add_many(&inputs[..num_inputs]);
+            builder.register_public_input(sum);
+            builder.build::<C>().verifier_only
+        });
+
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let targets = verifier_data
+            .each_ref()
+            .map(|vd| builder.constant_verifier_data(vd));
+        let anchor = verifier_data_anchor_circuit(&mut builder, &targets);
+        builder.register_public_inputs(&anchor.elements);
+
+        let data = builder.build::<C>();
+        let proof = data.prove(PartialWitness::new()).unwrap();
+        assert_eq!(
+            proof.public_inputs,
+            verifier_data_anchor::<F, C, D>(&verifier_data).elements
+        );
+        data.verify(proof).unwrap();
+    }
+
+    #[test]
+    fn verifier_merkle_proof() -> Result<()> {
+        let circuits = [1, 2, 3, 4].map(|constant| {
+            let mut builder =
+                CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+            let constant = builder.constant(F::from_canonical_u64(constant));
+            builder.register_public_input(constant);
+            builder.build::<C>()
+        });
+        let tree = verifier_data_merkle_tree::<F, C, D>(
+            &circuits
+                .each_ref()
+                .map(|circuit| circuit.verifier_only.clone()),
+        );
+        let root = tree.cap.0[0];
+
+        let circuit = &circuits[2];
+        let proof = circuit.prove(PartialWitness::new())?;
+        verify_with_verifier_merkle_proof(
+            proof.clone(),
+            &circuit.verifier_data(),
+            2,
+            &tree.prove(2),
+            root,
+        )?;
+
+        // The membership proof of another circuit doesn't carry over.
+        assert!(verify_with_verifier_merkle_proof(
+            proof,
+            &circuit.verifier_data(),
+            1,
+            &tree.prove(1),
+            root,
+        )
+        .is_err());
+
+        // Neither does a committed digest with the cap of another circuit.
+        let mut forged = circuits[3].verifier_data();
+        forged.verifier_only.circuit_digest = circuit.verifier_only.circuit_digest;
+        let e = verify_with_verifier_merkle_proof(
+            circuits[3].prove(PartialWitness::new())?,
+            &forged,
+            2,
+            &tree.prove(2),
+            root,
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("circuit digest"), "{e}");
+        Ok(())
+    }
+}
@@ -9,9 +9,18 @@ use mpt_trie::{
     nibbles::Nibbles,
     partial_trie::{HashedPartialTrie, Node, PartialTrie},
 };
+use plonky2::field::extension::Extendable;
+use plonky2::hash::hash_types::RichField;
+use plonky2::plonk::config::GenericConfig;
+use plonky2::util::timing::TimingTree;
+use starky::config::StarkConfig;
 
+use crate::all_stark::AllStark;
 pub use crate::cpu::kernel::cancun_constants::*;
 pub use crate::cpu::kernel::constants::global_exit_root::*;
+use crate::generation::{GenerationInputs, TrieInputs};
+use crate::proof::{AllProof, TrieRoots, EMPTY_CONSOLIDATED_BLOCKHASH};
+use crate::prover::testing::prove_all_segments;
 use crate::{generation::mpt::AccountRlp, proof::BlockMetadata, util::h2u};
 
 pub const EMPTY_NODE_HASH: H256 = H256(hex!(
@@ -161,3 +170,94 @@ pub fn scalable_contract_from_storage(storage_trie: &HashedPartialTrie) -> Accou
         ..Default::default()
     }
 }
+
+/// Get `GenerationInputs` for a dummy payload, where the block has the given
+/// timestamp.
+pub fn dummy_payload<F: RichField>(
+    timestamp: u64,
+    is_first_payload: bool,
+) -> anyhow::Result<GenerationInputs<F>> {
+    let beneficiary = hex!("deadbeefdeadbeefdeadbeefdeadbeefdeadbeef");
+
+    let block_metadata = BlockMetadata {
+        block_beneficiary: beneficiary.into(),
+        block_timestamp: timestamp.into(),
+        block_number: 1.into(),
+        block_difficulty: 0x020000.into(),
+        block_random: H256::from_uint(&0x020000.into()),
+        block_gaslimit: 0xff112233u32.into(),
+        block_chain_id: 1.into(),
+        block_base_fee: 0xa.into(),
+        ..Default::default()
+    };
+
+    let (mut state_trie_before, mut storage_tries) = preinitialized_state_and_storage_tries()?;
+    let checkpoint_state_trie_root = state_trie_before.hash();
+    let mut beacon_roots_account_storage = storage_tries[0].1.clone();
+
+    update_beacon_roots_account_storage(
+        &mut beacon_roots_account_storage,
+        block_metadata.block_timestamp,
+        block_metadata.parent_beacon_block_root,
+    )?;
+    let updated_beacon_roots_account =
+        beacon_roots_contract_from_storage(&beacon_roots_account_storage);
+
+    if !is_first_payload {
+        // This isn't the first dummy payload being processed. We need to update the
+        // initial state trie to account for the update on the beacon roots contract.
+        state_trie_before.insert(
+            beacon_roots_account_nibbles(),
+            rlp::encode(&updated_beacon_roots_account).to_vec(),
+        )?;
+        storage_tries[0].1 = beacon_roots_account_storage;
+    }
+
+    let tries_before = TrieInputs {
+        state_trie: state_trie_before,
+        storage_tries,
+        ..Default::default()
+    };
+
+    let expected_state_trie_after: HashedPartialTrie = {
+        let mut state_trie_after = HashedPartialTrie::from(Node::Empty);
+        state_trie_after.insert(
+            beacon_roots_account_nibbles(),
+            rlp::encode(&updated_beacon_roots_account).to_vec(),
+        )?;
+
+        state_trie_after
+    };
+
+    let trie_roots_after = TrieRoots {
+        state_root: expected_state_trie_after.hash(),
+        transactions_root: tries_before.transactions_trie.hash(),
+        receipts_root: tries_before.receipts_trie.hash(),
+    };
+
+    let inputs = GenerationInputs {
+        tries: tries_before.clone(),
+        burn_addr: None,
+        trie_roots_after,
+        checkpoint_state_trie_root,
+        checkpoint_consolidated_hash: EMPTY_CONSOLIDATED_BLOCKHASH.map(F::from_canonical_u64),
+        block_metadata,
+        ..Default::default()
+    };
+
+    Ok(inputs)
+}
+
+/// Proves the first [`dummy_payload`] of an empty block, returning one
+/// [`AllProof`] per segment.
+pub fn prove_empty_block<F, C, const D: usize>(
+    all_stark: &AllStark<F, D>,
+    config: &StarkConfig,
+) -> anyhow::Result<Vec<AllProof<F, C, D>>>
+where
+    F: RichField + Extendable<D>,
+    C: GenericConfig<D, F = F>,
+{
+    let timing = &mut TimingTree::new("prove empty block", log::Level::Debug);
+    prove_all_segments(all_stark, config, dummy_payload(1, true)?, 20, timing, None)
+}
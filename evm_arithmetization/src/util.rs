@@ -225,6 +225,23 @@ pub(crate) fn get_h160<F: RichField>(slice: &[F]) -> H160 {
     )
 }
 
+/// Reconstructs an address from its 5 little-endian 32-bit limbs, as found in
+/// the public values, and errors if any limb is out of range.
+pub fn h160_from_limbs<F: RichField>(limbs: &[F]) -> anyhow::Result<H160> {
+    anyhow::ensure!(
+        limbs.len() == 5,
+        "expected 5 limbs for an address, got {}",
+        limbs.len()
+    );
+    for limb in limbs {
+        anyhow::ensure!(
+            limb.to_canonical_u64() <= u32::MAX as u64,
+            "address limb {limb} doesn't fit in 32 bits"
+        );
+    }
+    Ok(get_h160(limbs))
+}
+
 pub(crate) fn get_h256<F: RichField>(slice: &[F]) -> H256 {
     H256::from_slice(
         &slice
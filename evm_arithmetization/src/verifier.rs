@@ -1,8 +1,11 @@
-use anyhow::{ensure, Result};
-use ethereum_types::{BigEndianHash, U256};
+use anyhow::{ensure, Context as _, Result};
+use ethereum_types::{BigEndianHash, H256, U256};
 use itertools::Itertools;
+use mpt_trie::nibbles::Nibbles;
+use mpt_trie::partial_trie::{HashedPartialTrie, PartialTrie};
 use plonky2::field::extension::Extendable;
 use plonky2::field::polynomial::PolynomialValues;
+use plonky2::field::types::Field;
 use plonky2::fri::oracle::PolynomialBatch;
 use plonky2::hash::hash_types::RichField;
 use plonky2::hash::merkle_tree::MerkleCap;
@@ -10,18 +13,21 @@ use plonky2::plonk::config::{GenericConfig, GenericHashOut};
 use plonky2::util::timing::TimingTree;
 use plonky2::util::transpose;
 use starky::config::StarkConfig;
-use starky::cross_table_lookup::{get_ctl_vars_from_proofs, verify_cross_table_lookups};
-use starky::lookup::GrandProductChallenge;
+use starky::cross_table_lookup::{
+    get_ctl_vars_from_proofs, verify_cross_table_lookups, CrossTableLookup,
+};
+use starky::lookup::{GrandProductChallenge, GrandProductChallengeSet};
 use starky::stark::Stark;
 use starky::verifier::verify_stark_proof_with_challenges;
 
-use crate::all_stark::{AllStark, Table, NUM_TABLES};
+use crate::all_stark::{check_equal_cap_heights, AllStark, Table, NUM_TABLES};
 use crate::cpu::kernel::aggregator::KERNEL;
 use crate::cpu::kernel::constants::global_metadata::GlobalMetadata;
 use crate::memory::segments::Segment;
 use crate::memory::VALUE_LIMBS;
 use crate::proof::{AllProof, AllProofChallenges, PublicValues};
 use crate::util::h2u;
+use crate::Node;
 
 pub(crate) fn initial_memory_merkle_cap<
     F: RichField + Extendable<D>,
@@ -121,13 +127,106 @@ fn verify_proof<F: RichField + Extendable<D>, C: GenericConfig<D, F = F>, const
     all_proof: AllProof<F, C, D>,
     config: &StarkConfig,
     is_initial: bool,
+) -> Result<()> {
+    let challenges = all_proof
+        .get_challenges(config)
+        .map_err(|_| anyhow::Error::msg("Invalid sampling of proof challenges."))?;
+
+    verify_proof_with_challenges(all_stark, &all_proof, &challenges, config, is_initial)
+}
+
+/// Verifies `all_proof` against challenges previously sampled with
+/// [`AllProof::get_challenges`], so that callers verifying the same proof
+/// several times only need to sample them once.
+///
+/// `is_initial` must be set for the first segment of a block, whose initial
+/// memory is checked against the kernel.
+///
+/// The cross-table lookups are always taken from the verifier's `all_stark`:
+/// proofs don't carry lookup definitions, so they can't supply more
+/// permissive ones.
+pub fn verify_proof_with_challenges<
+    F: RichField + Extendable<D>,
+    C: GenericConfig<D, F = F>,
+    const D: usize,
+>(
+    all_stark: &AllStark<F, D>,
+    all_proof: &AllProof<F, C, D>,
+    challenges: &AllProofChallenges<F, D>,
+    config: &StarkConfig,
+    is_initial: bool,
+) -> Result<()> {
+    // The checks are ordered from cheapest to most expensive, so that invalid
+    // proofs are rejected before running any FRI verification if possible.
+    check_equal_cap_heights(
+        &all_proof.trace_cap_heights(),
+        all_stark.require_equal_cap_heights,
+    )?;
+
+    let ctl_zs_first = all_proof
+        .multi_proof
+        .stark_proofs
+        .each_ref()
+        .map(|p| p.proof.openings.ctl_zs_first.clone());
+    for (table, ctl_zs) in Table::all().iter().zip(&ctl_zs_first) {
+        ensure!(
+            ctl_zs.is_some(),
+            "missing CTL openings in {:?} STARK proof",
+            table
+        );
+    }
+    let ctl_zs_first = ctl_zs_first.map(|ctl_zs| ctl_zs.expect("checked above"));
+
+    let public_values = &all_proof.public_values;
+
+    verify_cross_table_lookups::<F, D, NUM_TABLES>(
+        &all_stark.cross_table_lookups,
+        ctl_zs_first,
+        Some(&extra_looking_sums(
+            public_values,
+            &challenges.ctl_challenges,
+            config,
+        )),
+        config,
+    )?;
+
+    // Verify shift table and kernel code.
+    if is_initial {
+        verify_initial_memory::<F, C, D>(public_values, config)?;
+    }
+
+    verify_stark_proofs(all_stark, all_proof, challenges, config, &Table::all())
+}
+
+/// Extra sums to add to the looked last value.
+/// Only necessary for the Memory values.
+fn extra_looking_sums<F: RichField + Extendable<D>, const D: usize>(
+    public_values: &PublicValues<F>,
+    ctl_challenges: &GrandProductChallengeSet<F>,
+    config: &StarkConfig,
+) -> Vec<Vec<F>> {
+    let mut extra_looking_sums = vec![vec![F::ZERO; config.num_challenges]; NUM_TABLES];
+
+    // Memory
+    extra_looking_sums[*Table::Memory] = (0..config.num_challenges)
+        .map(|i| get_memory_extra_looking_sum(public_values, ctl_challenges.challenges[i]))
+        .collect_vec();
+
+    extra_looking_sums
+}
+
+/// Verifies the STARK proofs of `tables`, skipping all others.
+fn verify_stark_proofs<F: RichField + Extendable<D>, C: GenericConfig<D, F = F>, const D: usize>(
+    all_stark: &AllStark<F, D>,
+    all_proof: &AllProof<F, C, D>,
+    challenges: &AllProofChallenges<F, D>,
+    config: &StarkConfig,
+    tables: &[Table],
 ) -> Result<()> {
     let AllProofChallenges {
         stark_challenges,
         ctl_challenges,
-    } = all_proof
-        .get_challenges(config)
-        .map_err(|_| anyhow::Error::msg("Invalid sampling of proof challenges."))?;
+    } = challenges;
 
     let num_lookup_columns = all_stark.num_lookups_helper_columns(config);
 
@@ -144,12 +243,13 @@ fn verify_proof<F: RichField + Extendable<D>, C: GenericConfig<D, F = F>, const
         #[cfg(feature = "cdk_erigon")]
         poseidon_stark,
         cross_table_lookups,
+        require_equal_cap_heights: _,
     } = all_stark;
 
     let ctl_vars_per_table = get_ctl_vars_from_proofs(
         &all_proof.multi_proof,
         cross_table_lookups,
-        &ctl_challenges,
+        ctl_challenges,
         &num_lookup_columns,
         all_stark.arithmetic_stark.constraint_degree(),
     );
@@ -158,14 +258,17 @@ fn verify_proof<F: RichField + Extendable<D>, C: GenericConfig<D, F = F>, const
 
     macro_rules! verify_table {
         ($stark:ident, $table:expr) => {
-            verify_stark_proof_with_challenges(
-                $stark,
-                &stark_proofs[*$table].proof,
-                &stark_challenges[*$table],
-                Some(&ctl_vars_per_table[*$table]),
-                &[],
-                config,
-            )?;
+            if tables.contains(&$table) {
+                verify_stark_proof_with_challenges(
+                    $stark,
+                    &stark_proofs[*$table].proof,
+                    &stark_challenges[*$table],
+                    Some(&ctl_vars_per_table[*$table]),
+                    &[],
+                    config,
+                )
+                .with_context(|| format!("verifying {:?} STARK proof", $table))?;
+            }
         };
     }
 
@@ -182,31 +285,177 @@ fn verify_proof<F: RichField + Extendable<D>, C: GenericConfig<D, F = F>, const
     #[cfg(feature = "cdk_erigon")]
     verify_table!(poseidon_stark, Table::Poseidon);
 
-    let public_values = all_proof.public_values;
+    Ok(())
+}
 
-    // Verify shift table and kernel code.
-    if is_initial {
-        verify_initial_memory::<F, C, D>(&public_values, config)?;
+/// Checks the cross-table lookups whose looking and looked tables all belong
+/// to `tables`, ignoring the others.
+///
+/// `starky` doesn't expose which tables a lookup involves, so we recover it
+/// with [`ctl_tables`], and then consume the `Z` openings of every table in
+/// the order [`verify_cross_table_lookups`] does: lookup by lookup, challenge
+/// by challenge, looking tables first.
+fn verify_cross_table_lookups_subset<F: RichField + Extendable<D>, const D: usize>(
+    all_stark: &AllStark<F, D>,
+    ctl_zs_first: &[Vec<F>; NUM_TABLES],
+    extra_looking_sums: &[Vec<F>],
+    config: &StarkConfig,
+    tables: &[Table],
+) -> Result<()> {
+    let mut ctl_zs_openings = ctl_zs_first.each_ref().map(|zs| zs.iter());
+    for (index, ctl) in all_stark.cross_table_lookups.iter().enumerate() {
+        let (looking_tables, looked_table) = ctl_tables(ctl)?;
+        let in_subset = looking_tables
+            .iter()
+            .chain([&looked_table])
+            .all(|table| tables.contains(table));
+
+        let mut next_z = |table: Table| {
+            ctl_zs_openings[*table]
+                .next()
+                .copied()
+                .with_context(|| format!("missing CTL openings for {:?}", table))
+        };
+        for c in 0..config.num_challenges {
+            let mut looking_zs_sum = F::ZERO;
+            for &table in &looking_tables {
+                looking_zs_sum += next_z(table)?;
+            }
+            let looked_z = next_z(looked_table)?;
+            if in_subset {
+                ensure!(
+                    looking_zs_sum == looked_z + extra_looking_sums[*looked_table][c],
+                    "cross-table lookup {index} verification failed"
+                );
+            }
+        }
     }
+    Ok(())
+}
 
-    // Extra sums to add to the looked last value.
-    // Only necessary for the Memory values.
-    let mut extra_looking_sums = vec![vec![F::ZERO; config.num_challenges]; NUM_TABLES];
+/// Recovers the looking and looked tables of `ctl`, from the number of CTL
+/// polynomials each table gets for that lookup alone.
+fn ctl_tables<F: Field>(ctl: &CrossTableLookup<F>) -> Result<(Vec<Table>, Table)> {
+    // Any degree works, as we only care about which helpers are non-empty.
+    let constraint_degree = 3;
+    let mut looking_tables = vec![];
+    let mut looked_table = None;
+    for table in Table::all() {
+        let (_, num_zs, num_helpers_by_ctl) = CrossTableLookup::num_ctl_helpers_zs_all(
+            core::slice::from_ref(ctl),
+            *table,
+            1,
+            constraint_degree,
+        );
+        let is_looking = num_helpers_by_ctl[0] > 0;
+        if is_looking {
+            looking_tables.push(table);
+        }
+        if num_zs > usize::from(is_looking) {
+            looked_table = Some(table);
+        }
+    }
+    let looked_table = looked_table.context("cross-table lookup without a looked table")?;
+    Ok((looking_tables, looked_table))
+}
 
-    // Memory
-    extra_looking_sums[*Table::Memory] = (0..config.num_challenges)
-        .map(|i| get_memory_extra_looking_sum(&public_values, ctl_challenges.challenges[i]))
-        .collect_vec();
+/// Checks the cross-table lookups like [`verify_cross_table_lookups`], but
+/// takes the CTL `Z` openings of one table at a time, e.g. as each STARK proof
+/// is read, so that those of all tables need not be resident at once.
+///
+/// Only a running sum per lookup and challenge is kept in between, and the
+/// result is the same as that of the batch check.
+#[derive(Debug)]
+pub struct StreamingCtlVerifier<F: Field> {
+    /// The looking and looked tables of each lookup.
+    ctl_tables: Vec<(Vec<Table>, Table)>,
+    /// For each lookup and challenge, the looking `Z`s absorbed so far, minus
+    /// the looked one.
+    sums: Vec<Vec<F>>,
+    extra_looking_sums: Vec<Vec<F>>,
+    absorbed: [bool; NUM_TABLES],
+}
 
-    verify_cross_table_lookups::<F, D, NUM_TABLES>(
-        cross_table_lookups,
-        all_proof
-            .multi_proof
-            .stark_proofs
-            .map(|p| p.proof.openings.ctl_zs_first.unwrap()),
-        Some(&extra_looking_sums),
-        config,
-    )
+impl<F: RichField> StreamingCtlVerifier<F> {
+    pub fn new<const D: usize>(
+        all_stark: &AllStark<F, D>,
+        public_values: &PublicValues<F>,
+        ctl_challenges: &GrandProductChallengeSet<F>,
+        config: &StarkConfig,
+    ) -> Result<Self>
+    where
+        F: Extendable<D>,
+    {
+        let ctl_tables = all_stark
+            .cross_table_lookups
+            .iter()
+            .map(ctl_tables)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            sums: vec![vec![F::ZERO; config.num_challenges]; ctl_tables.len()],
+            ctl_tables,
+            extra_looking_sums: extra_looking_sums(public_values, ctl_challenges, config),
+            absorbed: [false; NUM_TABLES],
+        })
+    }
+
+    /// Adds the `ctl_zs_first` openings of `table`'s STARK proof to the
+    /// running sums.
+    pub fn absorb(&mut self, table: Table, ctl_zs_first: &[F]) -> Result<()> {
+        ensure!(
+            !self.absorbed[*table],
+            "CTL openings for {:?} were already absorbed",
+            table
+        );
+        self.absorbed[*table] = true;
+
+        // Openings are laid out lookup by lookup, challenge by challenge,
+        // looking tables first, see `verify_cross_table_lookups_subset`.
+        let mut openings = ctl_zs_first.iter().copied();
+        for ((looking_tables, looked_table), sums) in self.ctl_tables.iter().zip(&mut self.sums) {
+            let is_looking = looking_tables.contains(&table);
+            for sum in sums {
+                if is_looking {
+                    *sum += openings
+                        .next()
+                        .with_context(|| format!("missing CTL openings for {:?}", table))?;
+                }
+                if *looked_table == table {
+                    *sum -= openings
+                        .next()
+                        .with_context(|| format!("missing CTL openings for {:?}", table))?;
+                }
+            }
+        }
+        ensure!(
+            openings.next().is_none(),
+            "too many CTL openings for {:?}",
+            table
+        );
+        Ok(())
+    }
+
+    /// Checks the lookups, once the openings of every table were absorbed.
+    pub fn finish(self) -> Result<()> {
+        for table in Table::all() {
+            ensure!(
+                self.absorbed[*table],
+                "CTL openings for {:?} were never absorbed",
+                table
+            );
+        }
+        for (index, ((_, looked_table), sums)) in self.ctl_tables.iter().zip(&self.sums).enumerate()
+        {
+            for (sum, extra_looking_sum) in sums.iter().zip(&self.extra_looking_sums[*looked_table])
+            {
+                ensure!(
+                    sum == extra_looking_sum,
+                    "cross-table lookup {index} verification failed"
+                );
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Computes the extra product to multiply to the looked value. It contains
@@ -408,6 +657,52 @@ where
     running_sum + challenge.combine(row.iter()).inverse()
 }
 
+/// Checks that the transactions trie after `public_values` holds exactly the
+/// RLP-encoded `txs`, keyed by their index, like the `transactionsRoot` of a
+/// block header.
+pub fn verify_transactions_root<F: RichField>(
+    public_values: &PublicValues<F>,
+    txs: &[Vec<u8>],
+) -> Result<()> {
+    let expected = index_trie_root(txs)?;
+    let actual = public_values.trie_roots_after.transactions_root;
+    ensure!(
+        actual == expected,
+        "transactions root mismatch: the proof commits to {actual:?}, \
+         but the transactions hash to {expected:?}"
+    );
+    Ok(())
+}
+
+/// Checks that the receipts trie after `public_values` holds exactly the
+/// encoded `receipts`, keyed by their index, like the `receiptsRoot` of a
+/// block header.
+pub fn verify_receipts_root<F: RichField>(
+    public_values: &PublicValues<F>,
+    receipts: &[Vec<u8>],
+) -> Result<()> {
+    let expected = index_trie_root(receipts)?;
+    let actual = public_values.trie_roots_after.receipts_root;
+    ensure!(
+        actual == expected,
+        "receipts root mismatch: the proof commits to {actual:?}, \
+         but the receipts hash to {expected:?}"
+    );
+    Ok(())
+}
+
+/// The root of a trie mapping the RLP of each index to its value.
+fn index_trie_root(values: &[Vec<u8>]) -> Result<H256> {
+    let mut trie = HashedPartialTrie::from(Node::Empty);
+    for (index, value) in values.iter().enumerate() {
+        trie.insert(
+            Nibbles::from_bytes_be(&rlp::encode(&index)).expect("RLP of an index is not empty"),
+            value.clone(),
+        )?;
+    }
+    Ok(trie.hash())
+}
+
 /// A utility module designed to verify proofs.
 pub mod testing {
     use super::*;
@@ -431,6 +726,42 @@ pub mod testing {
 
         Ok(())
     }
+
+    /// Verifies the STARK proofs of `tables` only, along with the cross-table
+    /// lookups which are fully contained within them, which is useful when
+    /// bringing up a new table.
+    ///
+    /// This does NOT prove the global consistency of `all_proof`: the other
+    /// tables, the lookups involving them and the initial memory are all
+    /// ignored.
+    pub fn verify_proof_subset<
+        F: RichField + Extendable<D>,
+        C: GenericConfig<D, F = F>,
+        const D: usize,
+    >(
+        all_stark: &AllStark<F, D>,
+        all_proof: &AllProof<F, C, D>,
+        config: &StarkConfig,
+        tables: &[Table],
+    ) -> Result<()> {
+        let challenges = all_proof
+            .get_challenges(config)
+            .map_err(|_| anyhow::Error::msg("Invalid sampling of proof challenges."))?;
+
+        verify_stark_proofs(all_stark, all_proof, &challenges, config, tables)?;
+
+        verify_cross_table_lookups_subset(
+            all_stark,
+            &all_proof
+                .multi_proof
+                .stark_proofs
+                .each_ref()
+                .map(|p| p.proof.openings.ctl_zs_first.clone().unwrap()),
+            &extra_looking_sums(&all_proof.public_values, &challenges.ctl_challenges, config),
+            config,
+            tables,
+        )
+    }
 }
 
 #[cfg(debug_assertions)]
@@ -627,3 +958,54 @@ pub(crate) mod debug_utils {
         row
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use hex_literal::hex;
+    use plonky2::field::goldilocks_field::GoldilocksField;
+
+    use super::*;
+    use crate::proof::TrieRoots;
+    use crate::testing_utils::EMPTY_NODE_HASH;
+
+    fn public_values(
+        transactions_root: H256,
+        receipts_root: H256,
+    ) -> PublicValues<GoldilocksField> {
+        PublicValues {
+            trie_roots_after: TrieRoots {
+                state_root: H256::zero(),
+                transactions_root,
+                receipts_root,
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn empty_transactions_and_receipts_roots() {
+        let public_values = public_values(EMPTY_NODE_HASH, EMPTY_NODE_HASH);
+        verify_transactions_root(&public_values, &[]).unwrap();
+        verify_receipts_root(&public_values, &[]).unwrap();
+    }
+
+    #[test]
+    fn transactions_and_receipts_roots() {
+        // A branch with the leaves `0x80 => txs[0]` and `0x01 => txs[1]`
+        // inlined, i.e. `keccak(rlp([[0x31, txs[1]], .., [0x30, txs[0]], ..]))`.
+        let root = H256(hex!(
+            "bce7b533889d391d123c0a57435628e5850645b865efa4741a33f0e50a4d7ac4"
+        ));
+        let txs = vec![vec![0xf8, 0x03], vec![0xf8, 0x01, 0x02]];
+        let public_values = public_values(root, root);
+        verify_transactions_root(&public_values, &txs).unwrap();
+        verify_receipts_root(&public_values, &txs).unwrap();
+
+        // reordered, so that the trie keys no longer match
+        let tampered = vec![txs[1].clone(), txs[0].clone()];
+        let e = verify_transactions_root(&public_values, &tampered).unwrap_err();
+        assert!(e.to_string().contains("transactions root mismatch"), "{e}");
+        let e = verify_receipts_root(&public_values, &txs[..1]).unwrap_err();
+        assert!(e.to_string().contains("receipts root mismatch"), "{e}");
+    }
+}
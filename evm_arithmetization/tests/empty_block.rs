@@ -0,0 +1,294 @@
+#![cfg(feature = "eth_mainnet")]
+
+use std::sync::OnceLock;
+
+use evm_arithmetization::all_stark::{CapHeightMismatch, Table};
+use evm_arithmetization::proof::AllProof;
+use evm_arithmetization::prover::testing::prove_all_segments;
+use evm_arithmetization::recursive_verifier::add_virtual_stark_proofs_like;
+use evm_arithmetization::testing_utils::{dummy_payload, init_logger, prove_empty_block};
+use evm_arithmetization::verifier::testing::{verify_all_proofs, verify_proof_subset};
+use evm_arithmetization::verifier::{verify_proof_with_challenges, StreamingCtlVerifier};
+use evm_arithmetization::{AllStark, StarkConfig};
+use plonky2::field::goldilocks_field::GoldilocksField;
+use plonky2::field::types::Field;
+use plonky2::iop::witness::PartialWitness;
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+use plonky2::plonk::circuit_data::CircuitConfig;
+use plonky2::plonk::config::{KeccakGoldilocksConfig, PoseidonGoldilocksConfig};
+use plonky2::util::timing::TimingTree;
+use starky::recursive_verifier::set_stark_proof_target;
+
+type F = GoldilocksField;
+const D: usize = 2;
+type C = KeccakGoldilocksConfig;
+
+/// Proofs of an empty block with the default `AllStark` and
+/// `StarkConfig::standard_fast_config`, shared by the tests of this file.
+fn empty_block_proofs() -> &'static [AllProof<F, C, D>] {
+    static PROOFS: OnceLock<Vec<AllProof<F, C, D>>> = OnceLock::new();
+    PROOFS.get_or_init(|| {
+        init_logger();
+        prove_empty_block::<F, C, D>(&AllStark::default(), &StarkConfig::standard_fast_config())
+            .expect("couldn't prove the empty block")
+    })
+}
+
+/// Smoke test for the testing utilities: prove and verify an empty block.
+#[test]
+fn test_empty_block() -> anyhow::Result<()> {
+    init_logger();
+
+    let all_stark = AllStark::<F, D>::default();
+    let config = StarkConfig::standard_fast_config();
+
+    verify_all_proofs(&all_stark, empty_block_proofs(), &config)
+}
+
+#[test]
+fn test_proof_summary() -> anyhow::Result<()> {
+    init_logger();
+
+    let all_stark = AllStark::<F, D>::default();
+    let config = StarkConfig::standard_fast_config();
+
+    let proofs = empty_block_proofs();
+    let summary = proofs[0].summary(&all_stark, &config);
+    assert!(summary.contains("block number: 1"));
+    for degree_bits in proofs[0].degree_bits(&config) {
+        assert!(summary.contains(&format!("degree bits {degree_bits}")));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_same_shape() -> anyhow::Result<()> {
+    init_logger();
+
+    let all_stark = AllStark::<F, D>::default();
+    let config = StarkConfig::standard_fast_config();
+
+    // Another block, proven independently with the same config, has different
+    // field elements but the same shape.
+    let proofs = empty_block_proofs();
+    let timing = &mut TimingTree::new("prove other empty block", log::Level::Debug);
+    let other_proofs = prove_all_segments::<F, C, D>(
+        &all_stark,
+        &config,
+        dummy_payload(2, true)?,
+        20,
+        timing,
+        None,
+    )?;
+    assert_ne!(
+        proofs[0].multi_proof.stark_proofs[*Table::Cpu]
+            .proof
+            .trace_cap,
+        other_proofs[0].multi_proof.stark_proofs[*Table::Cpu]
+            .proof
+            .trace_cap
+    );
+    assert!(proofs[0].same_shape(&other_proofs[0], &config));
+    assert!(other_proofs[0].same_shape(&proofs[0], &config));
+
+    // Whereas a missing CTL polynomial changes the shape.
+    let mut truncated = other_proofs[0].clone();
+    truncated.multi_proof.stark_proofs[*Table::Cpu]
+        .proof
+        .openings
+        .ctl_zs_first
+        .as_mut()
+        .unwrap()
+        .pop();
+    assert!(!proofs[0].same_shape(&truncated, &config));
+    Ok(())
+}
+
+#[test]
+fn test_same_shape_with_different_configs() -> anyhow::Result<()> {
+    init_logger();
+
+    let all_stark = AllStark::<F, D>::default();
+    let config = StarkConfig::standard_fast_config();
+    let other_config = StarkConfig {
+        num_challenges: config.num_challenges + 1,
+        ..StarkConfig::standard_fast_config()
+    };
+
+    let proofs = empty_block_proofs();
+    let other_proofs = prove_empty_block::<F, C, D>(&all_stark, &other_config)?;
+    assert!(!proofs[0].same_shape(&other_proofs[0], &config));
+    Ok(())
+}
+
+#[test]
+fn test_verify_proof_with_challenges() -> anyhow::Result<()> {
+    init_logger();
+
+    let all_stark = AllStark::<F, D>::default();
+    let config = StarkConfig::standard_fast_config();
+
+    let proofs = empty_block_proofs();
+    assert_eq!(proofs.len(), 1);
+    let challenges = proofs[0]
+        .get_challenges(&config)
+        .map_err(|e| anyhow::anyhow!("invalid sampling of proof challenges: {e:?}"))?;
+
+    // The challenges are sampled once, and reused for each verification.
+    for _ in 0..2 {
+        verify_proof_with_challenges(&all_stark, &proofs[0], &challenges, &config, true)?;
+    }
+
+    // They are bound to the public values the proof was sampled with.
+    let mut tampered = proofs[0].clone();
+    tampered.public_values.block_metadata.block_timestamp += 1.into();
+    assert!(
+        verify_proof_with_challenges(&all_stark, &tampered, &challenges, &config, true).is_err()
+    );
+    Ok(())
+}
+
+#[test]
+fn test_verify_proof_subset() -> anyhow::Result<()> {
+    init_logger();
+
+    let all_stark = AllStark::<F, D>::default();
+    let config = StarkConfig::standard_fast_config();
+
+    let proofs = empty_block_proofs();
+    verify_proof_subset(
+        &all_stark,
+        &proofs[0],
+        &config,
+        &[Table::Cpu, Table::Memory],
+    )
+}
+
+#[test]
+fn test_streaming_ctl_verifier() -> anyhow::Result<()> {
+    init_logger();
+
+    let all_stark = AllStark::<F, D>::default();
+    let config = StarkConfig::standard_fast_config();
+
+    let proofs = empty_block_proofs();
+    let mut tampered = proofs[0].clone();
+    tampered.multi_proof.stark_proofs[*Table::Logic]
+        .proof
+        .openings
+        .ctl_zs_first
+        .as_mut()
+        .unwrap()[0] += F::ONE;
+
+    for (all_proof, valid) in [(&proofs[0], true), (&tampered, false)] {
+        let challenges = all_proof
+            .get_challenges(&config)
+            .map_err(|e| anyhow::anyhow!("invalid sampling of proof challenges: {e:?}"))?;
+        let mut streaming = StreamingCtlVerifier::new(
+            &all_stark,
+            &all_proof.public_values,
+            &challenges.ctl_challenges,
+            &config,
+        )?;
+        for table in Table::all() {
+            let ctl_zs_first = all_proof.multi_proof.stark_proofs[*table]
+                .proof
+                .openings
+                .ctl_zs_first
+                .as_ref()
+                .unwrap();
+            streaming.absorb(table, ctl_zs_first)?;
+        }
+
+        let batch = verify_proof_with_challenges(&all_stark, all_proof, &challenges, &config, true);
+        assert_eq!(batch.is_ok(), valid);
+        assert_eq!(streaming.finish().is_ok(), valid);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_corrupted_table_proof() -> anyhow::Result<()> {
+    init_logger();
+
+    let all_stark = AllStark::<F, D>::default();
+    let config = StarkConfig::standard_fast_config();
+
+    let mut proofs = empty_block_proofs().to_vec();
+    let opening = &mut proofs[0].multi_proof.stark_proofs[*Table::Logic]
+        .proof
+        .openings
+        .local_values[0];
+    *opening = opening.add_one();
+
+    let e = verify_all_proofs(&all_stark, &proofs, &config).unwrap_err();
+    assert!(e.to_string().contains("Logic"), "{e:#}");
+    Ok(())
+}
+
+#[test]
+fn test_ctl_checked_before_stark_proofs() -> anyhow::Result<()> {
+    init_logger();
+
+    let all_stark = AllStark::<F, D>::default();
+    let config = StarkConfig::standard_fast_config();
+
+    let mut proofs = empty_block_proofs().to_vec();
+    proofs[0].multi_proof.stark_proofs[*Table::Logic]
+        .proof
+        .openings
+        .ctl_zs_first
+        .as_mut()
+        .unwrap()[0] += F::ONE;
+
+    // This also breaks the Logic STARK proof, but the CTL check is cheaper,
+    // so it must fail first, before any STARK proof is verified.
+    let e = verify_all_proofs(&all_stark, &proofs, &config).unwrap_err();
+    assert!(!format!("{e:#}").contains("STARK proof"), "{e:#}");
+    Ok(())
+}
+
+#[test]
+fn test_equal_cap_heights_required() -> anyhow::Result<()> {
+    init_logger();
+
+    let all_stark = AllStark::<F, D>::default().with_equal_cap_heights(true);
+    let config = StarkConfig::standard_fast_config();
+
+    let mut proofs = empty_block_proofs().to_vec();
+    verify_all_proofs(&all_stark, &proofs, &config)?;
+
+    // Halving the cap of a single table lowers its height by one.
+    let trace_cap = &mut proofs[0].multi_proof.stark_proofs[*Table::Keccak]
+        .proof
+        .trace_cap;
+    let height = trace_cap.height();
+    trace_cap.0.truncate(trace_cap.0.len() / 2);
+    let e = verify_all_proofs(&all_stark, &proofs, &config).unwrap_err();
+    let mismatch = e
+        .downcast_ref::<CapHeightMismatch>()
+        .unwrap_or_else(|| panic!("expected a cap height mismatch, got {e:#}"));
+    assert!(mismatch.tables.contains(&(Table::Keccak, height - 1)));
+    Ok(())
+}
+
+#[test]
+fn test_stark_proof_targets_like() -> anyhow::Result<()> {
+    init_logger();
+
+    let all_stark = AllStark::<F, D>::default();
+    let config = StarkConfig::standard_fast_config();
+
+    // Recursion needs an algebraic hasher.
+    let proofs = prove_empty_block::<F, PoseidonGoldilocksConfig, D>(&all_stark, &config)?;
+    let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+    let targets = add_virtual_stark_proofs_like(&mut builder, &all_stark, &config, &proofs[0]);
+    let zero = builder.zero();
+
+    // Setting the targets would panic if their shape didn't match the proofs.
+    let mut inputs = PartialWitness::<F>::new();
+    for (target, stark_proof) in targets.iter().zip(&proofs[0].multi_proof.stark_proofs) {
+        set_stark_proof_target(&mut inputs, target, &stark_proof.proof, zero);
+    }
+    Ok(())
+}
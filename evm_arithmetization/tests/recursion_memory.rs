@@ -0,0 +1,91 @@
+#![cfg(feature = "eth_mainnet")]
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use evm_arithmetization::all_stark::Table;
+use evm_arithmetization::recursive_verifier::{
+    estimate_table_recursion_memory, recursively_verify_table,
+};
+use evm_arithmetization::testing_utils::{init_logger, prove_empty_block};
+use evm_arithmetization::{AllStark, StarkConfig};
+use plonky2::field::goldilocks_field::GoldilocksField;
+use plonky2::plonk::circuit_data::CircuitConfig;
+use plonky2::plonk::config::PoseidonGoldilocksConfig;
+
+type F = GoldilocksField;
+const D: usize = 2;
+type C = PoseidonGoldilocksConfig;
+
+/// Keeps track of the peak number of bytes allocated by this test binary.
+struct PeakAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for PeakAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(allocated, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: PeakAllocator = PeakAllocator;
+
+/// Measures the peak memory of building and proving the recursive circuit of
+/// the Logic table, and checks it against `estimate_table_recursion_memory`.
+///
+/// This is the only test of this binary, so that no other test allocates
+/// while measuring.
+#[test]
+#[ignore] // Too slow to run on CI.
+fn test_recursion_memory_estimate() -> anyhow::Result<()> {
+    init_logger();
+
+    let all_stark = AllStark::<F, D>::default();
+    let config = StarkConfig::standard_fast_config();
+    let circuit_config = CircuitConfig::standard_recursion_config();
+
+    let proofs = prove_empty_block::<F, C, D>(&all_stark, &config)?;
+    let ctl_challenges = proofs[0]
+        .get_challenges(&config)
+        .map_err(|e| anyhow::anyhow!("invalid sampling of proof challenges: {e:?}"))?
+        .ctl_challenges;
+    let degree_bits = proofs[0].degree_bits(&config)[*Table::Logic];
+    let estimate = estimate_table_recursion_memory(
+        &all_stark,
+        Table::Logic,
+        degree_bits,
+        &config,
+        &circuit_config,
+    );
+
+    let baseline = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(baseline, Ordering::Relaxed);
+    recursively_verify_table(
+        &all_stark,
+        Table::Logic,
+        &proofs[0].multi_proof.stark_proofs[*Table::Logic],
+        &ctl_challenges,
+        &config,
+        &circuit_config,
+    )?;
+    let measured = PEAK.load(Ordering::Relaxed) - baseline;
+
+    assert!(
+        measured / 4 <= estimate && estimate <= measured * 4,
+        "estimated {estimate} bytes, but proving the Logic table's recursive circuit took \
+         {measured}"
+    );
+    Ok(())
+}
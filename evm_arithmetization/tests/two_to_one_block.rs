@@ -1,19 +1,22 @@
 #![cfg(feature = "eth_mainnet")]
 
-use ethereum_types::{Address, BigEndianHash, H256};
+use ethereum_types::H256;
+use evm_arithmetization::all_stark::Table;
 use evm_arithmetization::fixed_recursive_verifier::{
-    extract_block_final_public_values, extract_two_to_one_block_hash,
+    extract_block_final_public_values, extract_two_to_one_block_hash, BlockConstraints,
 };
 use evm_arithmetization::generation::{GenerationInputs, TrieInputs};
 use evm_arithmetization::proof::{
-    BlockMetadata, FinalPublicValues, PublicValues, TrieRoots, EMPTY_CONSOLIDATED_BLOCKHASH,
+    AggregatedPublicValues, BlockHashes, BlockMetadata, FinalPublicValues, PublicValues, TrieRoots,
 };
+use evm_arithmetization::recursive_verifier::RecursionOptions;
 use evm_arithmetization::testing_utils::{
-    beacon_roots_account_nibbles, beacon_roots_contract_from_storage, init_logger,
-    preinitialized_state_and_storage_tries, update_beacon_roots_account_storage,
+    beacon_roots_account_nibbles, beacon_roots_contract_from_storage, dummy_payload, init_logger,
+    prove_empty_block, update_beacon_roots_account_storage,
+};
+use evm_arithmetization::{
+    AllRecursiveCircuits, AllStark, Node, SegmentDataIterator, StarkConfig, NUM_TABLES,
 };
-use evm_arithmetization::{AllRecursiveCircuits, AllStark, Node, StarkConfig};
-use hex_literal::hex;
 use mpt_trie::partial_trie::{HashedPartialTrie, PartialTrie};
 use plonky2::field::goldilocks_field::GoldilocksField;
 use plonky2::field::types::Field;
@@ -26,90 +29,114 @@ type F = GoldilocksField;
 const D: usize = 2;
 type C = PoseidonGoldilocksConfig;
 
-/// Get `GenerationInputs` for a dummy payload, where the block has the given
-/// timestamp.
-fn dummy_payload(timestamp: u64, is_first_payload: bool) -> anyhow::Result<GenerationInputs<F>> {
-    let beneficiary = hex!("deadbeefdeadbeefdeadbeefdeadbeefdeadbeef");
-
+/// Get `GenerationInputs` for a dummy payload of the block following the one
+/// of `parent`, where the block has the given timestamp.
+fn next_dummy_payload(
+    parent: &GenerationInputs<F>,
+    timestamp: u64,
+    is_first_payload: bool,
+) -> anyhow::Result<GenerationInputs<F>> {
     let block_metadata = BlockMetadata {
-        block_beneficiary: Address::from(beneficiary),
         block_timestamp: timestamp.into(),
-        block_number: 1.into(),
-        block_difficulty: 0x020000.into(),
-        block_random: H256::from_uint(&0x020000.into()),
-        block_gaslimit: 0xff112233u32.into(),
-        block_chain_id: 1.into(),
-        block_base_fee: 0xa.into(),
-        ..Default::default()
+        block_number: parent.block_metadata.block_number + 1,
+        ..parent.block_metadata.clone()
     };
 
-    let (mut state_trie_before, mut storage_tries) = preinitialized_state_and_storage_tries()?;
-    let checkpoint_state_trie_root = state_trie_before.hash();
-    let mut beacon_roots_account_storage = storage_tries[0].1.clone();
+    let state_trie_from_storage = |storage: &HashedPartialTrie| -> anyhow::Result<_> {
+        let mut state_trie = HashedPartialTrie::from(Node::Empty);
+        state_trie.insert(
+            beacon_roots_account_nibbles(),
+            rlp::encode(&beacon_roots_contract_from_storage(storage)).to_vec(),
+        )?;
+        Ok(state_trie)
+    };
 
+    // The beacon roots contract is the only account, and was last updated by
+    // the parent block.
+    let (beacon_roots_account_hash, mut beacon_roots_account_storage) =
+        parent.tries.storage_tries[0].clone();
     update_beacon_roots_account_storage(
         &mut beacon_roots_account_storage,
+        parent.block_metadata.block_timestamp,
+        parent.block_metadata.parent_beacon_block_root,
+    )?;
+    let mut updated_beacon_roots_account_storage = beacon_roots_account_storage.clone();
+    update_beacon_roots_account_storage(
+        &mut updated_beacon_roots_account_storage,
         block_metadata.block_timestamp,
         block_metadata.parent_beacon_block_root,
     )?;
-    let updated_beacon_roots_account =
-        beacon_roots_contract_from_storage(&beacon_roots_account_storage);
-
     if !is_first_payload {
-        // This isn't the first dummy payload being processed. We need to update the
-        // initial state trie to account for the update on the beacon roots contract.
-        state_trie_before.insert(
-            beacon_roots_account_nibbles(),
-            rlp::encode(&updated_beacon_roots_account).to_vec(),
-        )?;
-        storage_tries[0].1 = beacon_roots_account_storage;
+        beacon_roots_account_storage = updated_beacon_roots_account_storage.clone();
     }
 
     let tries_before = TrieInputs {
-        state_trie: state_trie_before,
-        storage_tries,
+        state_trie: state_trie_from_storage(&beacon_roots_account_storage)?,
+        storage_tries: vec![(beacon_roots_account_hash, beacon_roots_account_storage)],
         ..Default::default()
     };
 
-    let expected_state_trie_after: HashedPartialTrie = {
-        let mut state_trie_after = HashedPartialTrie::from(Node::Empty);
-        state_trie_after.insert(
-            beacon_roots_account_nibbles(),
-            rlp::encode(&updated_beacon_roots_account).to_vec(),
-        )?;
-
-        state_trie_after
-    };
-
     let trie_roots_after = TrieRoots {
-        state_root: expected_state_trie_after.hash(),
+        state_root: state_trie_from_storage(&updated_beacon_roots_account_storage)?.hash(),
         transactions_root: tries_before.transactions_trie.hash(),
         receipts_root: tries_before.receipts_trie.hash(),
     };
 
-    let inputs = GenerationInputs {
-        tries: tries_before.clone(),
+    let mut prev_hashes = parent.block_hashes.prev_hashes[1..].to_vec();
+    prev_hashes.push(parent.block_hashes.cur_hash);
+
+    Ok(GenerationInputs {
+        tries: tries_before,
         burn_addr: None,
         trie_roots_after,
-        checkpoint_state_trie_root,
-        checkpoint_consolidated_hash: EMPTY_CONSOLIDATED_BLOCKHASH.map(F::from_canonical_u64),
+        checkpoint_state_trie_root: parent.checkpoint_state_trie_root,
+        checkpoint_consolidated_hash: parent.checkpoint_consolidated_hash,
         block_metadata,
+        block_hashes: BlockHashes {
+            prev_hashes,
+            cur_hash: H256::default(),
+        },
         ..Default::default()
-    };
-
-    Ok(inputs)
+    })
 }
 
-fn get_test_block_proof(
+/// Proves a block made of dummy payloads, returning the unwrapped block proof
+/// and its public values.
+fn get_unwrapped_test_block_proof(
     timestamp: u64,
     all_circuits: &AllRecursiveCircuits,
     all_stark: &AllStark<GoldilocksField, 2>,
     config: &StarkConfig,
-) -> anyhow::Result<ProofWithPublicInputs<GoldilocksField, PoseidonGoldilocksConfig, 2>> {
-    let dummy0 = dummy_payload(timestamp, true)?;
-    let dummy1 = dummy_payload(timestamp, false)?;
+) -> anyhow::Result<(
+    ProofWithPublicInputs<GoldilocksField, PoseidonGoldilocksConfig, 2>,
+    PublicValues<F>,
+)> {
+    let payloads = [
+        dummy_payload(timestamp, true)?,
+        dummy_payload(timestamp, false)?,
+    ];
+    prove_test_block(payloads, None, all_circuits, all_stark, config)
+}
 
-    let timing = &mut TimingTree::new(&format!("Blockproof {timestamp}"), log::Level::Info);
+/// Proves a block made of the two given payloads on top of the given parent
+/// block proof, if any, returning the unwrapped block proof and its public
+/// values.
+fn prove_test_block(
+    payloads: [GenerationInputs<F>; 2],
+    opt_parent_block_proof: Option<&ProofWithPublicInputs<F, C, D>>,
+    all_circuits: &AllRecursiveCircuits,
+    all_stark: &AllStark<GoldilocksField, 2>,
+    config: &StarkConfig,
+) -> anyhow::Result<(
+    ProofWithPublicInputs<GoldilocksField, PoseidonGoldilocksConfig, 2>,
+    PublicValues<F>,
+)> {
+    let [dummy0, dummy1] = payloads;
+
+    let timing = &mut TimingTree::new(
+        &format!("Blockproof {}", dummy0.block_metadata.block_timestamp),
+        log::Level::Info,
+    );
     let dummy0_proof0 =
         all_circuits.prove_all_segments(all_stark, config, dummy0, 20, timing, None)?;
     let dummy1_proof =
@@ -138,18 +165,51 @@ fn get_test_block_proof(
     // Test retrieved public values from the proof public inputs.
     let retrieved_public_values = PublicValues::from_public_inputs(&agg_proof.public_inputs);
     assert_eq!(retrieved_public_values, pv);
-    assert_eq!(
-        pv.trie_roots_before.state_root,
-        pv.extra_block_data.checkpoint_state_trie_root
-    );
+    if opt_parent_block_proof.is_none() {
+        assert_eq!(
+            pv.trie_roots_before.state_root,
+            pv.extra_block_data.checkpoint_state_trie_root
+        );
+    }
 
-    let (block_proof, block_public_values) = all_circuits.prove_block(
-        None, // We don't specify a previous proof, considering block 1 as the new checkpoint.
-        &agg_proof, pv,
-    )?;
+    // Without a previous proof, the block is considered as the new checkpoint.
+    let (block_proof, block_public_values) =
+        all_circuits.prove_block(opt_parent_block_proof, &agg_proof, pv)?;
 
     all_circuits.verify_block(&block_proof)?;
 
+    Ok((block_proof, block_public_values))
+}
+
+fn get_test_block_proof(
+    timestamp: u64,
+    all_circuits: &AllRecursiveCircuits,
+    all_stark: &AllStark<GoldilocksField, 2>,
+    config: &StarkConfig,
+) -> anyhow::Result<ProofWithPublicInputs<GoldilocksField, PoseidonGoldilocksConfig, 2>> {
+    let (block_proof, block_public_values) =
+        get_unwrapped_test_block_proof(timestamp, all_circuits, all_stark, config)?;
+
+    // Test retrieved block range boundaries from the proof public inputs.
+    let aggregated_public_values =
+        AggregatedPublicValues::from_public_inputs(&block_proof.public_inputs);
+    assert_eq!(
+        aggregated_public_values.trie_roots_before,
+        block_public_values.trie_roots_before
+    );
+    assert_eq!(
+        aggregated_public_values.trie_roots_after,
+        block_public_values.trie_roots_after
+    );
+    assert_eq!(
+        aggregated_public_values.first_block_number,
+        block_public_values.block_metadata.block_number
+    );
+    assert_eq!(
+        aggregated_public_values.block_number,
+        block_public_values.block_metadata.block_number
+    );
+
     let (wrapped_block_proof, block_final_public_values) =
         all_circuits.prove_block_wrapper(&block_proof, block_public_values)?;
 
@@ -282,3 +342,374 @@ fn test_two_to_one_block_aggregation() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[ignore]
+#[test]
+fn test_aggregated_block_range() -> anyhow::Result<()> {
+    init_logger();
+
+    let all_stark = AllStark::<F, D>::default();
+    let config = StarkConfig::standard_fast_config();
+    let all_circuits = AllRecursiveCircuits::new(
+        &all_stark,
+        &[16..17, 8..9, 12..13, 8..9, 8..9, 6..7, 17..18, 17..18, 7..8],
+        &config,
+    );
+
+    let block1_payloads = [dummy_payload(42, true)?, dummy_payload(42, false)?];
+    let block2_payloads = [
+        next_dummy_payload(&block1_payloads[1], 43, true)?,
+        next_dummy_payload(&block1_payloads[1], 43, false)?,
+    ];
+    let (block1_proof, block1_public_values) =
+        prove_test_block(block1_payloads, None, &all_circuits, &all_stark, &config)?;
+    let (block2_proof, block2_public_values) = prove_test_block(
+        block2_payloads,
+        Some(&block1_proof),
+        &all_circuits,
+        &all_stark,
+        &config,
+    )?;
+
+    // The proof of block 2 covers the range of blocks 1 to 2.
+    let aggregated_public_values =
+        AggregatedPublicValues::from_public_inputs(&block2_proof.public_inputs);
+    assert_eq!(aggregated_public_values.first_block_number, 1.into());
+    assert_eq!(aggregated_public_values.block_number, 2.into());
+    assert_eq!(
+        aggregated_public_values.trie_roots_before,
+        block1_public_values.trie_roots_before
+    );
+    assert_eq!(
+        aggregated_public_values.trie_roots_after,
+        block2_public_values.trie_roots_after
+    );
+
+    Ok(())
+}
+
+/// Makes the block of the given payloads the checkpoint of a new block range,
+/// which can then be proven without a parent block.
+fn with_own_checkpoint(mut payloads: [GenerationInputs<F>; 2]) -> [GenerationInputs<F>; 2] {
+    let checkpoint_state_trie_root = payloads[0].tries.state_trie.hash();
+    for payload in &mut payloads {
+        payload.checkpoint_state_trie_root = checkpoint_state_trie_root;
+    }
+    payloads
+}
+
+#[ignore]
+#[test]
+fn test_merge_adjacent_block_ranges() -> anyhow::Result<()> {
+    init_logger();
+
+    let all_stark = AllStark::<F, D>::default();
+    let config = StarkConfig::standard_fast_config();
+    let all_circuits = AllRecursiveCircuits::new(
+        &all_stark,
+        &[16..17, 8..9, 12..13, 8..9, 8..9, 6..7, 17..18, 17..18, 7..8],
+        &config,
+    );
+
+    // Blocks 1 and 2 are proven as a single range, while blocks 3 and 4 each
+    // start their own range.
+    let block1_payloads = [dummy_payload(42, true)?, dummy_payload(42, false)?];
+    let block2_payloads = [
+        next_dummy_payload(&block1_payloads[1], 43, true)?,
+        next_dummy_payload(&block1_payloads[1], 43, false)?,
+    ];
+    let block3_payloads = with_own_checkpoint([
+        next_dummy_payload(&block2_payloads[1], 44, true)?,
+        next_dummy_payload(&block2_payloads[1], 44, false)?,
+    ]);
+    let block4_payloads = with_own_checkpoint([
+        next_dummy_payload(&block3_payloads[1], 45, true)?,
+        next_dummy_payload(&block3_payloads[1], 45, false)?,
+    ]);
+
+    let (block1_proof, block1_public_values) =
+        prove_test_block(block1_payloads, None, &all_circuits, &all_stark, &config)?;
+    let (blocks_1_2_proof, _) = prove_test_block(
+        block2_payloads,
+        Some(&block1_proof),
+        &all_circuits,
+        &all_stark,
+        &config,
+    )?;
+    let (block3_proof, _) =
+        prove_test_block(block3_payloads, None, &all_circuits, &all_stark, &config)?;
+    let (block4_proof, block4_public_values) =
+        prove_test_block(block4_payloads, None, &all_circuits, &all_stark, &config)?;
+
+    let merge_circuit = all_circuits.block_range_merge_circuit();
+    let expected_public_values = AggregatedPublicValues {
+        trie_roots_before: block1_public_values.trie_roots_before,
+        trie_roots_after: block4_public_values.trie_roots_after,
+        first_block_number: 1.into(),
+        block_number: 4.into(),
+    };
+
+    // Merge a block proof with a merged proof, on either side.
+    let blocks_3_4_proof = merge_circuit.merge_adjacent(&block3_proof, &block4_proof)?;
+    merge_circuit.verify(&blocks_3_4_proof)?;
+    let blocks_1_4_proof = merge_circuit.merge_adjacent(&blocks_1_2_proof, &blocks_3_4_proof)?;
+    merge_circuit.verify(&blocks_1_4_proof)?;
+    assert_eq!(
+        AggregatedPublicValues::from_public_inputs(&blocks_1_4_proof.public_inputs),
+        expected_public_values
+    );
+
+    let blocks_1_3_proof = merge_circuit.merge_adjacent(&blocks_1_2_proof, &block3_proof)?;
+    merge_circuit.verify(&blocks_1_3_proof)?;
+    let blocks_1_4_proof = merge_circuit.merge_adjacent(&blocks_1_3_proof, &block4_proof)?;
+    merge_circuit.verify(&blocks_1_4_proof)?;
+    assert_eq!(
+        AggregatedPublicValues::from_public_inputs(&blocks_1_4_proof.public_inputs),
+        expected_public_values
+    );
+
+    // Ranges must be merged in order, without gaps.
+    let e = merge_circuit
+        .merge_adjacent(&block3_proof, &blocks_1_2_proof)
+        .unwrap_err();
+    assert!(e.to_string().contains("state root mismatch"), "{e}");
+    let e = merge_circuit
+        .merge_adjacent(&blocks_1_2_proof, &block4_proof)
+        .unwrap_err();
+    assert!(e.to_string().contains("state root mismatch"), "{e}");
+
+    Ok(())
+}
+
+#[ignore]
+#[test]
+fn test_verify_block_light() -> anyhow::Result<()> {
+    init_logger();
+
+    let all_stark = AllStark::<F, D>::default();
+    let config = StarkConfig::standard_fast_config();
+    let all_circuits = AllRecursiveCircuits::new(
+        &all_stark,
+        &[16..17, 8..9, 12..13, 8..9, 8..9, 6..7, 17..18, 17..18, 7..8],
+        &config,
+    );
+
+    let (block_proof, public_values) =
+        get_unwrapped_test_block_proof(42, &all_circuits, &all_stark, &config)?;
+    let digest = all_circuits
+        .final_verifier_data()
+        .verifier_only
+        .circuit_digest;
+    all_circuits.verify_block_light(&block_proof, &digest, &public_values)?;
+
+    let wrong_digest = PoseidonHash::hash_no_pad(&[F::ONE]);
+    let e = all_circuits
+        .verify_block_light(&block_proof, &wrong_digest, &public_values)
+        .unwrap_err();
+    assert!(e.to_string().contains("digest mismatch"), "{e}");
+
+    let mut wrong_public_values = public_values.clone();
+    wrong_public_values.block_metadata.block_number += 1.into();
+    let e = all_circuits
+        .verify_block_light(&block_proof, &digest, &wrong_public_values)
+        .unwrap_err();
+    assert!(e.to_string().contains("public values mismatch"), "{e}");
+
+    Ok(())
+}
+
+#[ignore]
+#[test]
+fn test_block_expected_chain_id() {
+    init_logger();
+
+    let all_stark = AllStark::<F, D>::default();
+    let config = StarkConfig::standard_fast_config();
+
+    // The dummy payloads are for chain id 1.
+    let all_circuits = AllRecursiveCircuits::new_with_block_constraints(
+        &all_stark,
+        &[16..17, 8..9, 12..13, 8..9, 8..9, 6..7, 17..18, 17..18, 7..8],
+        &config,
+        BlockConstraints {
+            expected_chain_id: Some(10),
+            ..Default::default()
+        },
+    );
+
+    let e = get_test_block_proof(42, &all_circuits, &all_stark, &config).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "block 1 has chain id 1, but the block circuit expects chain id 10"
+    );
+}
+
+// This needs the recursive circuits of every table, like the other tests of
+// this file. `test_verify_proof_with_challenges` in `empty_block.rs` cheaply
+// checks the same binding on the host side.
+#[ignore]
+#[test]
+#[should_panic(expected = "was set twice with different values")]
+fn test_segment_proof_binds_public_values() {
+    init_logger();
+
+    let all_stark = AllStark::<F, D>::default();
+    let config = StarkConfig::standard_fast_config();
+    let mut all_proof = prove_empty_block::<F, C, D>(&all_stark, &config)
+        .unwrap()
+        .remove(0);
+    let degree_bits = all_proof.degree_bits(&config);
+    let mut all_circuits =
+        AllRecursiveCircuits::new(&all_stark, &degree_bits.map(|bits| bits..bits + 1), &config);
+    let table_circuits = core::array::from_fn(|table| {
+        let circuits = all_circuits.by_table[table]
+            .by_stark_size
+            .remove(&degree_bits[table])
+            .unwrap();
+        (circuits, 0)
+    });
+
+    // The STARK proofs are untouched, but the root circuit observes the public
+    // values before sampling the CTL challenges, which then conflict with the
+    // ones the table proofs were generated with.
+    all_proof.public_values.block_metadata.block_timestamp += 1.into();
+    let _ = all_circuits.prove_segment_after_initial_stark(all_proof, &table_circuits, None);
+}
+
+#[ignore]
+#[test]
+fn test_root_circuit_table_order() -> anyhow::Result<()> {
+    init_logger();
+
+    let all_stark = AllStark::<F, D>::default();
+    let config = StarkConfig::standard_fast_config();
+    let all_proof = prove_empty_block::<F, C, D>(&all_stark, &config)?.remove(0);
+    let degree_bits = all_proof.degree_bits(&config);
+    let degree_bits_ranges = degree_bits.map(|bits| bits..bits + 1);
+
+    let mut public_values = vec![];
+    for table_order in [None, Some(Table::all())] {
+        let mut all_circuits = AllRecursiveCircuits::new_with_table_order(
+            &all_stark,
+            &degree_bits_ranges,
+            &config,
+            BlockConstraints::default(),
+            table_order,
+        );
+        let table_circuits = core::array::from_fn(|table| {
+            let circuits = all_circuits.by_table[table]
+                .by_stark_size
+                .remove(&degree_bits[table])
+                .unwrap();
+            (circuits, 0)
+        });
+
+        let (root_proof, pv) = all_circuits.prove_segment_after_initial_stark(
+            all_proof.clone(),
+            &table_circuits,
+            None,
+        )?;
+        all_circuits.verify_root(root_proof)?;
+        public_values.push(pv);
+    }
+    assert_eq!(public_values[0], public_values[1]);
+
+    Ok(())
+}
+
+#[ignore]
+#[test]
+fn test_root_circuit_recursion_options() -> anyhow::Result<()> {
+    init_logger();
+
+    let all_stark = AllStark::<F, D>::default();
+    let config = StarkConfig::standard_fast_config();
+    let all_proof = prove_empty_block::<F, C, D>(&all_stark, &config)?.remove(0);
+    let degree_bits = all_proof.degree_bits(&config);
+
+    // The root circuit must expect the trace cap hash each table proof then
+    // exposes, while the cap height override only applies to the first circuit
+    // of each shrinking chain.
+    let mut all_circuits = AllRecursiveCircuits::new_with_recursion_options(
+        &all_stark,
+        &degree_bits.map(|bits| bits..bits + 1),
+        &config,
+        BlockConstraints::default(),
+        None,
+        RecursionOptions {
+            with_trace_cap_hash: true,
+            cap_height: Some(2),
+        },
+    );
+    let table_circuits = core::array::from_fn(|table| {
+        let circuits = all_circuits.by_table[table]
+            .by_stark_size
+            .remove(&degree_bits[table])
+            .unwrap();
+        (circuits, 0)
+    });
+
+    let (root_proof, _) =
+        all_circuits.prove_segment_after_initial_stark(all_proof, &table_circuits, None)?;
+    all_circuits.verify_root(root_proof)
+}
+
+#[ignore]
+#[test]
+fn test_resumable_segment_proof() -> anyhow::Result<()> {
+    init_logger();
+
+    let all_stark = AllStark::<F, D>::default();
+    let config = StarkConfig::standard_fast_config();
+    let all_circuits = AllRecursiveCircuits::new(
+        &all_stark,
+        &[16..17, 8..9, 12..13, 8..9, 8..9, 6..7, 17..18, 17..18, 7..8],
+        &config,
+    );
+
+    let proof_dir = std::env::temp_dir().join(format!("resumable-segment-{}", std::process::id()));
+    std::fs::create_dir_all(&proof_dir)?;
+
+    let inputs = dummy_payload(42, true)?;
+    let prove = || -> anyhow::Result<()> {
+        let timing = &mut TimingTree::new("resumable segment", log::Level::Info);
+        let (_, mut segment_data) = SegmentDataIterator::<F>::new(&inputs, Some(20))
+            .next()
+            .expect("there is at least one segment")?;
+        let proof = all_circuits.prove_segment_resumable(
+            &all_stark,
+            &config,
+            inputs.trim(),
+            &mut segment_data,
+            &proof_dir,
+            timing,
+            None,
+        )?;
+        all_circuits.verify_root(proof.proof_with_pis)
+    };
+
+    // Pre-seed the proof directory, then only keep the proof of one table.
+    prove()?;
+    let seeded = proof_dir.join(format!("{:?}.proof", Table::Logic));
+    for entry in std::fs::read_dir(&proof_dir)? {
+        let path = entry?.path();
+        if path != seeded {
+            std::fs::remove_file(path)?;
+        }
+    }
+    let seeded_modified = std::fs::metadata(&seeded)?.modified()?;
+
+    // Resuming proves all other tables again, but reuses the seeded proof.
+    prove()?;
+    assert_eq!(std::fs::metadata(&seeded)?.modified()?, seeded_modified);
+    assert_eq!(std::fs::read_dir(&proof_dir)?.count(), NUM_TABLES);
+
+    // A corrupted proof is proven again, rather than failing the segment.
+    std::fs::write(&seeded, b"corrupted")?;
+    prove()?;
+    assert_ne!(std::fs::read(&seeded)?, b"corrupted");
+    assert_eq!(std::fs::read_dir(&proof_dir)?.count(), NUM_TABLES);
+
+    std::fs::remove_dir_all(&proof_dir)?;
+    Ok(())
+}
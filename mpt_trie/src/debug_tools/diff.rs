@@ -25,6 +25,7 @@
 //!   If there are multiple differences, then this will likely be what you want
 //!   to use.
 
+use std::collections::BTreeMap;
 use std::fmt::{self, Debug};
 use std::{fmt::Display, ops::Deref};
 
@@ -34,6 +35,7 @@ use crate::utils::{get_segment_from_node_and_key_piece, TriePath};
 use crate::{
     nibbles::Nibbles,
     partial_trie::{HashedPartialTrie, Node, PartialTrie},
+    trie_ops::ValOrHash,
     utils::TrieNodeType,
 };
 
@@ -183,6 +185,122 @@ pub fn create_diff_between_tries(a: &HashedPartialTrie, b: &HashedPartialTrie) -
     }
 }
 
+/// A difference at a single leaf between two tries. See [`trie_diff`].
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum TrieChange {
+    /// A leaf which is only present in the second trie.
+    Added {
+        /// The key of the leaf.
+        key: Nibbles,
+        /// The value of the leaf.
+        value: Vec<u8>,
+    },
+    /// A leaf which is only present in the first trie.
+    Removed {
+        /// The key of the leaf.
+        key: Nibbles,
+        /// The value of the leaf.
+        value: Vec<u8>,
+    },
+    /// A leaf whose value differs between the two tries.
+    Modified {
+        /// The key of the leaf.
+        key: Nibbles,
+        /// The value of the leaf in the first trie.
+        old: Vec<u8>,
+        /// The value of the leaf in the second trie.
+        new: Vec<u8>,
+    },
+    /// A subtrie which is hashed out in at least one of the tries, and may
+    /// hide changes.
+    Unknown {
+        /// The key of the [hash][`Node::Hash`] node.
+        key: Nibbles,
+    },
+}
+
+impl TrieChange {
+    const fn key(&self) -> Nibbles {
+        match self {
+            TrieChange::Added { key, .. }
+            | TrieChange::Removed { key, .. }
+            | TrieChange::Modified { key, .. }
+            | TrieChange::Unknown { key } => *key,
+        }
+    }
+}
+
+/// List the leaves which differ between two tries, in key order.
+///
+/// Unlike [`create_diff_between_tries`], this compares the leaves of the tries
+/// rather than their structure. [Hash][`Node::Hash`] nodes are opaque: unless
+/// both tries have the same hash node, a hash node is reported as
+/// [`TrieChange::Unknown`], and the leaves of the other trie below it are
+/// skipped.
+pub fn trie_diff(before: &HashedPartialTrie, after: &HashedPartialTrie) -> Vec<TrieChange> {
+    let (before_leaves, before_hashes) = split_items(before);
+    let (after_leaves, after_hashes) = split_items(after);
+    let is_hashed_out = |key: &Nibbles, hashes: &BTreeMap<Nibbles, H256>| {
+        hashes.keys().any(|prefix| {
+            prefix.count <= key.count && key.get_next_nibbles(prefix.count) == *prefix
+        })
+    };
+
+    let mut changes = vec![];
+    for (key, hash) in &before_hashes {
+        if after_hashes.get(key) != Some(hash) {
+            changes.push(TrieChange::Unknown { key: *key });
+        }
+    }
+    for key in after_hashes.keys() {
+        if !before_hashes.contains_key(key) {
+            changes.push(TrieChange::Unknown { key: *key });
+        }
+    }
+    for (key, old) in &before_leaves {
+        match after_leaves.get(key) {
+            Some(new) if new != old => changes.push(TrieChange::Modified {
+                key: *key,
+                old: old.clone(),
+                new: new.clone(),
+            }),
+            Some(_) => {}
+            None if is_hashed_out(key, &after_hashes) => {}
+            None => changes.push(TrieChange::Removed {
+                key: *key,
+                value: old.clone(),
+            }),
+        }
+    }
+    for (key, new) in &after_leaves {
+        if !before_leaves.contains_key(key) && !is_hashed_out(key, &before_hashes) {
+            changes.push(TrieChange::Added {
+                key: *key,
+                value: new.clone(),
+            });
+        }
+    }
+    changes.sort_by_key(TrieChange::key);
+    changes
+}
+
+/// Splits the items of `trie` into its leaves and its hash nodes.
+fn split_items(trie: &HashedPartialTrie) -> (BTreeMap<Nibbles, Vec<u8>>, BTreeMap<Nibbles, H256>) {
+    let mut leaves = BTreeMap::new();
+    let mut hashes = BTreeMap::new();
+    for (key, item) in trie.items() {
+        match item {
+            ValOrHash::Val(value) => {
+                leaves.insert(key, value);
+            }
+            ValOrHash::Hash(hash) => {
+                hashes.insert(key, hash);
+            }
+        }
+    }
+    (leaves, hashes)
+}
+
 // Only support `HashedPartialTrie` due to it being significantly faster to
 // detect differences because of caching hashes.
 fn find_latest_diff_point_between_tries(
@@ -425,7 +543,7 @@ const fn get_value_from_node<T: PartialTrie>(n: &Node<T>) -> Option<&Vec<u8>> {
 
 #[cfg(test)]
 mod tests {
-    use super::{create_diff_between_tries, DiffPoint, NodeInfo, TriePath};
+    use super::{create_diff_between_tries, trie_diff, DiffPoint, NodeInfo, TrieChange, TriePath};
     use crate::{
         nibbles::Nibbles,
         partial_trie::{HashedPartialTrie, PartialTrie},
@@ -486,6 +604,28 @@ mod tests {
         todo!()
     }
 
+    #[test]
+    fn leaf_diffs_work() -> TrieOpResult<()> {
+        let mut before = HashedPartialTrie::default();
+        before.insert(0x1234, vec![0])?;
+        before.insert(0x5678, vec![1])?;
+
+        let mut after = before.clone();
+        after.insert(0x1234, vec![2])?;
+
+        assert_eq!(
+            trie_diff(&before, &after),
+            vec![TrieChange::Modified {
+                key: 0x1234.into(),
+                old: vec![0],
+                new: vec![2],
+            }]
+        );
+        assert_eq!(trie_diff(&before, &before), vec![]);
+
+        Ok(())
+    }
+
     #[test]
     #[ignore]
     fn depth_multi_node_single_node_node_diffs_work() {
@@ -35,6 +35,23 @@ pub(crate) fn hash_trie<N: PartialTrie + TrieNodeIntern>(node: &Node<N>) -> H256
 
 pub(crate) fn rlp_encode_and_hash_node<N: PartialTrie + TrieNodeIntern>(
     node: &Node<N>,
+) -> EncodedNode {
+    encode_node(node, &|child| child.hash_intern())
+}
+
+/// Recomputes the hash of a trie from scratch, RLP-encoding and hashing every
+/// node bottom-up instead of relying on any cached hash.
+pub(crate) fn recompute_hash<N: PartialTrie>(node: &Node<N>) -> H256 {
+    fn recompute<N: PartialTrie>(node: &Node<N>) -> EncodedNode {
+        encode_node(node, &|child| recompute(child))
+    }
+    (&recompute(node)).into()
+}
+
+/// RLP-encodes `node`, using `child_hash` to encode its children.
+fn encode_node<N: PartialTrie>(
+    node: &Node<N>,
+    child_hash: &dyn Fn(&N) -> EncodedNode,
 ) -> EncodedNode {
     let res = match node {
         Node::Empty => EncodedNode::Raw(Bytes::from_static(&rlp::NULL_RLP)),
@@ -43,7 +60,7 @@ pub(crate) fn rlp_encode_and_hash_node<N: PartialTrie + TrieNodeIntern>(
             let mut stream = RlpStream::new_list(17);
 
             for c in children.iter() {
-                append_to_stream(&mut stream, c.hash_intern());
+                append_to_stream(&mut stream, child_hash(c));
             }
 
             match value.is_empty() {
@@ -57,7 +74,7 @@ pub(crate) fn rlp_encode_and_hash_node<N: PartialTrie + TrieNodeIntern>(
             let mut stream = RlpStream::new_list(2);
 
             stream.append(&nibbles.to_hex_prefix_encoding(false));
-            append_to_stream(&mut stream, child.hash_intern());
+            append_to_stream(&mut stream, child_hash(child));
 
             hash_bytes_if_large_enough(stream.out().into())
         }
@@ -12,7 +12,8 @@ use num_traits::PrimInt;
 
 use crate::{
     nibbles::{Nibble, Nibbles, NibblesIntern},
-    partial_trie::{Node, PartialTrie},
+    partial_trie::{HashedPartialTrie, Node, PartialTrie},
+    trie_hashing::recompute_hash,
     trie_ops::TrieOpResult,
 };
 
@@ -79,6 +80,25 @@ pub(crate) fn bytes_to_h256(b: &[u8; 32]) -> H256 {
     keccak_hash::H256::from_slice(b)
 }
 
+/// Recomputes the root hash of `trie` from scratch, RLP-encoding and hashing
+/// every node instead of trusting the hashes cached by [`HashedPartialTrie`].
+///
+/// This is useful to cross-check cached hashes, e.g. against a reference
+/// implementation.
+pub fn recompute_root(trie: &HashedPartialTrie) -> H256 {
+    recompute_hash(trie)
+}
+
+/// Rebuilds `trie` from its leaves and hash nodes, so that tries which store
+/// the same entries, but e.g. split a path across a chain of extensions or keep
+/// redundant branches, compare equal afterwards.
+///
+/// The result is the structure [`PartialTrie::insert`] produces, which is the
+/// one the Ethereum specification hashes.
+pub fn canonicalize(trie: &HashedPartialTrie) -> TrieOpResult<HashedPartialTrie> {
+    HashedPartialTrie::try_from_iter(trie.items())
+}
+
 /// Minimal key information of "segments" (nodes) used to construct trie
 /// "traces" of a trie query. Unlike [`TrieNodeType`], this type also contains
 /// the key piece of the node if applicable (eg. [`Node::Empty`] &
@@ -258,8 +278,11 @@ pub trait TryFromIterator<A>: Sized {
 mod tests {
     use std::str::FromStr;
 
-    use super::{IntoTrieKey, TriePath, TrieSegment};
-    use crate::nibbles::Nibbles;
+    use super::{canonicalize, IntoTrieKey, TriePath, TrieSegment, TryFromIterator};
+    use crate::{
+        nibbles::Nibbles,
+        partial_trie::{HashedPartialTrie, Node, PartialTrie, WrappedNode},
+    };
 
     #[test]
     fn path_from_query_works() {
@@ -274,4 +297,35 @@ mod tests {
         let reconstructed_key = query_path.iter().into_key();
         assert_eq!(reconstructed_key, Nibbles::from_str("0x1234567").unwrap());
     }
+
+    #[test]
+    fn canonicalize_merges_extension_chains() {
+        let leaf = |nibbles: &str, value: u8| Node::Leaf {
+            nibbles: Nibbles::from_str(nibbles).unwrap(),
+            value: vec![value],
+        };
+        let extension = |nibbles: &str, child: Node<HashedPartialTrie>| Node::Extension {
+            nibbles: Nibbles::from_str(nibbles).unwrap(),
+            child: child.into(),
+        };
+        let mut children: [WrappedNode<HashedPartialTrie>; 16] =
+            std::array::from_fn(|_| Node::Empty.into());
+        children[3] = leaf("0x4", 1).into();
+        children[5] = leaf("0x6", 2).into();
+        let branch = Node::Branch {
+            children,
+            value: vec![],
+        };
+
+        let canonical = HashedPartialTrie::try_from_iter([
+            (Nibbles::from_str("0x1234").unwrap(), vec![1]),
+            (Nibbles::from_str("0x1256").unwrap(), vec![2]),
+        ])
+        .unwrap();
+        let chained = HashedPartialTrie::new(extension("0x1", extension("0x2", branch)));
+        assert_ne!(chained, canonical);
+
+        assert_eq!(canonicalize(&chained).unwrap(), canonical);
+        assert_eq!(canonicalize(&canonical).unwrap(), canonical);
+    }
 }
@@ -20,6 +20,7 @@ use nunny::NonEmpty;
 use zk_evm_common::gwei_to_wei;
 
 use crate::observer::Observer;
+use crate::wire::Instruction;
 use crate::{
     typed_mpt::{ReceiptTrie, StateMpt, StateTrie, StorageTrie, TransactionTrie, TrieKey},
     BlockLevelData, BlockTrace, BlockTraceTriePreImages, CombinedPreImages, ContractCodeUsage,
@@ -33,6 +34,18 @@ pub fn entrypoint(
     other: OtherBlockData,
     batch_size_hint: usize,
     observer: &mut impl Observer<StateMpt>,
+) -> anyhow::Result<Vec<GenerationInputs>> {
+    entrypoint_with_resolver(trace, other, batch_size_hint, observer, &|_| None)
+}
+
+/// Like [`entrypoint`], but hash stubs in a [`CombinedPreImages`] witness are
+/// expanded from `node_resolver`, see [`crate::frontend_with_resolver`].
+pub fn entrypoint_with_resolver(
+    trace: BlockTrace,
+    other: OtherBlockData,
+    batch_size_hint: usize,
+    observer: &mut impl Observer<StateMpt>,
+    node_resolver: &(dyn Fn(H256) -> Option<Vec<Instruction>> + Sync),
 ) -> anyhow::Result<Vec<GenerationInputs>> {
     ensure!(batch_size_hint != 0);
 
@@ -41,7 +54,7 @@ pub fn entrypoint(
         code_db,
         txn_info,
     } = trace;
-    let (state, storage, mut code) = start(trie_pre_images)?;
+    let (state, storage, mut code) = start(trie_pre_images, node_resolver)?;
     code.extend(code_db);
 
     let OtherBlockData {
@@ -129,6 +142,7 @@ pub fn entrypoint(
 /// representations.
 fn start(
     pre_images: BlockTraceTriePreImages,
+    node_resolver: &(dyn Fn(H256) -> Option<Vec<Instruction>> + Sync),
 ) -> anyhow::Result<(StateMpt, BTreeMap<H256, StorageTrie>, Hash2Code)> {
     Ok(match pre_images {
         // TODO(0xaatif): https://github.com/0xPolygonZero/zk_evm/issues/401
@@ -186,7 +200,7 @@ fn start(
                 state,
                 storage,
                 code,
-            } = crate::type1::frontend(instructions)?;
+            } = crate::type1::frontend_with_resolver(instructions, node_resolver)?;
             (state, storage, code.into_iter().map(Into::into).collect())
         }
     })
@@ -65,7 +65,16 @@ mod type2;
 mod typed_mpt;
 mod wire;
 
-pub use core::entrypoint;
+pub use core::{entrypoint, entrypoint_with_resolver};
+
+pub use type1::{
+    frontend_with_resolver, truncate_witness, AnnotatedNode, AnnotatedTrie, BatchWitnessParser,
+    NodeVisitor, ParsedWitness, TouchedStorage,
+};
+pub use wire::{
+    disassemble, lint_witness, parse_chunks, parse_compressed, parse_lenient, parse_with_footer,
+    peek_witness, read_version, Version, MAX_DECOMPRESSED,
+};
 
 mod core;
 
@@ -97,6 +106,31 @@ mod hex {
     }
 }
 
+#[cfg(test)]
+mod test_utils {
+    use std::fs;
+    use std::path::Path;
+
+    /// Loads the hex-encoded witness fixture `name` from `src/cases/witnesses`.
+    ///
+    /// Whitespace is ignored, so fixtures may be split over several lines.
+    #[track_caller]
+    pub fn load_witness(name: &str) -> Vec<u8> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/cases/witnesses")
+            .join(name)
+            .with_extension("hex");
+        let contents = fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("couldn't read fixture {}: {e}", path.display()));
+        let digits = contents
+            .split_whitespace()
+            .map(|line| line.strip_prefix("0x").unwrap_or(line))
+            .collect::<String>();
+        hex::decode(digits)
+            .unwrap_or_else(|e| panic!("invalid hex in fixture {}: {e}", path.display()))
+    }
+}
+
 #[cfg(test)]
 #[derive(serde::Deserialize)]
 struct Case {
@@ -1,8 +1,9 @@
 //! Frontend for the witness format emitted by e.g the [`0xPolygonZero/erigon`](https://github.com/0xPolygonZero/erigon)
 //! Ethereum node (a.k.a "jerigon").
 
-use std::array;
 use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::{array, iter};
 
 use anyhow::{bail, ensure, Context as _};
 use either::Either;
@@ -10,6 +11,7 @@ use evm_arithmetization::generation::mpt::AccountRlp;
 use keccak_hash::H256;
 use mpt_trie::partial_trie::OnOrphanedHashNode;
 use nunny::NonEmpty;
+use plonky2_maybe_rayon::*;
 use u4::U4;
 
 use crate::typed_mpt::{StateMpt, StateTrie as _, StorageTrie, TrieKey};
@@ -34,126 +36,246 @@ impl Default for Frontend {
     }
 }
 
+/// Below this many accounts, building the storage tries in parallel isn't
+/// worth the overhead.
+const PARALLEL_STORAGE_THRESHOLD: usize = 64;
+
+#[cfg(test)]
 pub fn frontend(instructions: impl IntoIterator<Item = Instruction>) -> anyhow::Result<Frontend> {
-    let executions = execute(instructions)?;
-    ensure!(
-        executions.len() == 1,
-        "only a single execution is supported"
-    );
-    let execution = executions.into_vec().remove(0);
+    frontend_with_resolver(instructions, &|_| None)
+}
 
+/// Builds the tries of a type 1 witness, expanding hash stubs into the
+/// subtries returned by `resolver`, e.g. from a node store, and keeping the
+/// stubs it returns [`None`] for.
+///
+/// Each resolved subtrie must hash to the stub it replaces.
+pub fn frontend_with_resolver(
+    instructions: impl IntoIterator<Item = Instruction>,
+    resolver: &(dyn Fn(H256) -> Option<Vec<Instruction>> + Sync),
+) -> anyhow::Result<Frontend> {
+    frontend_with(instructions, resolver, PARALLEL_STORAGE_THRESHOLD)
+}
+
+fn frontend_with(
+    instructions: impl IntoIterator<Item = Instruction>,
+    resolver: &(dyn Fn(H256) -> Option<Vec<Instruction>> + Sync),
+    parallel_storage_threshold: usize,
+) -> anyhow::Result<Frontend> {
     let mut frontend = Frontend::default();
+    let mut accounts = vec![];
     visit(
         &mut frontend,
+        &mut accounts,
+        resolver,
         &stackstack::Stack::new(),
-        match execution {
-            Execution::Leaf(it) => Node::Leaf(it),
-            Execution::Extension(it) => Node::Extension(it),
-            Execution::Branch(it) => Node::Branch(it),
-            Execution::Empty => Node::Empty,
-        },
+        Node::from(execute_single(instructions)?),
     )?;
 
+    // Storage tries are disjoint, so we can build them independently.
+    let storage_nodes = accounts
+        .iter_mut()
+        .map(|(_, account)| match account.storage.take() {
+            Some(it) => *it,
+            None => Node::Empty,
+        })
+        .collect::<Vec<_>>();
+    let storage = match storage_nodes.len() < parallel_storage_threshold {
+        true => storage_nodes
+            .into_iter()
+            .map(|node| node2storagetrie(node, resolver))
+            .collect::<anyhow::Result<Vec<_>>>()?,
+        false => storage_nodes
+            .into_par_iter()
+            .map(|node| node2storagetrie(node, resolver))
+            .collect::<anyhow::Result<Vec<_>>>()?,
+    };
+
+    for ((path, account), storage) in iter::zip(accounts, storage) {
+        let Account {
+            nonce,
+            balance,
+            storage: _,
+            code,
+        } = account;
+        let account = AccountRlp {
+            nonce,
+            balance,
+            storage_root: {
+                let storage_root = storage.root();
+                let clobbered = frontend.storage.insert(path, storage);
+                ensure!(clobbered.is_none(), "duplicate storage");
+                storage_root
+            },
+            code_hash: {
+                match code {
+                    Some(Either::Left(Hash { raw_hash })) => raw_hash.into(),
+                    Some(Either::Right(Code { code })) => {
+                        let code = Arc::unwrap_or_clone(code);
+                        let hash = keccak_hash::keccak(&code);
+                        frontend.code.insert(code);
+                        hash
+                    }
+                    None => keccak_hash::keccak([]),
+                }
+            },
+        };
+        #[expect(deprecated)] // this is MPT-specific code
+        let clobbered = frontend.state.insert_by_hashed_address(path, account)?;
+        ensure!(clobbered.is_none(), "duplicate account");
+    }
+
     Ok(frontend)
 }
 
+/// Expands the stub of `hash`, found at `path`, into the subtrie `resolver`
+/// returns for it, or [`None`] if it returns nothing.
+///
+/// The subtrie is checked against the stub before it is built into a trie,
+/// so a node store which disagrees with the witness is reported at the
+/// offending path, rather than as a wrong root once the trie is complete.
+///
+/// This check also rules out cyclic references: a subtrie which contains its
+/// own stub, directly or via other stubs, would have to hash to a hash it
+/// contains, so the expansion is rejected at its first step rather than
+/// repeated forever, and no path needs tracking for it.
+fn resolve(
+    hash: H256,
+    resolver: &(dyn Fn(H256) -> Option<Vec<Instruction>> + Sync),
+    path: &stackstack::Stack<'_, U4>,
+) -> anyhow::Result<Option<Node>> {
+    let Some(instructions) = resolver(hash) else {
+        return Ok(None);
+    };
+    let subtrie = Node::from(
+        execute_single(instructions)
+            .with_context(|| format!("couldn't execute resolved subtrie for hash {hash:x}"))?,
+    );
+    check_hash(&subtrie, hash, path)?;
+    Ok(Some(subtrie))
+}
+
+/// Checks that `node`, found at `path`, hashes to `expected`, i.e. that it
+/// can stand in for a hash stub of `expected`.
+fn check_hash(node: &Node, expected: H256, path: &stackstack::Stack<'_, U4>) -> anyhow::Result<()> {
+    let path = path.iter().collect::<Vec<_>>();
+    let actual = node_hash(node)
+        .with_context(|| format!("subtrie at path {path:?} isn't a trie, so can't be hashed"))?;
+    ensure!(
+        actual == expected,
+        "subtrie at path {path:?} hashes to {actual:x}, not to the stub {expected:x} it replaces"
+    );
+    Ok(())
+}
+
+/// Populates the state trie with hash stubs, and the code set with bare code
+/// nodes, collecting all the accounts along the way, keyed by their path.
+///
+/// Stubs which `resolver` knows are expanded in place, see [`resolve`].
 fn visit(
     frontend: &mut Frontend,
+    accounts: &mut Vec<(H256, Account)>,
+    resolver: &(dyn Fn(H256) -> Option<Vec<Instruction>> + Sync),
     path: &stackstack::Stack<'_, U4>,
     node: Node,
 ) -> anyhow::Result<()> {
     match node {
-        Node::Hash(Hash { raw_hash }) => {
-            frontend
+        Node::Hash(Hash { raw_hash }) => match resolve(raw_hash.into(), resolver, path)? {
+            Some(subtrie) => visit(frontend, accounts, resolver, path, subtrie)?,
+            // A hash stub stands in for a whole subtrie, so the witness can't
+            // also expand it elsewhere - the best we can do is fail fast, and
+            // say where.
+            None => frontend
                 .state
-                .insert_hash_by_key(TrieKey::new(path.iter().copied())?, raw_hash.into())?;
-        }
+                .insert_hash_by_key(TrieKey::new(path.iter().copied())?, raw_hash.into())
+                .with_context(|| {
+                    format!(
+                        "couldn't insert hash stub at path {:?}",
+                        path.iter().collect::<Vec<_>>()
+                    )
+                })?,
+        },
         Node::Leaf(Leaf { key, value }) => {
             let path = TrieKey::new(path.iter().copied().chain(key))?
                 .into_hash()
                 .context("invalid depth for leaf of state trie")?;
             match value {
                 Either::Left(Value { .. }) => bail!("unsupported value node at top level"),
-                Either::Right(Account {
-                    nonce,
-                    balance,
-                    storage,
-                    code,
-                }) => {
-                    let account = AccountRlp {
-                        nonce: nonce.into(),
-                        balance,
-                        storage_root: {
-                            let storage = node2storagetrie(match storage {
-                                Some(it) => *it,
-                                None => Node::Empty,
-                            })?;
-                            let storage_root = storage.root();
-                            let clobbered = frontend.storage.insert(path, storage);
-                            ensure!(clobbered.is_none(), "duplicate storage");
-                            storage_root
-                        },
-                        code_hash: {
-                            match code {
-                                Some(Either::Left(Hash { raw_hash })) => raw_hash.into(),
-                                Some(Either::Right(Code { code })) => {
-                                    let hash = keccak_hash::keccak(&code);
-                                    frontend.code.insert(code);
-                                    hash
-                                }
-                                None => keccak_hash::keccak([]),
-                            }
-                        },
-                    };
-                    #[expect(deprecated)] // this is MPT-specific code
-                    let clobbered = frontend.state.insert_by_hashed_address(path, account)?;
-                    ensure!(clobbered.is_none(), "duplicate account");
-                }
+                Either::Right(account) => accounts.push((path, account)),
             }
         }
-        Node::Extension(Extension { key, child }) => {
-            path.with_all(key, |path| visit(frontend, path, *child))?
-        }
+        Node::Extension(Extension { key, child }) => path.with_all(key, |path| {
+            visit(frontend, accounts, resolver, path, *child)
+        })?,
         Node::Branch(Branch { children }) => {
             for (ix, node) in children.into_iter().enumerate() {
                 if let Some(node) = node {
                     path.with(
                         U4::new(ix.try_into().expect("ix is in range 0..16"))
                             .expect("ix is in range 0..16"),
-                        |path| visit(frontend, path, *node),
+                        |path| visit(frontend, accounts, resolver, path, *node),
                     )?;
                 }
             }
         }
         Node::Code(Code { code }) => {
-            frontend.code.insert(code);
+            frontend.code.insert(Arc::unwrap_or_clone(code));
         }
-        Node::Empty => {}
+        Node::EmptyCode | Node::Empty => {}
     }
     Ok(())
 }
 
-fn node2storagetrie(node: Node) -> anyhow::Result<StorageTrie> {
+fn node2storagetrie(
+    node: Node,
+    resolver: &(dyn Fn(H256) -> Option<Vec<Instruction>> + Sync),
+) -> anyhow::Result<StorageTrie> {
+    let mut mpt = StorageTrie::new(OnOrphanedHashNode::CollapseToExtension);
+    extend_storage_trie(&mut mpt, resolver, node)?;
+    Ok(mpt)
+}
+
+/// Inserts the storage leaves and hash stubs of `node`, a storage trie, into
+/// `mpt`, rejecting leaves at keys which `mpt` already has.
+///
+/// Like the state trie, stubs which `resolver` knows are expanded in place.
+fn extend_storage_trie(
+    mpt: &mut StorageTrie,
+    resolver: &(dyn Fn(H256) -> Option<Vec<Instruction>> + Sync),
+    node: Node,
+) -> anyhow::Result<()> {
     fn visit(
         mpt: &mut StorageTrie,
+        resolver: &(dyn Fn(H256) -> Option<Vec<Instruction>> + Sync),
         path: &stackstack::Stack<U4>,
         node: Node,
     ) -> anyhow::Result<()> {
         match node {
-            Node::Hash(Hash { raw_hash }) => {
-                mpt.insert_hash(TrieKey::new(path.iter().copied())?, raw_hash.into())?;
-            }
-            Node::Leaf(Leaf { key, value }) => {
-                match value {
-                    Either::Left(Value { raw_value }) => mpt.insert(
-                        TrieKey::new(path.iter().copied().chain(key))?,
-                        rlp::encode(&raw_value.as_slice()).to_vec(),
-                    )?,
-                    Either::Right(_) => bail!("unexpected account node in storage trie"),
-                };
-            }
+            Node::Hash(Hash { raw_hash }) => match resolve(raw_hash.into(), resolver, path)
+                .context("couldn't resolve hash stub of storage trie")?
+            {
+                Some(subtrie) => visit(mpt, resolver, path, subtrie)?,
+                None => mpt
+                    .insert_hash(TrieKey::new(path.iter().copied())?, raw_hash.into())
+                    .with_context(|| {
+                        format!(
+                            "couldn't insert hash stub at path {:?} of storage trie",
+                            path.iter().collect::<Vec<_>>()
+                        )
+                    })?,
+            },
+            Node::Leaf(Leaf { key, value }) => match value {
+                Either::Left(Value { raw_value }) => {
+                    let key = TrieKey::new(path.iter().copied().chain(key))?;
+                    // Like accounts, slots can't be clobbered by a well-formed
+                    // witness, so don't let a later leaf silently win.
+                    let clobbered = mpt.insert(key, rlp::encode(&raw_value.as_slice()).to_vec())?;
+                    ensure!(clobbered.is_none(), "duplicate key {key} in storage trie");
+                }
+                Either::Right(_) => bail!("unexpected account node in storage trie"),
+            },
             Node::Extension(Extension { key, child }) => {
-                path.with_all(key, |path| visit(mpt, path, *child))?
+                path.with_all(key, |path| visit(mpt, resolver, path, *child))?
             }
             Node::Branch(Branch { children }) => {
                 for (ix, node) in children.into_iter().enumerate() {
@@ -161,20 +283,584 @@ fn node2storagetrie(node: Node) -> anyhow::Result<StorageTrie> {
                         path.with(
                             U4::new(ix.try_into().expect("ix is in range 0..16"))
                                 .expect("ix is in range 0..16"),
-                            |path| visit(mpt, path, *node),
+                            |path| visit(mpt, resolver, path, *node),
                         )?;
                     }
                 }
             }
-            Node::Code(_) => bail!("unexpected Code node in storage trie"),
+            Node::Code(_) | Node::EmptyCode => bail!("unexpected Code node in storage trie"),
             Node::Empty => {}
         }
         Ok(())
     }
 
-    let mut mpt = StorageTrie::new(OnOrphanedHashNode::CollapseToExtension);
-    visit(&mut mpt, &stackstack::Stack::new(), node)?;
-    Ok(mpt)
+    visit(mpt, resolver, &stackstack::Stack::new(), node)
+}
+
+/// Callbacks for walking a [`ParsedWitness`] with
+/// [`ParsedWitness::visit_nodes`].
+///
+/// Every callback is given the nibble path of its node from the root of the
+/// trie it is in.
+/// Storage tries are walked right after the account which owns them, with
+/// paths starting afresh from the storage root.
+/// All callbacks default to doing nothing.
+pub trait NodeVisitor {
+    /// An account leaf of the state trie.
+    fn on_account(
+        &mut self,
+        path: &[U4],
+        nonce: ethereum_types::U256,
+        balance: ethereum_types::U256,
+    ) {
+        let _ = (path, nonce, balance);
+    }
+    /// A storage leaf, with its raw value.
+    fn on_leaf(&mut self, path: &[U4], value: &[u8]) {
+        let _ = (path, value);
+    }
+    /// `mask` has bit `i` set if the branch has child `i`.
+    fn on_branch(&mut self, path: &[U4], mask: u16) {
+        let _ = (path, mask);
+    }
+    /// A hash stub, or the code hash of an account.
+    fn on_hash(&mut self, path: &[U4], hash: H256) {
+        let _ = (path, hash);
+    }
+    /// A bare code node, or the inline code of an account.
+    fn on_code(&mut self, path: &[U4], code: &[u8]) {
+        let _ = (path, code);
+    }
+}
+
+/// A witness executed into a single state trie, for consumers which want to
+/// walk it with their own [`NodeVisitor`].
+#[derive(Debug, Clone)]
+pub struct ParsedWitness {
+    root: Node,
+    repaired: bool,
+}
+
+impl ParsedWitness {
+    /// Parses and executes the witness in `input`.
+    pub fn parse(input: &[u8]) -> anyhow::Result<Self> {
+        Self::parse_with_repair(input, false)
+    }
+
+    /// Like [`ParsedWitness::parse`], but if `repair` is set, a witness which
+    /// ends before its trie is closed, e.g. because it is missing its
+    /// terminating empty root, is completed with implicit empty nodes rather
+    /// than rejected.
+    ///
+    /// See [`ParsedWitness::repaired`].
+    pub fn parse_with_repair(input: &[u8], repair: bool) -> anyhow::Result<Self> {
+        Self::new_with_repair(crate::wire::parse(input)?, repair)
+    }
+
+    /// Like [`ParsedWitness::parse`], but for legacy witnesses where the code
+    /// of an account without storage may be up to `code_lookback` nodes
+    /// before its account leaf, rather than right before it.
+    ///
+    /// A code node within that window takes precedence over e.g. a hash
+    /// stub right before the account leaf. Without one, the node right before
+    /// the account leaf must be its code hash.
+    pub fn parse_with_code_lookback(input: &[u8], code_lookback: usize) -> anyhow::Result<Self> {
+        Self::new_with(crate::wire::parse(input)?, false, code_lookback)
+    }
+
+    /// Whether the witness had to be repaired, see
+    /// [`ParsedWitness::parse_with_repair`].
+    pub fn repaired(&self) -> bool {
+        self.repaired
+    }
+
+    fn new(instructions: impl IntoIterator<Item = Instruction>) -> anyhow::Result<Self> {
+        Self::new_with_repair(instructions, false)
+    }
+
+    fn new_with_repair(
+        instructions: impl IntoIterator<Item = Instruction>,
+        repair: bool,
+    ) -> anyhow::Result<Self> {
+        Self::new_with(instructions, repair, 0)
+    }
+
+    fn new_with(
+        instructions: impl IntoIterator<Item = Instruction>,
+        repair: bool,
+        code_lookback: usize,
+    ) -> anyhow::Result<Self> {
+        let (execution, repaired) =
+            execute_single_with_repair(instructions, repair, code_lookback)?;
+        Ok(Self {
+            root: Node::from(execution),
+            repaired,
+        })
+    }
+
+    /// Walks the trie depth-first, visiting branch children in order.
+    pub fn visit_nodes(&self, visitor: &mut dyn NodeVisitor) {
+        fn walk(visitor: &mut dyn NodeVisitor, path: &mut Vec<U4>, node: &Node) {
+            match node {
+                Node::Hash(Hash { raw_hash }) => visitor.on_hash(path, H256(*raw_hash)),
+                Node::Leaf(Leaf { key, value }) => {
+                    let len = path.len();
+                    path.extend(key.iter().copied());
+                    match value {
+                        Either::Left(Value { raw_value }) => visitor.on_leaf(path, raw_value),
+                        Either::Right(Account {
+                            nonce,
+                            balance,
+                            storage,
+                            code,
+                        }) => {
+                            visitor.on_account(path, *nonce, *balance);
+                            match code {
+                                Some(Either::Left(Hash { raw_hash })) => {
+                                    visitor.on_hash(path, H256(*raw_hash))
+                                }
+                                Some(Either::Right(Code { code })) => visitor.on_code(path, code),
+                                None => {}
+                            }
+                            if let Some(storage) = storage {
+                                walk(visitor, &mut vec![], storage)
+                            }
+                        }
+                    }
+                    path.truncate(len);
+                }
+                Node::Extension(Extension { key, child }) => {
+                    let len = path.len();
+                    path.extend(key.iter().copied());
+                    walk(visitor, path, child);
+                    path.truncate(len);
+                }
+                Node::Branch(Branch { children }) => {
+                    let mask = children
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, child)| child.is_some())
+                        .fold(0, |mask, (ix, _)| mask | 1 << ix);
+                    visitor.on_branch(path, mask);
+                    for (ix, child) in children.iter().enumerate() {
+                        if let Some(child) = child {
+                            path.push(
+                                U4::new(ix.try_into().expect("ix is in range 0..16"))
+                                    .expect("ix is in range 0..16"),
+                            );
+                            walk(visitor, path, child);
+                            path.pop();
+                        }
+                    }
+                }
+                Node::Code(Code { code }) => visitor.on_code(path, code),
+                Node::EmptyCode | Node::Empty => {}
+            }
+        }
+        walk(visitor, &mut vec![], &self.root)
+    }
+}
+
+/// Parses the witnesses of e.g. a range of blocks, storing contract code which
+/// is identical across them only once.
+#[derive(Debug, Clone, Default)]
+pub struct BatchWitnessParser {
+    code: BTreeSet<Arc<NonEmpty<Vec<u8>>>>,
+}
+
+impl BatchWitnessParser {
+    /// Creates a parser which hasn't seen any code yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Like [`ParsedWitness::parse`], but code seen in a previous witness is
+    /// shared with it.
+    pub fn parse(&mut self, input: &[u8]) -> anyhow::Result<ParsedWitness> {
+        Ok(self.share(ParsedWitness::parse(input)?))
+    }
+
+    /// The number of distinct pieces of code seen so far.
+    pub fn num_codes(&self) -> usize {
+        self.code.len()
+    }
+
+    fn share(&mut self, mut witness: ParsedWitness) -> ParsedWitness {
+        fn walk(cache: &mut BTreeSet<Arc<NonEmpty<Vec<u8>>>>, node: &mut Node) {
+            let mut share = |code: &mut Arc<NonEmpty<Vec<u8>>>| match cache.get(code) {
+                Some(shared) => *code = Arc::clone(shared),
+                None => {
+                    cache.insert(Arc::clone(code));
+                }
+            };
+            match node {
+                Node::Code(Code { code }) => share(code),
+                Node::Leaf(Leaf {
+                    value: Either::Right(Account { storage, code, .. }),
+                    ..
+                }) => {
+                    if let Some(Either::Right(Code { code })) = code {
+                        share(code)
+                    }
+                    if let Some(storage) = storage {
+                        walk(cache, storage)
+                    }
+                }
+                Node::Extension(Extension { child, .. }) => walk(cache, child),
+                Node::Branch(Branch { children }) => {
+                    for child in children.iter_mut().flatten() {
+                        walk(cache, child)
+                    }
+                }
+                Node::Hash(_) | Node::Leaf(_) | Node::EmptyCode | Node::Empty => {}
+            }
+        }
+        walk(&mut self.code, &mut witness.root);
+        witness
+    }
+}
+
+/// The nodes of a [`ParsedWitness`] which carry data, each with its full
+/// nibble path from the root of its trie, for auditing.
+///
+/// See [`ParsedWitness::annotate`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AnnotatedTrie {
+    /// Accounts and hash stubs of the state trie, in traversal order.
+    pub state: Vec<(Vec<u8>, AnnotatedNode)>,
+    /// Storage leaves and hash stubs, keyed by the path of their account.
+    pub storage: BTreeMap<Vec<u8>, Vec<(Vec<u8>, AnnotatedNode)>>,
+}
+
+/// The storage slots of an account which are present in a [`ParsedWitness`].
+///
+/// See [`ParsedWitness::touched_storage_slots`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TouchedStorage {
+    /// The hashed keys of the storage leaves, in traversal order.
+    pub slots: Vec<H256>,
+    /// Whether part of the storage trie is a hash stub, so that the account
+    /// may have more slots than those listed.
+    pub partial: bool,
+}
+
+/// A node of an [`AnnotatedTrie`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AnnotatedNode {
+    /// An account leaf of the state trie.
+    Account {
+        /// The nonce of the account.
+        nonce: ethereum_types::U256,
+        /// The balance of the account, in wei.
+        balance: ethereum_types::U256,
+        /// The account as it is encoded in the state trie, or [`None`] if
+        /// its storage trie is malformed.
+        rlp: Option<Vec<u8>>,
+    },
+    /// A storage leaf, with its raw value.
+    Leaf(Vec<u8>),
+    /// A hash stub, standing in for a subtrie.
+    Hash(H256),
+}
+
+impl ParsedWitness {
+    /// Records the full nibble path of each account, storage leaf and hash
+    /// stub, using the same traversal as trie construction.
+    pub fn annotate(&self) -> AnnotatedTrie {
+        fn walk(trie: &mut AnnotatedTrie, account: Option<&[u8]>, path: &mut Vec<u8>, node: &Node) {
+            let push = |trie: &mut AnnotatedTrie, path: &[u8], it| match account {
+                Some(account) => trie
+                    .storage
+                    .entry(account.to_vec())
+                    .or_default()
+                    .push((path.to_vec(), it)),
+                None => trie.state.push((path.to_vec(), it)),
+            };
+            match node {
+                Node::Hash(Hash { raw_hash }) => {
+                    push(trie, path, AnnotatedNode::Hash(H256(*raw_hash)))
+                }
+                Node::Leaf(Leaf { key, value }) => {
+                    let len = path.len();
+                    path.extend(key.iter().map(|it| *it as u8));
+                    match value {
+                        Either::Left(Value { raw_value }) => {
+                            push(trie, path, AnnotatedNode::Leaf(raw_value.to_vec()))
+                        }
+                        Either::Right(
+                            account @ Account {
+                                nonce,
+                                balance,
+                                storage,
+                                code: _,
+                            },
+                        ) => {
+                            push(
+                                trie,
+                                path,
+                                AnnotatedNode::Account {
+                                    nonce: *nonce,
+                                    balance: *balance,
+                                    rlp: account.to_rlp(),
+                                },
+                            );
+                            if let Some(storage) = storage {
+                                walk(trie, Some(path.as_slice()), &mut vec![], storage)
+                            }
+                        }
+                    }
+                    path.truncate(len);
+                }
+                Node::Extension(Extension { key, child }) => {
+                    let len = path.len();
+                    path.extend(key.iter().map(|it| *it as u8));
+                    walk(trie, account, path, child);
+                    path.truncate(len);
+                }
+                Node::Branch(Branch { children }) => {
+                    for (ix, child) in children.iter().enumerate() {
+                        if let Some(child) = child {
+                            path.push(ix.try_into().expect("ix is in range 0..16"));
+                            walk(trie, account, path, child);
+                            path.pop();
+                        }
+                    }
+                }
+                Node::Code(_) | Node::EmptyCode | Node::Empty => {}
+            }
+        }
+        let mut trie = AnnotatedTrie::default();
+        walk(&mut trie, None, &mut vec![], &self.root);
+        trie
+    }
+
+    /// Collects the storage slots of each account with storage, keyed by
+    /// hashed address, e.g. to check an access list against the witness.
+    ///
+    /// Witnesses only carry hashed addresses and slot keys, so those are what
+    /// is returned.
+    pub fn touched_storage_slots(&self) -> anyhow::Result<BTreeMap<H256, TouchedStorage>> {
+        fn hash(path: &[u8]) -> anyhow::Result<H256> {
+            TrieKey::new(
+                path.iter()
+                    .map(|it| U4::new(*it).expect("nibbles are in range 0..16")),
+            )?
+            .into_hash()
+            .with_context(|| format!("invalid depth for path {:?}", path))
+        }
+
+        let mut touched = BTreeMap::new();
+        for (account, nodes) in self.annotate().storage {
+            let mut storage = TouchedStorage::default();
+            for (path, node) in nodes {
+                match node {
+                    AnnotatedNode::Leaf(_) => storage
+                        .slots
+                        .push(hash(&path).context("invalid depth for leaf of storage trie")?),
+                    AnnotatedNode::Hash(_) => storage.partial = true,
+                    AnnotatedNode::Account { .. } => bail!("unexpected account in storage trie"),
+                }
+            }
+            let account = hash(&account).context("invalid depth for account of state trie")?;
+            ensure!(
+                touched.insert(account, storage).is_none(),
+                "duplicate account {account:x}"
+            );
+        }
+        Ok(touched)
+    }
+}
+
+/// Replaces the subtries of the witness in `input` which are `max_depth` or
+/// more nodes below the root with hash stubs, and re-encodes it, e.g. to
+/// derive progressively shallower test fixtures from a full witness.
+///
+/// The storage trie of an account counts as its child, and subtries which
+/// are small enough to be embedded in their parent rather than hashed are
+/// kept, so the truncated witness has the same state root.
+pub fn truncate_witness(input: &[u8], max_depth: usize) -> anyhow::Result<Vec<u8>> {
+    fn truncate(node: Node, depth: usize, max_depth: usize) -> Node {
+        if depth >= max_depth {
+            if let Some(rlp) = node_rlp(&node).filter(|rlp| rlp.len() >= 32) {
+                return Node::Hash(Hash {
+                    raw_hash: keccak_hash::keccak(rlp).0,
+                });
+            }
+        }
+        let descend = |child: Box<Node>| Box::new(truncate(*child, depth + 1, max_depth));
+        match node {
+            Node::Leaf(Leaf {
+                key,
+                value: Either::Right(account),
+            }) => Node::Leaf(Leaf {
+                key,
+                value: Either::Right(Account {
+                    storage: account.storage.map(descend),
+                    ..account
+                }),
+            }),
+            Node::Extension(Extension { key, child }) => Node::Extension(Extension {
+                key,
+                child: descend(child),
+            }),
+            Node::Branch(Branch { children }) => Node::Branch(Branch {
+                children: children.map(|child| child.map(descend)),
+            }),
+            it
+            @ (Node::Hash(_) | Node::Leaf(_) | Node::Code(_) | Node::EmptyCode | Node::Empty) => it,
+        }
+    }
+
+    let version = crate::wire::read_version(input)?;
+    let ParsedWitness { root, .. } = ParsedWitness::parse(input)?;
+    let mut instructions = vec![];
+    node2instructions(truncate(root, 0, max_depth), &mut instructions);
+    Ok(crate::wire::encode(version, &instructions))
+}
+
+/// The inverse of [`execute`], for a single trie.
+fn node2instructions(node: Node, instructions: &mut Vec<Instruction>) {
+    match node {
+        Node::Hash(Hash { raw_hash }) => instructions.push(Instruction::Hash { raw_hash }),
+        Node::Leaf(Leaf {
+            key,
+            value: Either::Left(Value { raw_value }),
+        }) => instructions.push(Instruction::Leaf {
+            key,
+            value: raw_value,
+        }),
+        Node::Leaf(Leaf {
+            key,
+            value:
+                Either::Right(Account {
+                    nonce,
+                    balance,
+                    storage,
+                    code,
+                }),
+        }) => {
+            // The code is pushed first, so that it is popped after the storage.
+            let has_code = code.is_some();
+            match code {
+                Some(Either::Left(Hash { raw_hash })) => {
+                    instructions.push(Instruction::Hash { raw_hash })
+                }
+                Some(Either::Right(Code { code })) => instructions.push(Instruction::Code {
+                    raw_code: Arc::unwrap_or_clone(code),
+                }),
+                None => {}
+            }
+            let has_storage = storage.is_some();
+            if let Some(storage) = storage {
+                node2instructions(*storage, instructions)
+            }
+            instructions.push(Instruction::AccountLeaf {
+                key,
+                nonce: Some(nonce),
+                balance: Some(balance),
+                has_code,
+                has_storage,
+                storage_root: None,
+                incarnation: None,
+            })
+        }
+        Node::Extension(Extension { key, child }) => {
+            node2instructions(*child, instructions);
+            instructions.push(Instruction::Extension { key })
+        }
+        Node::Branch(Branch { children }) => {
+            // The children are popped from the highest index down.
+            let mut mask = 0;
+            for (ix, child) in children.into_iter().enumerate() {
+                if let Some(child) = child {
+                    mask |= 1 << ix;
+                    node2instructions(*child, instructions)
+                }
+            }
+            instructions.push(Instruction::Branch { mask })
+        }
+        Node::Code(Code { code }) => instructions.push(Instruction::Code {
+            raw_code: Arc::unwrap_or_clone(code),
+        }),
+        Node::EmptyCode => instructions.push(Instruction::EmptyCode),
+        Node::Empty => instructions.push(Instruction::EmptyRoot),
+    }
+}
+
+/// The hash of `node` as a node of a Merkle Patricia Trie, i.e. the hash a
+/// stub for it carries, or [`None`] if it isn't one, see [`node_rlp`].
+fn node_hash(node: &Node) -> Option<H256> {
+    match node {
+        Node::Hash(Hash { raw_hash }) => Some(H256(*raw_hash)),
+        node => node_rlp(node).map(keccak_hash::keccak),
+    }
+}
+
+/// The RLP encoding of `node` as a node of a Merkle Patricia Trie, or [`None`]
+/// if it isn't one, i.e. it is (or contains) a bare [`Node::Code`] or is
+/// already a [`Node::Hash`].
+fn node_rlp(node: &Node) -> Option<Vec<u8>> {
+    /// Compact encoding of a path, see the Yellow Paper, appendix C.
+    fn hex_prefix(key: &[U4], leaf: bool) -> Vec<u8> {
+        let flag = (u8::from(leaf) << 1) | u8::from(key.len() % 2 == 1);
+        let (mut encoded, rest) = match key.split_first() {
+            Some((first, rest)) if key.len() % 2 == 1 => (vec![(flag << 4) | *first as u8], rest),
+            _ => (vec![flag << 4], key),
+        };
+        encoded.extend(
+            rest.chunks(2)
+                .map(|pair| ((pair[0] as u8) << 4) | pair[1] as u8),
+        );
+        encoded
+    }
+    /// How a parent refers to `node`.
+    fn append_child(stream: &mut rlp::RlpStream, node: &Node) -> Option<()> {
+        match node {
+            Node::Hash(Hash { raw_hash }) => {
+                stream.append(&raw_hash.as_slice());
+            }
+            node => {
+                let rlp = node_rlp(node)?;
+                match rlp.len() < 32 {
+                    true => stream.append_raw(&rlp, 1),
+                    false => stream.append(&keccak_hash::keccak(rlp).as_bytes()),
+                };
+            }
+        }
+        Some(())
+    }
+    let mut stream;
+    match node {
+        Node::Hash(_) | Node::Code(_) | Node::EmptyCode => return None,
+        Node::Empty => return Some(rlp::NULL_RLP.to_vec()),
+        Node::Leaf(Leaf { key, value }) => {
+            stream = rlp::RlpStream::new_list(2);
+            stream.append(&hex_prefix(key, true));
+            let value = match value {
+                Either::Left(Value { raw_value }) => rlp::encode(&raw_value.as_slice()).to_vec(),
+                Either::Right(account) => account.to_rlp()?,
+            };
+            stream.append(&value);
+        }
+        Node::Extension(Extension { key, child }) => {
+            stream = rlp::RlpStream::new_list(2);
+            stream.append(&hex_prefix(key, false));
+            append_child(&mut stream, child)?;
+        }
+        Node::Branch(Branch { children }) => {
+            stream = rlp::RlpStream::new_list(17);
+            for child in children {
+                match child {
+                    Some(child) => append_child(&mut stream, child)?,
+                    None => {
+                        stream.append_empty_data();
+                    }
+                }
+            }
+            stream.append_empty_data();
+        }
+    }
+    Some(stream.out().to_vec())
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -189,15 +875,49 @@ struct Value {
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 struct Account {
-    nonce: u64,
+    nonce: ethereum_types::U256,
     balance: ethereum_types::U256,
     storage: Option<Box<Node>>,
     code: Option<Either<Hash, Code>>,
 }
 
+impl Account {
+    /// The RLP encoding of this account as a value of the state trie, as
+    /// [`AccountRlp`] encodes it, or [`None`] if its storage isn't a trie, see
+    /// [`node_rlp`].
+    fn to_rlp(&self) -> Option<Vec<u8>> {
+        let Self {
+            nonce,
+            balance,
+            storage,
+            code,
+        } = self;
+        let storage_root = match storage.as_deref() {
+            Some(Node::Hash(Hash { raw_hash })) => H256(*raw_hash),
+            Some(node) => keccak_hash::keccak(node_rlp(node)?),
+            None => keccak_hash::KECCAK_NULL_RLP,
+        };
+        let code_hash = match code {
+            Some(Either::Left(Hash { raw_hash })) => H256(*raw_hash),
+            Some(Either::Right(Code { code })) => keccak_hash::keccak(code.as_slice()),
+            None => keccak_hash::KECCAK_EMPTY,
+        };
+        Some(
+            rlp::encode(&AccountRlp {
+                nonce: *nonce,
+                balance: *balance,
+                storage_root,
+                code_hash,
+            })
+            .to_vec(),
+        )
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 struct Code {
-    code: NonEmpty<Vec<u8>>,
+    /// Shared between witnesses by [`BatchWitnessParser`].
+    code: Arc<NonEmpty<Vec<u8>>>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -228,6 +948,10 @@ enum Node {
     Extension(Extension),
     Branch(Branch),
     Code(Code),
+    /// The code of an account without code, see [`Instruction::EmptyCode`].
+    ///
+    /// Unlike [`Node::Empty`], this can't stand in for a trie.
+    EmptyCode,
     // BUG: this is undocumented, see [`Instruction::EmptyRoot`]
     Empty,
 }
@@ -246,23 +970,66 @@ enum Execution {
 ///
 /// That spec is why we return multiple [`Execution`]s, even though we only
 /// support one.
+///
+/// Each instruction collapses the nodes it consumes from the top of the stack
+/// as soon as it is read, so the instructions are walked exactly once, and
+/// nothing needs re-traversing after a node is replaced.
 fn execute(
     instructions: impl IntoIterator<Item = Instruction>,
 ) -> anyhow::Result<NonEmpty<Vec<Execution>>> {
+    execute_with_repair(instructions, false, 0).map(|(executions, _repaired)| executions)
+}
+
+/// Like [`execute`], but if `repair` is set, a witness which ends before its
+/// tries are closed, e.g. because it is missing its terminating
+/// [`Instruction::EmptyRoot`], is completed with implicit empty nodes.
+///
+/// Also returns whether such a repair occurred: without `repair`, those
+/// witnesses are rejected.
+///
+/// Legacy witnesses may emit the code of an [`Instruction::AccountLeaf`]
+/// without storage before other nodes, e.g. hash stubs of its siblings: its
+/// code is then looked for up to `code_lookback` nodes below the top of the
+/// stack, rather than only at the top.
+fn execute_with_repair(
+    instructions: impl IntoIterator<Item = Instruction>,
+    repair: bool,
+    code_lookback: usize,
+) -> anyhow::Result<(NonEmpty<Vec<Execution>>, bool)> {
     let mut witnesses = vec![];
     let mut stack = vec![];
+    // Only the trie structure is repaired, so e.g. a missing Code for an
+    // AccountLeaf is still an error.
+    let mut repaired = false;
 
     for instruction in instructions {
         match instruction {
             Instruction::EmptyRoot => stack.push(Node::Empty),
             Instruction::Hash { raw_hash } => stack.push(Node::Hash(Hash { raw_hash })),
-            Instruction::Code { raw_code } => stack.push(Node::Code(Code { code: raw_code })),
+            Instruction::Code { raw_code } => stack.push(Node::Code(Code {
+                code: Arc::new(raw_code),
+            })),
+            // Empty code is the code of accounts without code, see the
+            // AccountLeaf handling below.
+            Instruction::EmptyCode => stack.push(Node::EmptyCode),
+            // The hash has already been checked against the code by the parser.
+            Instruction::CodeWithHash { raw_code, .. } => stack.push(Node::Code(Code {
+                code: Arc::new(raw_code),
+            })),
             Instruction::Leaf { key, value } => stack.push(Node::Leaf(Leaf {
                 key,
                 value: Either::Left(Value { raw_value: value }),
             })),
             Instruction::Extension { key } => {
-                let child = Box::new(stack.pop().context("no Node for Extension")?);
+                let child = match stack.pop() {
+                    Some(child) => child,
+                    None if repair => {
+                        repaired = true;
+                        Node::Empty
+                    }
+                    None => bail!("no Node for Extension"),
+                };
+                let child = Box::new(child);
                 stack.push(Node::Extension(Extension { key, child }))
             }
             Instruction::AccountLeaf {
@@ -271,13 +1038,20 @@ fn execute(
                 balance,
                 has_code,
                 has_storage,
+                storage_root,
+                // Storage tries are keyed by hashed address alone here, so
+                // incarnations don't affect the tries we build.
+                incarnation: _,
             } => {
                 // BUG: the spec sometimes writes Node::Account with 5 fields..
                 // TODO(0xaatif): https://github.com/0xPolygonZero/zk_evm/issues/275
                 //                should these fields even be optional?
                 let nonce = nonce.unwrap_or_default();
                 let balance = balance.unwrap_or_default();
-                let account = match (has_code, has_storage) {
+                // An inline storage root stands in for the storage node that
+                // would otherwise follow on the stack.
+                let has_storage_node = has_storage && storage_root.is_none();
+                let mut account = match (has_code, has_storage_node) {
                     (true, true) => {
                         let right = stack.pop();
                         let left = stack.pop();
@@ -294,6 +1068,12 @@ fn execute(
                                 storage: Some(Box::new(storage)),
                                 code: Some(Either::Right(code)),
                             },
+                            (Some(Node::EmptyCode), Some(storage)) => Account {
+                                nonce,
+                                balance,
+                                storage: Some(Box::new(storage)),
+                                code: None,
+                            },
                             other => bail!(
                                 "expected (Code | Hash, Node) for AccountLeaf, got {:?}",
                                 other
@@ -310,19 +1090,25 @@ fn execute(
                             code: None,
                         }
                     }
-                    (true, false) => match stack.pop() {
-                        Some(Node::Hash(it)) => Account {
+                    (true, false) => match pop_code(&mut stack, code_lookback)? {
+                        Node::Hash(it) => Account {
                             nonce,
                             balance,
                             storage: None,
                             code: Some(Either::Left(it)),
                         },
-                        Some(Node::Code(it)) => Account {
+                        Node::Code(it) => Account {
                             nonce,
                             balance,
                             storage: None,
                             code: Some(Either::Right(it)),
                         },
+                        Node::EmptyCode => Account {
+                            nonce,
+                            balance,
+                            storage: None,
+                            code: None,
+                        },
                         other => bail!("expected Code | Hash for AccountLeaf, got {:?}", other),
                     },
                     (false, false) => Account {
@@ -332,6 +1118,9 @@ fn execute(
                         code: None,
                     },
                 };
+                if let Some(raw_hash) = storage_root {
+                    account.storage = Some(Box::new(Node::Hash(Hash { raw_hash })));
+                }
                 stack.push(Node::Leaf(Leaf {
                     key,
                     value: Either::Right(account),
@@ -339,26 +1128,117 @@ fn execute(
             }
             Instruction::Branch { mask } => {
                 use bitvec::{order::Lsb0, view::BitView as _};
+                let expected = mask.count_ones() as usize;
+                ensure!(
+                    repair || stack.len() >= expected,
+                    "Branch with mask {mask:#06x} expects {expected} children, \
+                     but only {} nodes are available - is the witness truncated?",
+                    stack.len()
+                );
+                // The missing children are the last ones, which are popped first.
+                let mut missing = match repair {
+                    true => expected.saturating_sub(stack.len()),
+                    false => 0,
+                };
+                repaired |= missing > 0;
                 let mut children = array::from_fn(|_ix| None);
                 for (ix, it) in mask.view_bits::<Lsb0>().iter().by_vals().enumerate().rev() {
                     if it {
-                        *children.get_mut(ix).context("oob mask bit for Branch")? =
-                            Some(Box::new(stack.pop().context("no Node for Branch")?));
+                        let child = match missing {
+                            0 => stack.pop().context("no Node for Branch")?,
+                            _ => {
+                                missing -= 1;
+                                Node::Empty
+                            }
+                        };
+                        // An empty root may stand in for a child, which is
+                        // the same as the child being absent.
+                        *children.get_mut(ix).context("oob mask bit for Branch")? = match child {
+                            Node::Empty => None,
+                            child => Some(Box::new(child)),
+                        };
                     }
                 }
                 stack.push(Node::Branch(Branch { children }))
             }
-            Instruction::NewTrie => witnesses.push(finish_stack(&mut stack)?),
+            Instruction::NewTrie => {
+                if repair && stack.is_empty() {
+                    repaired = true;
+                    stack.push(Node::Empty)
+                }
+                witnesses.push(finish_stack(&mut stack)?)
+            }
             Instruction::SmtLeaf(SmtLeaf { .. }) => {
                 bail!("unexpected SmtLeaf instruction in type 1 format")
             }
         }
     }
+    if repair && stack.is_empty() {
+        repaired = true;
+        stack.push(Node::Empty)
+    }
     witnesses.push(finish_stack(&mut stack)?);
 
-    NonEmpty::<Vec<_>>::new(witnesses)
+    let witnesses = NonEmpty::<Vec<_>>::new(witnesses)
         .ok()
-        .context("no instructions to execute")
+        .context("no instructions to execute")?;
+    Ok((witnesses, repaired))
+}
+
+/// Pops the code of an account leaf, looking for a [`Node::Code`] or
+/// [`Node::EmptyCode`] up to `lookback` nodes below the top of the stack, see
+/// [`execute_with_repair`].
+///
+/// Without such code in reach, the top node is popped, which must then be a
+/// code hash if `lookback` is set.
+fn pop_code(stack: &mut Vec<Node>, lookback: usize) -> anyhow::Result<Node> {
+    if lookback == 0 {
+        return stack.pop().context("no Node for AccountLeaf");
+    }
+    let depth = stack
+        .iter()
+        .rev()
+        .take(lookback + 1)
+        .position(|node| matches!(node, Node::Code(_) | Node::EmptyCode));
+    match depth {
+        Some(depth) => Ok(stack.remove(stack.len() - 1 - depth)),
+        None => match stack.pop() {
+            Some(node @ Node::Hash(_)) => Ok(node),
+            _ => bail!("no code or code hash within {lookback} nodes of AccountLeaf"),
+        },
+    }
+}
+
+/// [`execute`] instructions which must describe a single trie.
+fn execute_single(
+    instructions: impl IntoIterator<Item = Instruction>,
+) -> anyhow::Result<Execution> {
+    execute_single_with_repair(instructions, false, 0).map(|(execution, _repaired)| execution)
+}
+
+/// [`execute_with_repair`] instructions which must describe a single trie.
+fn execute_single_with_repair(
+    instructions: impl IntoIterator<Item = Instruction>,
+    repair: bool,
+    code_lookback: usize,
+) -> anyhow::Result<(Execution, bool)> {
+    let (executions, repaired) = execute_with_repair(instructions, repair, code_lookback)?;
+    ensure!(
+        executions.len() == 1,
+        "only a single execution is supported"
+    );
+    Ok((executions.into_vec().remove(0), repaired))
+}
+
+impl From<Execution> for Node {
+    fn from(value: Execution) -> Self {
+        match value {
+            Execution::Leaf(it) => Node::Leaf(it),
+            Execution::Extension(it) => Node::Extension(it),
+            Execution::Branch(it) => Node::Branch(it),
+            Execution::Empty => Node::Empty,
+        }
+    }
 }
 
 /// Narrow (a) [`Node`] to a subset of its variants, an [`Execution`].
@@ -378,6 +1258,67 @@ fn finish_stack(v: &mut Vec<Node>) -> anyhow::Result<Execution> {
     }
 }
 
+/// Shorthands for hand-written instructions, shared by tests.
+#[cfg(test)]
+mod fixtures {
+    use ethereum_types::U256;
+    use nunny::NonEmpty;
+    use u4::U4;
+
+    use crate::wire::Instruction;
+
+    /// A key made of `nibbles`.
+    pub fn key(nibbles: &[u8]) -> NonEmpty<Vec<U4>> {
+        NonEmpty::<Vec<_>>::new(nibbles.iter().map(|it| U4::new(*it).unwrap()).collect()).unwrap()
+    }
+
+    /// A storage leaf at `nibbles`, with a single byte value.
+    pub fn leaf(nibbles: &[u8], value: u8) -> Instruction {
+        Instruction::Leaf {
+            key: key(nibbles),
+            value: nunny::vec![value],
+        }
+    }
+
+    /// An account leaf at `nibbles`, with a zero balance and without an inline
+    /// storage root.
+    pub fn account_leaf(
+        nibbles: &[u8],
+        nonce: Option<u64>,
+        has_code: bool,
+        has_storage: bool,
+    ) -> Instruction {
+        Instruction::AccountLeaf {
+            key: key(nibbles),
+            nonce: nonce.map(Into::into),
+            balance: None,
+            has_code,
+            has_storage,
+            storage_root: None,
+            incarnation: None,
+        }
+    }
+
+    /// An account leaf at `nibbles`, with a `nonce` and a big-endian
+    /// `balance`, but without code or storage.
+    pub fn funded_account_leaf(nibbles: &[u8], nonce: u64, balance: &[u8]) -> Instruction {
+        Instruction::AccountLeaf {
+            key: key(nibbles),
+            nonce: Some(nonce.into()),
+            balance: Some(U256::from_big_endian(balance)),
+            has_code: false,
+            has_storage: false,
+            storage_root: None,
+            incarnation: None,
+        }
+    }
+
+    /// `instructions` encoded as a witness with the version `0x01` header.
+    pub fn witness(instructions: &[Instruction]) -> Vec<u8> {
+        crate::wire::encode(crate::wire::read_version(&[0x01]).unwrap(), instructions)
+    }
+}
+
 #[test]
 fn test_tries() {
     for (ix, case) in
@@ -398,3 +1339,872 @@ fn test_tries() {
         }
     }
 }
+
+#[test]
+fn test_truncated_tail() {
+    let code = || Instruction::Code {
+        raw_code: nunny::vec![0x00],
+    };
+    let account = |has_code, has_storage| fixtures::account_leaf(&[0], None, has_code, has_storage);
+
+    // Each of these instructions is missing some of the nodes it consumes from
+    // the stack, which must be reported as an error rather than a panic.
+    for instructions in [
+        vec![Instruction::Extension {
+            key: fixtures::key(&[0]),
+        }],
+        vec![Instruction::Branch { mask: 0b11 }],
+        vec![Instruction::EmptyRoot, Instruction::Branch { mask: 0b11 }],
+        vec![account(true, false)],
+        vec![account(false, true)],
+        vec![code(), account(true, true)],
+    ] {
+        assert!(execute(instructions).is_err());
+    }
+
+    // ...whereas a complete tail executes.
+    assert!(execute([code(), Instruction::EmptyRoot, account(true, true)]).is_ok());
+}
+
+#[test]
+fn test_duplicate_storage_key() {
+    let leaf = |value| {
+        Node::Leaf(Leaf {
+            key: fixtures::key(&[0xA; 64]),
+            value: Either::Left(Value {
+                raw_value: nunny::vec![value],
+            }),
+        })
+    };
+    let mut mpt = node2storagetrie(leaf(0x01), &|_| None).unwrap();
+
+    // a second leaf at the same key is reported, rather than silently
+    // replacing the first
+    let e = extend_storage_trie(&mut mpt, &|_| None, leaf(0x02)).unwrap_err();
+    assert!(e.to_string().contains("duplicate key"), "{e}");
+}
+
+#[test]
+fn test_inline_storage_root() {
+    let account = |storage_root| Instruction::AccountLeaf {
+        key: fixtures::key(&[0]),
+        nonce: None,
+        balance: None,
+        has_code: false,
+        has_storage: true,
+        storage_root,
+        incarnation: None,
+    };
+    let storage = |it: Execution| match it {
+        Execution::Leaf(Leaf {
+            value: Either::Right(Account { storage, .. }),
+            ..
+        }) => storage.map(|it| *it),
+        other => panic!("expected an account leaf, got {:?}", other),
+    };
+
+    // storage follows as a separate node
+    let [execution] = execute([Instruction::EmptyRoot, account(None)])
+        .unwrap()
+        .into_vec()
+        .try_into()
+        .unwrap();
+    assert_eq!(storage(execution), Some(Node::Empty));
+
+    // storage root is inlined, and nothing is popped from the stack
+    let [execution] = execute([account(Some([0xAB; 32]))])
+        .unwrap()
+        .into_vec()
+        .try_into()
+        .unwrap();
+    assert_eq!(
+        storage(execution),
+        Some(Node::Hash(Hash {
+            raw_hash: [0xAB; 32]
+        }))
+    );
+}
+
+#[test]
+fn test_parallel_storage_tries() {
+    for case in
+        serde_json::from_str::<Vec<super::Case>>(include_str!("cases/zero_jerigon.json")).unwrap()
+    {
+        let instructions = crate::wire::parse(&case.bytes).unwrap();
+        let sequential = frontend_with(instructions.clone(), &|_| None, usize::MAX).unwrap();
+        let parallel = frontend_with(instructions, &|_| None, 0).unwrap();
+        assert_eq!(sequential.state.root(), parallel.state.root());
+        assert_eq!(
+            sequential
+                .storage
+                .iter()
+                .map(|(path, storage)| (*path, storage.root()))
+                .collect::<Vec<_>>(),
+            parallel
+                .storage
+                .iter()
+                .map(|(path, storage)| (*path, storage.root()))
+                .collect::<Vec<_>>(),
+        );
+    }
+}
+
+#[test]
+fn test_code_hashes() {
+    let account = || fixtures::account_leaf(&[0; 63], None, true, false);
+    let frontend = frontend([
+        Instruction::Code {
+            raw_code: nunny::vec![0x00],
+        },
+        account(),
+        Instruction::Hash {
+            raw_hash: [0xAB; 32],
+        },
+        account(),
+        Instruction::Branch { mask: 0b11 },
+    ])
+    .unwrap();
+
+    // inline code is hashed, and hashes are kept as-is
+    let code_hashes = frontend
+        .state
+        .iter()
+        .map(|(haddr, acct)| (haddr, acct.code_hash))
+        .collect::<BTreeMap<_, _>>();
+    let mut with_hash = [0; 32];
+    with_hash[0] = 0x10;
+    assert_eq!(
+        code_hashes,
+        BTreeMap::from([
+            (H256::zero(), keccak_hash::keccak([0x00])),
+            (H256(with_hash), H256([0xAB; 32])),
+        ])
+    );
+    assert_eq!(frontend.code, BTreeSet::from([nunny::vec![0x00]]));
+}
+
+#[test]
+fn test_empty_root_branch_child() {
+    let account = |key_len| fixtures::account_leaf(&vec![0; key_len], Some(1), false, false);
+    let instructions = || {
+        [
+            account(63),
+            Instruction::EmptyRoot,
+            Instruction::Branch { mask: 0b11 },
+        ]
+    };
+
+    match execute_single(instructions()).unwrap() {
+        Execution::Branch(Branch { children }) => {
+            assert!(children[0].is_some());
+            assert!(children[1].is_none());
+        }
+        other => panic!("expected a branch, got {:?}", other),
+    }
+    assert_eq!(
+        frontend(instructions()).unwrap().state.root(),
+        frontend([account(64)]).unwrap().state.root()
+    );
+}
+
+#[test]
+fn test_empty_code() {
+    let frontend = frontend([
+        Instruction::EmptyCode,
+        fixtures::account_leaf(&[0; 64], None, true, false),
+    ])
+    .unwrap();
+
+    // treated as an account without code
+    assert_eq!(
+        frontend
+            .state
+            .iter()
+            .map(|(_, acct)| acct.code_hash)
+            .collect::<Vec<_>>(),
+        [keccak_hash::keccak([])]
+    );
+    assert!(frontend.code.is_empty());
+}
+
+#[test]
+fn test_empty_root_as_code() {
+    let e = execute([
+        Instruction::EmptyRoot,
+        fixtures::account_leaf(&[0; 64], None, true, false),
+    ])
+    .unwrap_err();
+    assert!(e.to_string().contains("AccountLeaf"), "{e}");
+}
+
+#[test]
+fn test_branch_underflow() {
+    let leaf = |nibble| fixtures::leaf(&[nibble], 0x01);
+    let e = execute([leaf(0), leaf(1), Instruction::Branch { mask: 0b111 }]).unwrap_err();
+    assert!(
+        e.to_string()
+            .contains("expects 3 children, but only 2 nodes are available"),
+        "{e}"
+    );
+}
+
+#[test]
+fn test_cascading_collapse() {
+    let leaf = |nibble| {
+        Node::Leaf(Leaf {
+            key: fixtures::key(&[nibble]),
+            value: Either::Left(Value {
+                raw_value: nunny::vec![nibble],
+            }),
+        })
+    };
+    let mut children = array::from_fn(|_ix| None);
+    children[0] = Some(Box::new(leaf(0)));
+    children[1] = Some(Box::new(leaf(1)));
+    let storage = Node::Extension(Extension {
+        key: fixtures::key(&[2]),
+        child: Box::new(Node::Branch(Branch { children })),
+    });
+
+    // Each instruction collapses the node built by the previous one, all in a
+    // single pass over the instructions.
+    let execution = execute_single([
+        fixtures::leaf(&[0], 0),
+        fixtures::leaf(&[1], 1),
+        Instruction::Branch { mask: 0b11 },
+        Instruction::Extension {
+            key: fixtures::key(&[2]),
+        },
+        fixtures::account_leaf(&[3], Some(1), false, true),
+    ])
+    .unwrap();
+    assert_eq!(
+        execution,
+        Execution::Leaf(Leaf {
+            key: fixtures::key(&[3]),
+            value: Either::Right(Account {
+                nonce: 1.into(),
+                balance: 0.into(),
+                storage: Some(Box::new(storage)),
+                code: None,
+            }),
+        })
+    );
+}
+
+#[test]
+fn test_repair_missing_terminator() {
+    let leaf = |nibble| fixtures::leaf(&[nibble], 0x01);
+    // The second child of the branch, an empty root, is missing.
+    let instructions = || [leaf(0), Instruction::Branch { mask: 0b11 }];
+
+    assert!(ParsedWitness::new(instructions()).is_err());
+    let witness = ParsedWitness::new_with_repair(instructions(), true).unwrap();
+    assert!(witness.repaired());
+    assert_eq!(
+        witness.root,
+        ParsedWitness::new([
+            leaf(0),
+            Instruction::EmptyRoot,
+            Instruction::Branch { mask: 0b11 }
+        ])
+        .unwrap()
+        .root
+    );
+
+    // A missing extension child, and an empty witness.
+    for instructions in [
+        vec![Instruction::Extension {
+            key: fixtures::key(&[0]),
+        }],
+        vec![Instruction::NewTrie, leaf(0)],
+    ] {
+        assert!(execute(instructions.clone()).is_err());
+        let (_, repaired) = execute_with_repair(instructions, true, 0).unwrap();
+        assert!(repaired);
+    }
+
+    // Well-formed witnesses are left alone.
+    let witness = ParsedWitness::new_with_repair([leaf(0)], true).unwrap();
+    assert!(!witness.repaired());
+}
+
+#[test]
+fn test_visit_nodes() {
+    #[derive(Default)]
+    struct CountAccounts {
+        paths: Vec<Vec<U4>>,
+        branches: usize,
+    }
+    impl NodeVisitor for CountAccounts {
+        fn on_account(&mut self, path: &[U4], _: ethereum_types::U256, _: ethereum_types::U256) {
+            self.paths.push(path.to_vec())
+        }
+        fn on_branch(&mut self, _: &[U4], _: u16) {
+            self.branches += 1
+        }
+    }
+
+    let account = || fixtures::account_leaf(&[0; 63], None, false, false);
+    let witness = ParsedWitness::new([
+        account(),
+        account(),
+        account(),
+        Instruction::Branch { mask: 0b1011 },
+    ])
+    .unwrap();
+    let mut visitor = CountAccounts::default();
+    witness.visit_nodes(&mut visitor);
+
+    assert_eq!(visitor.branches, 1);
+    assert_eq!(
+        visitor.paths.iter().map(|path| path[0]).collect::<Vec<_>>(),
+        [0, 1, 3].map(|ix| U4::new(ix).unwrap())
+    );
+    assert!(visitor.paths.iter().all(|path| path.len() == 64));
+}
+
+#[test]
+fn test_code_lookback() {
+    // the code of the account comes before the hash stub of its sibling
+    let instructions = || {
+        [
+            Instruction::Code {
+                raw_code: nunny::vec![0x60, 0x00],
+            },
+            Instruction::Hash {
+                raw_hash: [0xBB; 32],
+            },
+            fixtures::account_leaf(&[0xC; 63], None, true, false),
+            Instruction::Branch { mask: 0b101 },
+        ]
+    };
+    let children = |witness: ParsedWitness| match witness.root {
+        Node::Branch(Branch { children }) => children,
+        other => panic!("expected a branch, got {other:?}"),
+    };
+
+    let [sibling, _, account, ..] = children(
+        ParsedWitness::parse_with_code_lookback(&fixtures::witness(&instructions()), 1).unwrap(),
+    );
+    assert_eq!(
+        sibling.as_deref(),
+        Some(&Node::Hash(Hash {
+            raw_hash: [0xBB; 32]
+        }))
+    );
+    let Some(Node::Leaf(Leaf {
+        value: Either::Right(Account { code, .. }),
+        ..
+    })) = account.as_deref()
+    else {
+        panic!("expected an account, got {account:?}")
+    };
+    assert!(matches!(code, Some(Either::Right(Code { code })) if code.as_slice() == [0x60, 0x00]));
+
+    // without lookback, the hash stub is taken for the code hash
+    let [sibling, ..] =
+        children(ParsedWitness::parse(&fixtures::witness(&instructions())).unwrap());
+    assert!(matches!(sibling.as_deref(), Some(Node::Code(_))));
+
+    // without code in the window, the top node is the code hash
+    let [_, hash, account_leaf, branch] = instructions();
+    let code_hash = Instruction::Hash {
+        raw_hash: [0xCC; 32],
+    };
+    let [sibling, _, account, ..] = children(
+        ParsedWitness::parse_with_code_lookback(
+            &fixtures::witness(&[hash, code_hash, account_leaf.clone(), branch.clone()]),
+            1,
+        )
+        .unwrap(),
+    );
+    assert_eq!(
+        sibling.as_deref(),
+        Some(&Node::Hash(Hash {
+            raw_hash: [0xBB; 32]
+        }))
+    );
+    assert!(matches!(
+        account.as_deref(),
+        Some(Node::Leaf(Leaf {
+            value: Either::Right(Account {
+                code: Some(Either::Left(Hash {
+                    raw_hash: [0xCC, ..]
+                })),
+                ..
+            }),
+            ..
+        }))
+    ));
+
+    // neither code nor a code hash
+    let e = ParsedWitness::parse_with_code_lookback(
+        &fixtures::witness(&[fixtures::leaf(&[0xA; 63], 0x01), account_leaf, branch]),
+        1,
+    )
+    .unwrap_err();
+    assert!(
+        format!("{e:#}").contains("no code or code hash within 1 nodes of AccountLeaf"),
+        "{e:#}"
+    );
+}
+
+#[test]
+fn test_touched_storage_slots() {
+    let account =
+        |nibble, has_storage| fixtures::account_leaf(&[nibble; 63], None, false, has_storage);
+    let witness = ParsedWitness::new([
+        fixtures::leaf(&[0xA; 63], 0x01),
+        fixtures::leaf(&[0xB; 63], 0x02),
+        Instruction::Branch { mask: 0b110 },
+        account(0xC, true),
+        Instruction::Hash {
+            raw_hash: [0xEE; 32],
+        },
+        account(0xD, true),
+        account(0xF, false),
+        Instruction::Branch { mask: 0b111 },
+    ])
+    .unwrap();
+
+    let hash = |first, rest| H256(array::from_fn(|ix| if ix == 0 { first } else { rest }));
+    assert_eq!(
+        witness.touched_storage_slots().unwrap(),
+        BTreeMap::from([
+            (
+                hash(0x0C, 0xCC),
+                TouchedStorage {
+                    slots: vec![hash(0x1A, 0xAA), hash(0x2B, 0xBB)],
+                    partial: false,
+                }
+            ),
+            (
+                hash(0x1D, 0xDD),
+                TouchedStorage {
+                    slots: vec![],
+                    partial: true,
+                }
+            ),
+        ])
+    );
+}
+
+#[test]
+fn test_annotate() {
+    let instructions = [
+        fixtures::leaf(&[0xA; 63], 0x01),
+        Instruction::Hash {
+            raw_hash: [0xBB; 32],
+        },
+        Instruction::Branch { mask: 0b101 },
+        fixtures::account_leaf(&[0xC; 63], Some(1), false, true),
+        Instruction::Hash {
+            raw_hash: [0xDD; 32],
+        },
+        Instruction::Branch { mask: 0b11 },
+    ];
+    let trie = ParsedWitness::new(instructions).unwrap().annotate();
+
+    let account_path = iter::once(0).chain([0xC; 63]).collect::<Vec<_>>();
+    assert_eq!(
+        trie.state,
+        [
+            (
+                account_path.clone(),
+                AnnotatedNode::Account {
+                    nonce: ethereum_types::U256::one(),
+                    balance: ethereum_types::U256::zero(),
+                    // the storage root is that of the branch below
+                    rlp: Some(
+                        hex_literal::hex!(
+                            "f8440180"
+                            "a0a21a8c680fb4526fea84a39b2020c95587e120af5ab383f856be748bddb015b3"
+                            "a0c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+                        )
+                        .to_vec()
+                    ),
+                }
+            ),
+            (vec![1], AnnotatedNode::Hash(H256([0xDD; 32]))),
+        ]
+    );
+    // each storage leaf sits at the key it was given, below its branch
+    assert_eq!(
+        trie.storage,
+        BTreeMap::from([(
+            account_path,
+            vec![
+                (
+                    iter::once(0).chain([0xA; 63]).collect(),
+                    AnnotatedNode::Leaf(vec![0x01])
+                ),
+                (vec![2], AnnotatedNode::Hash(H256([0xBB; 32]))),
+            ]
+        )])
+    );
+}
+
+#[test]
+fn test_account_to_rlp() {
+    // an externally owned account with a nonce of 1 and a balance of 1 ether,
+    // with the empty storage root and code hash
+    let account = Account {
+        nonce: ethereum_types::U256::one(),
+        balance: ethereum_types::U256::exp10(18),
+        storage: None,
+        code: None,
+    };
+    assert_eq!(
+        account.to_rlp().unwrap(),
+        hex_literal::hex!(
+            "f84c01880de0b6b3a7640000"
+            "a056e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421"
+            "a0c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+        )
+    );
+
+    // a contract whose storage is a hash stub, with inline code
+    let account = Account {
+        nonce: ethereum_types::U256::one(),
+        balance: ethereum_types::U256::zero(),
+        storage: Some(Box::new(Node::Hash(Hash {
+            raw_hash: [0xAA; 32],
+        }))),
+        code: Some(Either::Right(Code {
+            code: Arc::new(nunny::vec![0x60, 0x00]),
+        })),
+    };
+    assert_eq!(
+        account.to_rlp().unwrap(),
+        hex_literal::hex!(
+            "f8440180"
+            "a0aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"
+            "a007ad118d6cc8642c86c03827f276d8b791a65e5c99a3845faf186be720a1455d"
+        )
+    );
+
+    // accounts of mainnet block 19807080, see `tests/cases/b19807080_main.json`
+    let account = Account {
+        nonce: 335.into(),
+        balance: 104_829_055_498_284_640_763_u128.into(),
+        storage: None,
+        code: None,
+    };
+    assert_eq!(
+        account.to_rlp().unwrap(),
+        hex_literal::hex!(
+            "f84f82014f8905aecb9e914af729fb"
+            "a056e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421"
+            "a0c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+        )
+    );
+    let account = Account {
+        nonce: ethereum_types::U256::one(),
+        balance: ethereum_types::U256::zero(),
+        storage: Some(Box::new(Node::Hash(Hash {
+            raw_hash: hex_literal::hex!(
+                "6f1babd1aad365df43a426574d9f1bf6433ed3d5841aa3da7d1b7adc99d2e1fb"
+            ),
+        }))),
+        code: Some(Either::Left(Hash {
+            raw_hash: hex_literal::hex!(
+                "ff20557d6be1c48bc106100d951206a4a961c50b1af816568c92944301459ab8"
+            ),
+        })),
+    };
+    assert_eq!(
+        account.to_rlp().unwrap(),
+        hex_literal::hex!(
+            "f8440180"
+            "a06f1babd1aad365df43a426574d9f1bf6433ed3d5841aa3da7d1b7adc99d2e1fb"
+            "a0ff20557d6be1c48bc106100d951206a4a961c50b1af816568c92944301459ab8"
+        )
+    );
+
+    // storage which isn't a trie
+    let account = Account {
+        storage: Some(Box::new(Node::Code(Code {
+            code: Arc::new(nunny::vec![0x00]),
+        }))),
+        ..account
+    };
+    assert_eq!(account.to_rlp(), None);
+}
+
+#[test]
+fn test_batch_shared_code() {
+    let witness = |nonce| {
+        ParsedWitness::new([
+            Instruction::Code {
+                raw_code: nunny::vec![0x60, 0x00],
+            },
+            fixtures::account_leaf(&[0; 64], Some(nonce), true, false),
+        ])
+        .unwrap()
+    };
+
+    let mut parser = BatchWitnessParser::new();
+    let first = parser.share(witness(1));
+    let second = parser.share(witness(2));
+    assert_eq!(parser.num_codes(), 1);
+    // held by the parser, and by each witness
+    let shared = parser.code.first().unwrap();
+    assert_eq!(Arc::strong_count(shared), 3);
+    drop((first, second));
+}
+
+#[test]
+fn test_resolver() {
+    let account = |nonce| fixtures::account_leaf(&[0; 63], Some(nonce), false, false);
+    let hash = node_hash(&Node::from(execute_single([account(1)]).unwrap())).unwrap();
+    let instructions = || {
+        [
+            Instruction::Hash { raw_hash: hash.0 },
+            Instruction::Hash {
+                raw_hash: [0xBB; 32],
+            },
+            Instruction::Branch { mask: 0b11 },
+        ]
+    };
+
+    // only the first stub is known to the resolver
+    let frontend =
+        frontend_with_resolver(instructions(), &|it| (it == hash).then(|| vec![account(1)]))
+            .unwrap();
+    assert_eq!(
+        frontend
+            .state
+            .iter()
+            .map(|(haddr, acct)| (haddr, acct.nonce))
+            .collect::<Vec<_>>(),
+        [(H256::zero(), ethereum_types::U256::one())]
+    );
+
+    // ...which is the same as inlining it, and keeping the second stub
+    let inlined = frontend([
+        account(1),
+        Instruction::Hash {
+            raw_hash: [0xBB; 32],
+        },
+        Instruction::Branch { mask: 0b11 },
+    ])
+    .unwrap();
+    assert_eq!(frontend.state.root(), inlined.state.root());
+
+    // a tampered node doesn't hash to the stub it would replace
+    let e = frontend_with_resolver(instructions(), &|it| (it == hash).then(|| vec![account(2)]))
+        .unwrap_err();
+    assert!(e.to_string().contains("hashes to"), "{e}");
+}
+
+#[test]
+fn test_resolved_hash_mismatch() {
+    let account = |nonce| fixtures::account_leaf(&[0; 63], Some(nonce), false, false);
+    let hash = node_hash(&Node::from(execute_single([account(1)]).unwrap())).unwrap();
+    // the sibling of the stub is a subtrie of its own
+    let witness = [
+        fixtures::account_leaf(&[0; 63], Some(3), false, false),
+        Instruction::Hash { raw_hash: hash.0 },
+        Instruction::Branch { mask: 0b11 },
+    ];
+
+    // the node store's subtrie disagrees with the stub, which is reported
+    // where the stub is, rather than as a wrong root
+    let e =
+        frontend_with_resolver(witness, &|it| (it == hash).then(|| vec![account(2)])).unwrap_err();
+    assert!(
+        e.to_string().contains(&format!(
+            "subtrie at path {:?} hashes to",
+            [U4::new(1).unwrap()]
+        )),
+        "{e}"
+    );
+
+    // the same goes for stubs of storage tries, whose paths start afresh
+    let slot = fixtures::leaf(&[0; 64], 1);
+    let storage_root = node_hash(&Node::from(execute_single([slot.clone()]).unwrap())).unwrap();
+    let witness = [Instruction::AccountLeaf {
+        key: fixtures::key(&[0; 64]),
+        nonce: None,
+        balance: None,
+        has_code: false,
+        has_storage: true,
+        storage_root: Some(storage_root.0),
+        incarnation: None,
+    }];
+    let e = frontend_with_resolver(witness.clone(), &|it| {
+        (it == storage_root).then(|| vec![fixtures::leaf(&[0; 64], 2)])
+    })
+    .unwrap_err();
+    assert!(
+        format!("{e:#}").contains("subtrie at path [] hashes to"),
+        "{e:#}"
+    );
+    let frontend = frontend_with_resolver(witness, &|it| {
+        (it == storage_root).then(|| vec![slot.clone()])
+    })
+    .unwrap();
+    assert_eq!(
+        frontend.storage.values().next().unwrap().root(),
+        storage_root
+    );
+}
+
+#[test]
+fn test_resolver_cycle() {
+    let hash = |byte| Instruction::Hash {
+        raw_hash: [byte; 32],
+    };
+    let extension = || Instruction::Extension {
+        key: fixtures::key(&[1]),
+    };
+
+    // the stub resolves to an extension, whose child is the stub itself, which
+    // can't hash to the stub
+    let e = frontend_with_resolver([hash(0xAA), extension()], &|_| {
+        Some(vec![hash(0xAA), extension()])
+    })
+    .unwrap_err();
+    assert!(e.to_string().contains("not to the stub"), "{e}");
+
+    // the same goes for a cycle through another stub, which is rejected before
+    // it is expanded rather than looping
+    let e = frontend_with_resolver([hash(0xAA), extension()], &|it| {
+        Some(vec![
+            hash(if it == H256([0xAA; 32]) { 0xBB } else { 0xAA }),
+            extension(),
+        ])
+    })
+    .unwrap_err();
+    assert!(e.to_string().contains("not to the stub"), "{e}");
+}
+
+#[test]
+fn test_recompute_root() {
+    for case in
+        serde_json::from_str::<Vec<super::Case>>(include_str!("cases/zero_jerigon.json")).unwrap()
+    {
+        let instructions = crate::wire::parse(&case.bytes).unwrap();
+        let frontend = frontend(instructions).unwrap();
+        assert_eq!(
+            mpt_trie::utils::recompute_root(frontend.state.as_hashed_partial_trie()),
+            frontend.state.root()
+        );
+        for storage in frontend.storage.values() {
+            assert_eq!(
+                mpt_trie::utils::recompute_root(storage.as_hashed_partial_trie()),
+                storage.root()
+            );
+        }
+    }
+}
+
+/// Parses `witness` and checks that its state trie has the same root as a
+/// fresh [`HashedPartialTrie`](mpt_trie::partial_trie::HashedPartialTrie)
+/// with the `(key, value)` pairs of `expected_leaves`, to cross-check this
+/// parser with the reference trie implementation.
+#[cfg(test)]
+fn assert_parse_matches_reference(witness: &[u8], expected_leaves: &[(Vec<u8>, Vec<u8>)]) {
+    use mpt_trie::{
+        nibbles::Nibbles,
+        partial_trie::{HashedPartialTrie, PartialTrie as _},
+    };
+
+    let frontend = frontend(crate::wire::parse(witness).unwrap()).unwrap();
+    let mut reference = HashedPartialTrie::default();
+    for (key, value) in expected_leaves {
+        reference
+            .insert(Nibbles::from_bytes_be(key).unwrap(), value.clone())
+            .unwrap();
+    }
+    assert_eq!(frontend.state.root(), reference.hash());
+}
+
+#[test]
+fn test_truncate_witness() {
+    let src = fixtures::witness(&[
+        fixtures::funded_account_leaf(&[0xA; 63], 1, &[0x01, 0x00]),
+        fixtures::funded_account_leaf(&[0xB; 63], 2, &[0x02]),
+        Instruction::Branch { mask: 0b110 },
+    ]);
+
+    let truncated = truncate_witness(&src, 1).unwrap();
+    let Node::Branch(Branch { children }) = ParsedWitness::parse(&truncated).unwrap().root else {
+        panic!("expected the root branch to be kept")
+    };
+    let children = children.iter().flatten().collect::<Vec<_>>();
+    assert_eq!(children.len(), 2);
+    assert!(children
+        .iter()
+        .all(|child| matches!(***child, Node::Hash(_))));
+
+    let root = |src: &[u8]| {
+        frontend(crate::wire::parse(src).unwrap())
+            .unwrap()
+            .state
+            .root()
+    };
+    assert_eq!(root(&truncated), root(&src));
+    // Nothing is deep enough to be truncated.
+    assert_eq!(truncate_witness(&src, 2).unwrap(), src);
+}
+
+#[test]
+fn test_parse_matches_reference() {
+    use ethereum_types::U256;
+
+    let account = |nonce: u8, balance: &[u8]| {
+        rlp::encode(&AccountRlp {
+            nonce: nonce.into(),
+            balance: U256::from_big_endian(balance),
+            ..Default::default()
+        })
+        .to_vec()
+    };
+    // a single account leaf at the root
+    let src = fixtures::witness(&[fixtures::funded_account_leaf(&[0xE; 64], 1, &[0x10])]);
+    assert_parse_matches_reference(&src, &[(vec![0xEE; 32], account(1, &[0x10]))]);
+
+    // two accounts under the root branch
+    let src = fixtures::witness(&[
+        fixtures::funded_account_leaf(&[0xA; 63], 1, &[0x01, 0x00]),
+        fixtures::funded_account_leaf(&[0xB; 63], 2, &[0x02]),
+        Instruction::Branch { mask: 0b110 },
+    ]);
+    assert_parse_matches_reference(
+        &src,
+        &[
+            (
+                [&[0x1A][..], &[0xAA; 31]].concat(),
+                account(1, &[0x01, 0x00]),
+            ),
+            ([&[0x2B][..], &[0xBB; 31]].concat(), account(2, &[0x02])),
+        ],
+    );
+
+    // two accounts under a branch, behind an extension
+    let src = fixtures::witness(&[
+        fixtures::funded_account_leaf(&[0xC; 61], 3, &[0x03]),
+        fixtures::funded_account_leaf(&[0xD; 61], 4, &[0x04]),
+        Instruction::Branch {
+            mask: (1 << 3) | (1 << 4),
+        },
+        Instruction::Extension {
+            key: fixtures::key(&[0x1, 0x2]),
+        },
+    ]);
+    assert_parse_matches_reference(
+        &src,
+        &[
+            (
+                [&[0x12, 0x3C][..], &[0xCC; 30]].concat(),
+                account(3, &[0x03]),
+            ),
+            (
+                [&[0x12, 0x4D][..], &[0xDD; 30]].concat(),
+                account(4, &[0x04]),
+            ),
+        ],
+    );
+}
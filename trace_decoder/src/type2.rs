@@ -70,6 +70,7 @@ fn fold(
                 code.insert(raw_code);
                 None
             }
+            Instruction::EmptyCode => None,
             other => Some(other),
         });
     let folded = fold1(&mut instructions)?.context("no instructions to fold")?;
@@ -431,6 +431,13 @@ impl StorageTrie {
     pub fn get(&mut self, key: &TrieKey) -> Option<&[u8]> {
         self.untyped.get(key.into_nibbles())
     }
+    /// RLP-decode the value at `key` as a scalar.
+    ///
+    /// Returns [`None`] if there is no value, or if it is not a scalar
+    /// (e.g an RLP list).
+    pub fn get_u256(&mut self, key: &TrieKey) -> Option<U256> {
+        rlp::decode(self.get(key)?).ok()
+    }
     pub fn insert(&mut self, key: TrieKey, value: Vec<u8>) -> anyhow::Result<Option<Vec<u8>>> {
         let prev = self.get(&key).map(Vec::from);
         self.untyped.insert(key.into_nibbles(), value)?;
@@ -462,6 +469,22 @@ impl StorageTrie {
     }
 }
 
+#[test]
+fn storage_get_u256() {
+    let mut storage = StorageTrie::new(OnOrphanedHashNode::Reject);
+    let slot = TrieKey::from_slot_position(U256::from(1));
+    let other = TrieKey::from_slot_position(U256::from(2));
+    assert_eq!(storage.get_u256(&slot), None);
+    storage
+        .insert(slot, rlp::encode(&U256::from(0xdead_beef_u64)).to_vec())
+        .unwrap();
+    assert_eq!(storage.get_u256(&slot), Some(U256::from(0xdead_beef_u64)));
+    storage
+        .insert(other, rlp::encode(&AccountRlp::default()).to_vec())
+        .unwrap();
+    assert_eq!(storage.get_u256(&other), None);
+}
+
 impl From<StorageTrie> for HashedPartialTrie {
     fn from(value: StorageTrie) -> Self {
         value.untyped
@@ -8,34 +8,274 @@
 //!
 //! This is fine because we don't care about failing fast when parsing.
 
-use std::{any::type_name, iter};
+use std::{any::type_name, fmt, iter};
 
-use anyhow::bail;
+use anyhow::{bail, ensure, Context as _};
 use either::Either;
 use ethereum_types::U256;
 use nunny::NonEmpty;
 use serde::de::DeserializeOwned;
 use u4::{U4x2, U4};
 use winnow::{
-    combinator::{empty, eof, fail, preceded, repeat_till, trace},
-    error::{ErrorKind, FromExternalError, StrContext},
-    stream::Stream,
-    token::{any, one_of, take},
-    Parser as _,
+    combinator::{alt, cut_err, empty, eof, fail, peek, preceded, repeat_till, terminated, trace},
+    error::{ContextError, ErrMode, ErrorKind, FromExternalError, Needed, StrContext},
+    stream::{Offset as _, Stream, StreamIsPartial as _},
+    token::{any, rest, take},
+    Parser, Partial,
 };
 
 pub fn parse(input: &[u8]) -> anyhow::Result<NonEmpty<Vec<Instruction>>> {
-    match preceded(
-        one_of((0u8, 1u8)), // header
-        repeat_till(1.., instruction, eof).map(|(it, _)| {
-            NonEmpty::<Vec<_>>::new(it).expect("repeat_till should ensure non-empty collection")
-        }),
+    parse_complete(
+        input,
+        preceded(
+            header,
+            repeat_till(1.., instruction, eof).map(|(it, _)| {
+                NonEmpty::<Vec<_>>::new(it).expect("repeat_till should ensure non-empty collection")
+            }),
+        ),
     )
-    .parse(input)
-    {
+}
+
+/// Like [`Parser::parse`], which doesn't support [`Partial`] input, for input
+/// which is known to be complete.
+fn parse_complete<'a, O>(
+    input: &'a [u8],
+    parser: impl Parser<Input<'a>, O, ContextError>,
+) -> anyhow::Result<O> {
+    let mut input = complete(input);
+    let start = input.checkpoint();
+    match terminated(parser, eof).parse_next(&mut input) {
+        Ok(it) => Ok(it),
+        Err(e) => bail!(
+            "parse error at offset {}: {}",
+            input.offset_from(&start),
+            e.into_inner()
+                .expect("complete input shouldn't be reported as incomplete")
+        ),
+    }
+}
+
+/// Wrap `input`, which holds the whole witness.
+fn complete(input: &[u8]) -> Input<'_> {
+    let mut input = Partial::new(input);
+    let _ = input.complete();
+    input
+}
+
+/// Read the version from the header of a witness, e.g. to record which
+/// producer a witness came from, without parsing its instructions.
+pub fn read_version(input: &[u8]) -> anyhow::Result<Version> {
+    match header(&mut complete(input)) {
+        Ok(it) => Ok(it),
+        Err(e) => bail!("couldn't read witness version: {}", e),
+    }
+}
+
+/// Parse the header and at most the first `n` instructions of a witness, e.g.
+/// to route witnesses by version or first opcode without parsing all of them.
+///
+/// The rest of the input isn't looked at, so it may well be malformed.
+pub fn peek_witness(input: &[u8], n: usize) -> anyhow::Result<(Version, Vec<Instruction>)> {
+    let mut rest = complete(input);
+    let version = match header(&mut rest) {
+        Ok(it) => it,
+        Err(e) => bail!("couldn't read witness version: {}", e),
+    };
+    let mut instructions = vec![];
+    while instructions.len() < n && !rest.is_empty() {
+        let offset = input.len() - rest.len();
+        match instruction(&mut rest) {
+            Ok(it) => instructions.push(it),
+            Err(e) => bail!("parse error at offset {}: {}", offset, e),
+        }
+    }
+    Ok((version, instructions))
+}
+
+/// The magic number which starts every zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+/// The most bytes [`parse_compressed`] decompresses a witness to, so that a
+/// small malicious input can't exhaust memory.
+pub const MAX_DECOMPRESSED: u64 = 1 << 30;
+
+/// Like [`parse`], but `input` may be zstd-compressed, in which case it is
+/// decompressed first, up to [`MAX_DECOMPRESSED`] bytes.
+/// Input which doesn't start with the zstd magic number is parsed as-is.
+pub fn parse_compressed(input: &[u8]) -> anyhow::Result<NonEmpty<Vec<Instruction>>> {
+    parse_compressed_with_limit(input, MAX_DECOMPRESSED)
+}
+
+fn parse_compressed_with_limit(
+    input: &[u8],
+    limit: u64,
+) -> anyhow::Result<NonEmpty<Vec<Instruction>>> {
+    if !input.starts_with(&ZSTD_MAGIC) {
+        return parse(input);
+    }
+    let mut decompressed = vec![];
+    // read one more byte than allowed, to tell a witness of exactly `limit`
+    // bytes apart from a larger one
+    std::io::Read::read_to_end(
+        &mut std::io::Read::take(zstd::Decoder::new(input)?, limit + 1),
+        &mut decompressed,
+    )
+    .context("couldn't decompress zstd witness")?;
+    ensure!(
+        decompressed.len() as u64 <= limit,
+        "zstd witness decompresses to more than {limit} bytes"
+    );
+    parse(&decompressed)
+}
+
+/// Signals that the remaining bytes of a witness are a footer, e.g. a block
+/// hash or a checksum, rather than instructions.
+const FOOTER: u8 = 0xFE;
+
+/// Like [`parse`], but the instructions may be followed by a footer opcode
+/// (`0xFE`), whose remaining bytes are returned verbatim.
+pub fn parse_with_footer(
+    input: &[u8],
+) -> anyhow::Result<(NonEmpty<Vec<Instruction>>, Option<Vec<u8>>)> {
+    parse_complete(
+        input,
+        preceded(
+            header,
+            repeat_till(1.., instruction, alt((footer.map(Some), eof.value(None)))).map(
+                |(it, footer)| {
+                    (
+                        NonEmpty::<Vec<_>>::new(it)
+                            .expect("repeat_till should ensure non-empty collection"),
+                        footer,
+                    )
+                },
+            ),
+        ),
+    )
+}
+
+fn footer(input: &mut Input<'_>) -> PResult<Vec<u8>> {
+    trace("footer", preceded(FOOTER, rest.map(<[u8]>::to_vec))).parse_next(input)
+}
+
+/// Parse a witness which was delivered as several chunks, e.g. over JSON-RPC.
+///
+/// Instructions may straddle chunk boundaries, so each chunk is parsed along
+/// with the bytes the previous ones left over, and only those are kept: the
+/// chunks are never joined into one contiguous witness.
+/// Only instructions which are reported as [`ErrMode::Incomplete`] are retried
+/// with the next chunk, so that malformed input fails in the chunk it's in.
+pub fn parse_chunks(chunks: &[impl AsRef<[u8]>]) -> anyhow::Result<NonEmpty<Vec<Instruction>>> {
+    if let [chunk] = chunks {
+        return parse(chunk.as_ref());
+    }
+    let mut chunks = chunks.iter().map(AsRef::as_ref).peekable();
+    let mut leftover = vec![];
+    // how many bytes of the witness precede `leftover`
+    let mut offset = 0;
+    let mut has_header = false;
+    let mut instructions = vec![];
+    while let Some(chunk) = chunks.next() {
+        leftover.extend_from_slice(chunk);
+        let mut rest = Partial::new(&leftover[..]);
+        if chunks.peek().is_none() {
+            let _ = rest.complete();
+        }
+        while !has_header || !rest.is_empty() {
+            let position = offset + leftover.len() - rest.len();
+            let start = rest.checkpoint();
+            let parsed = match has_header {
+                true => instruction(&mut rest).map(Some),
+                false => header(&mut rest).map(|_| None),
+            };
+            match parsed {
+                Ok(it) => {
+                    has_header = true;
+                    instructions.extend(it);
+                }
+                // the rest of this instruction is in the next chunk
+                Err(ErrMode::Incomplete(_)) => {
+                    rest.reset(&start);
+                    break;
+                }
+                Err(e) => bail!("parse error at offset {}: {}", position, e),
+            }
+        }
+        let parsed = leftover.len() - rest.len();
+        leftover.drain(..parsed);
+        offset += parsed;
+    }
+    match NonEmpty::<Vec<_>>::new(instructions) {
         Ok(it) => Ok(it),
-        Err(e) => bail!("parse error at offset {}: {}", e.offset(), e.inner()),
+        Err(_) => bail!("witness contains no instructions"),
+    }
+}
+
+/// Parse a witness, skipping over opcodes this parser doesn't recognise, and
+/// returning them alongside the instructions.
+///
+/// This is meant for forward compatibility with witness producers which add
+/// new opcodes.
+/// Unknown opcodes must be followed by a single CBOR byte string, whose
+/// length prefix tells us how much of the input to skip.
+/// Known opcodes with malformed operands are still errors.
+pub fn parse_lenient(input: &[u8]) -> anyhow::Result<(NonEmpty<Vec<Instruction>>, Vec<u8>)> {
+    let mut skipped = vec![];
+    let parsed = parse_complete(
+        input,
+        preceded(
+            header,
+            repeat_till(
+                1..,
+                alt((
+                    preceded(peek(any.verify(is_known_opcode)), cut_err(instruction)).map(Some),
+                    unknown_instruction.map(|opcode| {
+                        skipped.push(opcode);
+                        None
+                    }),
+                )),
+                eof,
+            )
+            .map(|(it, _): (Vec<_>, _)| it.into_iter().flatten().collect::<Vec<_>>()),
+        ),
+    )?;
+    match NonEmpty::<Vec<_>>::new(parsed) {
+        Ok(it) => Ok((it, skipped)),
+        Err(_) => bail!("witness contains no known instructions"),
+    }
+}
+
+/// Parse a witness, rendering each instruction on its own line for debugging.
+pub fn disassemble(input: &[u8]) -> anyhow::Result<Vec<String>> {
+    Ok(parse(input)?.iter().map(Instruction::to_string).collect())
+}
+
+/// Check that a witness is syntactically valid, without building any tries.
+///
+/// This is much cheaper than a full decode, but only catches malformed
+/// opcodes and operands.
+/// Instructions can't be delimited without decoding their operands, so only
+/// the first error is reported.
+///
+/// If `max_balance` is given, e.g. the total supply of the chain, accounts with
+/// a larger balance are rejected as a sign of corruption.
+pub fn lint_witness(input: &[u8], max_balance: Option<U256>) -> anyhow::Result<()> {
+    let instructions = parse(input)?;
+    if let Some(max_balance) = max_balance {
+        for instruction in instructions {
+            if let Instruction::AccountLeaf {
+                balance: Some(balance),
+                ..
+            } = instruction
+            {
+                ensure!(
+                    balance <= max_balance,
+                    "balance {balance} exceeds the maximum of {max_balance} in {instruction}"
+                );
+            }
+        }
     }
+    Ok(())
 }
 
 /// Names are taken from the spec.
@@ -65,13 +305,29 @@ pub enum Instruction {
     Code {
         raw_code: NonEmpty<Vec<u8>>,
     },
+    /// A code node with no bytes, which some producers emit for accounts
+    /// without code.
+    EmptyCode,
+    /// Like [`Instruction::Code`], but also carries the precomputed hash of
+    /// the code, which is checked at the parse site [`code_with_hash`].
+    CodeWithHash {
+        raw_code: NonEmpty<Vec<u8>>,
+        raw_hash: [u8; 32],
+    },
     AccountLeaf {
         key: NonEmpty<Vec<U4>>,
-        nonce: Option<u64>,
+        /// See parse site [`account_leaf`].
+        nonce: Option<U256>,
         /// BUG(spec): see parse site [`account_leaf`].
         balance: Option<U256>,
         has_code: bool,
         has_storage: bool,
+        /// If present, the storage root is embedded in the account leaf
+        /// itself, rather than following as a separate node.
+        storage_root: Option<[u8; 32]>,
+        /// Erigon's incarnation of the account, which is bumped whenever
+        /// the account is re-created, e.g. after a self-destruct.
+        incarnation: Option<u64>,
     },
     SmtLeaf(SmtLeaf),
     /// BUG(spec): see parse site [`instruction`].
@@ -79,6 +335,83 @@ pub enum Instruction {
     NewTrie,
 }
 
+/// A human-readable rendering for debugging witnesses, see [`disassemble`].
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        struct Key<'a>(&'a [U4]);
+        impl fmt::Display for Key<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("0x")?;
+                for u in self.0 {
+                    write!(f, "{:x}", u)?
+                }
+                Ok(())
+            }
+        }
+
+        match self {
+            Instruction::Leaf { key, value } => {
+                write!(f, "Leaf(key={}, value_len={})", Key(key), value.len())
+            }
+            Instruction::Extension { key } => write!(f, "Extension(key={})", Key(key)),
+            Instruction::Branch { mask } => write!(f, "Branch(mask={:#06x})", mask),
+            Instruction::Hash { raw_hash } => {
+                write!(f, "Hash(hash=0x{})", hex::encode(raw_hash))
+            }
+            Instruction::Code { raw_code } => write!(f, "Code(len={})", raw_code.len()),
+            Instruction::EmptyCode => f.write_str("EmptyCode"),
+            Instruction::CodeWithHash { raw_code, raw_hash } => write!(
+                f,
+                "CodeWithHash(len={}, hash=0x{})",
+                raw_code.len(),
+                hex::encode(raw_hash)
+            ),
+            Instruction::AccountLeaf {
+                key,
+                nonce,
+                balance,
+                has_code,
+                has_storage,
+                storage_root,
+                incarnation,
+            } => {
+                write!(
+                    f,
+                    "AccountLeaf(key={}, nonce={}, balance={}, code={}, storage={}",
+                    Key(key),
+                    nonce.unwrap_or_default(),
+                    balance.unwrap_or_default(),
+                    has_code,
+                    has_storage
+                )?;
+                if let Some(storage_root) = storage_root {
+                    write!(f, ", storage_root=0x{}", hex::encode(storage_root))?
+                }
+                if let Some(incarnation) = incarnation {
+                    write!(f, ", incarnation={}", incarnation)?
+                }
+                f.write_str(")")
+            }
+            Instruction::SmtLeaf(SmtLeaf {
+                node_type,
+                address,
+                value,
+            }) => {
+                let address: &[u8] = address;
+                write!(
+                    f,
+                    "SmtLeaf(type={:?}, address=0x{}, value_len={})",
+                    node_type,
+                    hex::encode(address),
+                    value.len()
+                )
+            }
+            Instruction::EmptyRoot => f.write_str("EmptyRoot"),
+            Instruction::NewTrie => f.write_str("NewTrie"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct SmtLeaf {
     pub node_type: SmtLeafType,
@@ -95,10 +428,95 @@ pub enum SmtLeafType {
     CodeLength,
 }
 
+/// The version of a witness, as read from its header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Version {
+    major: u8,
+    minor: u8,
+}
+
+impl Version {
+    /// Bumped on breaking changes to the format.
+    pub const fn major(self) -> u8 {
+        self.major
+    }
+    /// Always `0` for the versions we support.
+    pub const fn minor(self) -> u8 {
+        self.minor
+    }
+    /// No minor versions have been released yet, so we don't know what
+    /// they'll break.
+    fn is_compatible(self) -> bool {
+        matches!(self.major, 0x00 | 0x01) && self.minor == 0
+    }
+}
+
+/// Signals a two-byte `major.minor` version, rather than a bare major version.
+const EXTENDED_VERSION: u8 = 0xFF;
+
+/// Optionally precedes the version, so that bytes which aren't a witness at
+/// all, e.g. JSON or a gzip stream, can be told apart from a malformed one.
+///
+/// Legacy witnesses don't have it. Its first byte is neither a version nor an
+/// opcode, so it is unambiguous.
+const MAGIC: [u8; 4] = *b"ZKWT";
+
+fn header(input: &mut Input<'_>) -> PResult<Version> {
+    if input.first() == Some(&MAGIC[0]) {
+        let start = input.checkpoint();
+        match array(input) {
+            Ok(MAGIC) => {}
+            Err(ErrMode::Incomplete(needed)) => return Err(ErrMode::Incomplete(needed)),
+            _ => {
+                input.reset(&start);
+                return fail
+                    .context(StrContext::Label("header, bad magic bytes"))
+                    .parse_next(input);
+            }
+        }
+    }
+    let start = input.checkpoint();
+    match any(input)? {
+        EXTENDED_VERSION => {
+            let [major, minor] = array(input)?;
+            let version = Version { major, minor };
+            match version.is_compatible() {
+                true => Ok(version),
+                false => {
+                    input.reset(&start);
+                    fail.context(StrContext::Label("header, unsupported version"))
+                        .parse_next(input)
+                }
+            }
+        }
+        major @ (0x00 | 0x01) => Ok(Version { major, minor: 0 }),
+        // these can't be versions, so the caller probably passed a bare
+        // instruction stream
+        0x02..=0x08 | 0xBB => {
+            input.reset(&start);
+            fail.context(StrContext::Label(
+                "header, found an opcode instead - is the header missing?",
+            ))
+            .parse_next(input)
+        }
+        _ => {
+            input.reset(&start);
+            fail.context(StrContext::Label(
+                "header, unrecognised version - is this a witness?",
+            ))
+            .parse_next(input)
+        }
+    }
+}
+
 /// A single place to swap out the error type if required.
-type PResult<T> = winnow::PResult<T, winnow::error::ContextError>;
+type PResult<T> = winnow::PResult<T, ContextError>;
 
-fn instruction(input: &mut &[u8]) -> PResult<Instruction> {
+/// The input of the parsers in this module, which is only partial in
+/// [`parse_chunks`], where an instruction may continue in the next chunk.
+type Input<'a> = Partial<&'a [u8]>;
+
+fn instruction(input: &mut Input<'_>) -> PResult<Instruction> {
     let start = input.checkpoint();
     let opcode = any(input)?;
     // this is [`winnow::combinator::dispatch`] without the macro magic
@@ -113,14 +531,20 @@ fn instruction(input: &mut &[u8]) -> PResult<Instruction> {
         0x03 => {
             trace("hash", array.map(|raw_hash| Instruction::Hash { raw_hash })).parse_next(input)
         }
-        0x04 => {
-            trace("code", cbor.map(|raw_code| Instruction::Code { raw_code })).parse_next(input)
-        }
+        0x04 => trace(
+            "code",
+            cbor::<Vec<u8>>.map(|raw_code| match NonEmpty::<Vec<_>>::new(raw_code) {
+                Ok(raw_code) => Instruction::Code { raw_code },
+                Err(_) => Instruction::EmptyCode,
+            }),
+        )
+        .parse_next(input),
         0x05 => trace("account_leaf", account_leaf).parse_next(input),
         // BUG(spec): this opcode is undocumented, but the previous version of
         //            this code had it, and our tests fail without it.
         0x06 => trace("empty_root", empty.value(Instruction::EmptyRoot)).parse_next(input),
         0x07 => trace("smt_leaf", smt_leaf).parse_next(input),
+        0x08 => trace("code_with_hash", code_with_hash).parse_next(input),
         0xBB => trace("new_trie", empty.value(Instruction::NewTrie)).parse_next(input),
         _ => {
             input.reset(&start);
@@ -130,31 +554,67 @@ fn instruction(input: &mut &[u8]) -> PResult<Instruction> {
     }
 }
 
+/// Whether [`instruction`] can parse this opcode.
+fn is_known_opcode(opcode: &u8) -> bool {
+    matches!(opcode, 0x00..=0x08 | 0xBB)
+}
+
+/// Skip an unknown opcode and its byte string operand.
+fn unknown_instruction(input: &mut Input<'_>) -> PResult<u8> {
+    let start = input.checkpoint();
+    match any(input)? {
+        opcode if is_known_opcode(&opcode) => {
+            input.reset(&start);
+            fail.context(StrContext::Label("unknown opcode"))
+                .parse_next(input)
+        }
+        opcode => trace("unknown", cbor::<Vec<u8>>.value(opcode)).parse_next(input),
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 #[error("{}", .0)]
 struct Error(&'static str);
 
-fn account_leaf(input: &mut &[u8]) -> PResult<Instruction> {
+fn account_leaf(input: &mut Input<'_>) -> PResult<Instruction> {
     bitflags::bitflags! {
         struct AccountLeafFlags: u8 {
             const HAS_CODE = 0b0000_0001;
             const HAS_STORAGE = 0b0000_0010;
             const ENCODES_NONCE = 0b0000_0100;
             const ENCODES_BALANCE = 0b0000_1000;
+            const ENCODES_STORAGE_ROOT = 0b0001_0000;
+            const ENCODES_INCARNATION = 0b0010_0000;
         }
     }
     let key = key(input)?;
+    // `has_code` and `has_storage` are bits of this raw byte rather than CBOR
+    // values, so they can't be encoded as either booleans or integers.
     let flags = any
         .try_map(|byte| {
             AccountLeafFlags::from_bits(byte)
                 .ok_or(Error("unrecognised bits in flags for account leaf"))
         })
         .parse_next(input)?;
+    if flags.contains(AccountLeafFlags::ENCODES_STORAGE_ROOT)
+        && !flags.contains(AccountLeafFlags::HAS_STORAGE)
+    {
+        return fail
+            .context(StrContext::Label(
+                "inline storage root for account leaf without storage",
+            ))
+            .parse_next(input);
+    }
 
     Ok(Instruction::AccountLeaf {
         key,
         nonce: match flags.contains(AccountLeafFlags::ENCODES_NONCE) {
-            true => Some(trace("nonce", cbor).parse_next(input)?),
+            // Nonces which don't fit in a CBOR integer may be encoded as
+            // bignums instead.
+            true => Some(
+                trace("nonce", alt((cbor::<u64>.map(U256::from), cbor_bignum)))
+                    .parse_next(input)?,
+            ),
             false => None,
         },
         balance: match flags.contains(AccountLeafFlags::ENCODES_BALANCE) {
@@ -181,10 +641,29 @@ fn account_leaf(input: &mut &[u8]) -> PResult<Instruction> {
             }
             has_code
         },
+        storage_root: match flags.contains(AccountLeafFlags::ENCODES_STORAGE_ROOT) {
+            true => Some(trace("storage_root", array).parse_next(input)?),
+            false => None,
+        },
+        incarnation: match flags.contains(AccountLeafFlags::ENCODES_INCARNATION) {
+            true => Some(trace("incarnation", cbor).parse_next(input)?),
+            false => None,
+        },
     })
 }
 
-fn smt_leaf(input: &mut &[u8]) -> PResult<Instruction> {
+fn code_with_hash(input: &mut Input<'_>) -> PResult<Instruction> {
+    (cbor, array)
+        .try_map(|(raw_code, raw_hash): (NonEmpty<Vec<u8>>, [u8; 32])| {
+            match keccak_hash::keccak(&raw_code).0 == raw_hash {
+                true => Ok(Instruction::CodeWithHash { raw_code, raw_hash }),
+                false => Err(Error("code hash mismatch")),
+            }
+        })
+        .parse_next(input)
+}
+
+fn smt_leaf(input: &mut Input<'_>) -> PResult<Instruction> {
     let start = input.checkpoint();
     let node_type = any(input)?;
     Ok(Instruction::SmtLeaf(SmtLeaf {
@@ -205,31 +684,74 @@ fn smt_leaf(input: &mut &[u8]) -> PResult<Instruction> {
     }))
 }
 
-fn key(input: &mut &[u8]) -> PResult<NonEmpty<Vec<U4>>> {
-    trace("key", cbor.try_map(|it: NonEmpty<Vec<u8>>| decode_key(&it))).parse_next(input)
+/// Starts a CBOR byte string which is split into several chunks, up to a
+/// [`CBOR_BREAK`].
+const CBOR_INDEFINITE_BYTES: u8 = 0x5F;
+const CBOR_BREAK: u8 = 0xFF;
+
+/// Long keys may be split into several chunks by some encoders, which are
+/// joined before decoding.
+fn key(input: &mut Input<'_>) -> PResult<NonEmpty<Vec<U4>>> {
+    trace(
+        "key",
+        alt((
+            cbor.try_map(|it: NonEmpty<Vec<u8>>| decode_key(&it)),
+            preceded(
+                CBOR_INDEFINITE_BYTES,
+                repeat_till(1.., cbor::<NonEmpty<Vec<u8>>>, CBOR_BREAK),
+            )
+            .try_map(|(chunks, _): (Vec<_>, _)| {
+                let joined = chunks
+                    .iter()
+                    .flat_map(|chunk| chunk.iter().copied())
+                    .collect();
+                decode_key(&NonEmpty::<Vec<_>>::new(joined).expect("chunks are non-empty"))
+            }),
+        )),
+    )
+    .parse_next(input)
 }
 
-fn cbor<T: DeserializeOwned + std::fmt::Debug>(input: &mut &[u8]) -> PResult<T> {
+fn cbor<T: DeserializeOwned + std::fmt::Debug>(input: &mut Input<'_>) -> PResult<T> {
     trace(
         format!("cbor{{{}}}", type_name::<T>()),
-        |input: &mut &[u8]| {
-            let start = input.checkpoint();
-            match ciborium::from_reader::<T, _>(&mut *input) {
-                Ok(it) => Ok(it),
-                Err(e) => {
-                    input.reset(&start);
-                    Err(FromExternalError::from_external_error(
-                        input,
-                        ErrorKind::Verify,
-                        e,
-                    ))
+        |input: &mut Input<'_>| {
+            let mut bytes: &[u8] = **input;
+            match ciborium::from_reader::<T, _>(&mut bytes) {
+                Ok(it) => {
+                    input.next_slice(input.len() - bytes.len());
+                    Ok(it)
+                }
+                Err(ciborium::de::Error::Io(e))
+                    if input.is_partial() && e.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    Err(ErrMode::Incomplete(Needed::Unknown))
                 }
+                Err(e) => Err(FromExternalError::from_external_error(
+                    input,
+                    ErrorKind::Verify,
+                    e,
+                )),
             }
         },
     )
     .parse_next(input)
 }
 
+/// An unsigned bignum, i.e. a big-endian byte string with CBOR tag 2[^1].
+///
+/// [^1]: <https://www.rfc-editor.org/rfc/rfc8949.html#name-bignums>
+fn cbor_bignum(input: &mut Input<'_>) -> PResult<U256> {
+    preceded(
+        0xC2,
+        cbor::<Vec<u8>>.try_map(|bytes| match bytes.len() <= 32 {
+            true => Ok(U256::from_big_endian(&bytes)),
+            false => Err(Error("bignum doesn't fit in 256 bits")),
+        }),
+    )
+    .parse_next(input)
+}
+
 fn decode_key(bytes: &NonEmpty<[u8]>) -> Result<NonEmpty<Vec<U4>>, Error> {
     bitflags::bitflags! {
         struct EncodeKeyFlags: u8 {
@@ -290,20 +812,172 @@ fn decode_key(bytes: &NonEmpty<[u8]>) -> Result<NonEmpty<Vec<U4>>, Error> {
     Ok(v)
 }
 
-fn array<const N: usize>(input: &mut &[u8]) -> PResult<[u8; N]> {
+fn array<const N: usize>(input: &mut Input<'_>) -> PResult<[u8; N]> {
     take(N)
         .map(|it: &[u8]| it.try_into().expect("take has already selected N bytes"))
         .parse_next(input)
 }
 
+/// Encode `instructions` as a witness with the given `version`, such that
+/// [`parse`] reads them back.
+///
+/// Fields which [`parse`] discards, like the code length of an
+/// [`Instruction::AccountLeaf`], are written as zero.
+pub(crate) fn encode(version: Version, instructions: &[Instruction]) -> Vec<u8> {
+    fn head(out: &mut Vec<u8>, major: u8, n: u64) {
+        let major = major << 5;
+        match n {
+            0..24 => out.push(major | n as u8),
+            24..0x100 => out.extend([major | 24, n as u8]),
+            0x100..0x1_0000 => {
+                out.push(major | 25);
+                out.extend((n as u16).to_be_bytes())
+            }
+            0x1_0000..0x1_0000_0000 => {
+                out.push(major | 26);
+                out.extend((n as u32).to_be_bytes())
+            }
+            _ => {
+                out.push(major | 27);
+                out.extend(n.to_be_bytes())
+            }
+        }
+    }
+    fn uint(out: &mut Vec<u8>, n: u64) {
+        head(out, 0, n)
+    }
+    fn bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+        head(out, 2, bytes.len() as u64);
+        out.extend(bytes)
+    }
+    fn big_endian(n: U256) -> Vec<u8> {
+        let mut be = [0; 32];
+        n.to_big_endian(&mut be);
+        be[(n.leading_zeros() / 8) as usize..].to_vec()
+    }
+    /// The inverse of [`decode_key`].
+    fn key(out: &mut Vec<u8>, key: &[U4]) {
+        let odd = key.len() % 2 == 1;
+        let mut packed = vec![u8::from(odd)];
+        packed.extend(key.chunks(2).map(|pair| match pair {
+            [left, right] => ((*left as u8) << 4) | *right as u8,
+            [left] => (*left as u8) << 4,
+            _ => unreachable!("chunks of two"),
+        }));
+        bytes(out, &packed)
+    }
+
+    let mut out = match version.minor {
+        0 => vec![version.major],
+        minor => vec![EXTENDED_VERSION, version.major, minor],
+    };
+    for instruction in instructions {
+        match instruction {
+            Instruction::Leaf { key: k, value } => {
+                out.push(0x00);
+                key(&mut out, k);
+                bytes(&mut out, value)
+            }
+            Instruction::Extension { key: k } => {
+                out.push(0x01);
+                key(&mut out, k)
+            }
+            Instruction::Branch { mask } => {
+                out.push(0x02);
+                uint(&mut out, *mask)
+            }
+            Instruction::Hash { raw_hash } => {
+                out.push(0x03);
+                out.extend(raw_hash)
+            }
+            Instruction::Code { raw_code } => {
+                out.push(0x04);
+                bytes(&mut out, raw_code)
+            }
+            Instruction::EmptyCode => {
+                out.push(0x04);
+                bytes(&mut out, &[])
+            }
+            Instruction::AccountLeaf {
+                key: k,
+                nonce,
+                balance,
+                has_code,
+                has_storage,
+                storage_root,
+                incarnation,
+            } => {
+                out.push(0x05);
+                key(&mut out, k);
+                let flags = u8::from(*has_code)
+                    | (u8::from(*has_storage) << 1)
+                    | (u8::from(nonce.is_some()) << 2)
+                    | (u8::from(balance.is_some()) << 3)
+                    | (u8::from(storage_root.is_some()) << 4)
+                    | (u8::from(incarnation.is_some()) << 5);
+                out.push(flags);
+                if let Some(nonce) = nonce {
+                    match nonce.bits() <= 64 {
+                        true => uint(&mut out, nonce.as_u64()),
+                        false => {
+                            out.push(0xC2);
+                            bytes(&mut out, &big_endian(*nonce))
+                        }
+                    }
+                }
+                if let Some(balance) = balance {
+                    bytes(&mut out, &big_endian(*balance))
+                }
+                if *has_code {
+                    // the code length
+                    uint(&mut out, 0)
+                }
+                if let Some(storage_root) = storage_root {
+                    out.extend(storage_root)
+                }
+                if let Some(incarnation) = incarnation {
+                    uint(&mut out, *incarnation)
+                }
+            }
+            Instruction::EmptyRoot => out.push(0x06),
+            Instruction::SmtLeaf(SmtLeaf {
+                node_type,
+                address,
+                value,
+            }) => {
+                out.push(0x07);
+                out.push(match node_type {
+                    SmtLeafType::Balance => 0,
+                    SmtLeafType::Nonce => 1,
+                    SmtLeafType::Code => 2,
+                    SmtLeafType::Storage(_) => 3,
+                    SmtLeafType::CodeLength => 4,
+                });
+                bytes(&mut out, address);
+                if let SmtLeafType::Storage(slot) = node_type {
+                    bytes(&mut out, slot)
+                }
+                bytes(&mut out, value)
+            }
+            Instruction::CodeWithHash { raw_code, raw_hash } => {
+                out.push(0x08);
+                bytes(&mut out, raw_code);
+                out.extend(raw_hash)
+            }
+            Instruction::NewTrie => out.push(0xBB),
+        }
+    }
+    out
+}
+
 #[cfg(test)]
 #[track_caller]
 fn do_test<'a, T: PartialEq + core::fmt::Debug>(
     src: &'a [u8],
     expected: T,
-    mut parser: impl winnow::Parser<&'a [u8], T, winnow::error::ContextError>,
+    parser: impl Parser<Input<'a>, T, ContextError>,
 ) {
-    let actual = parser.parse(src.as_ref()).unwrap();
+    let actual = parse_complete(src, parser).unwrap();
     assert_eq!(expected, actual)
 }
 
@@ -315,3 +989,445 @@ fn cbor_test_cases() {
     do_test(b"\x0a", 10, cbor);
     do_test(b"\x17", 23, cbor);
 }
+
+#[test]
+fn bignum_nonce() {
+    // 2^64, which doesn't fit in a CBOR integer
+    let mut src = b"\x81\x01\x04\xC2\x49\x01".to_vec();
+    src.extend([0x00; 8]);
+    do_test(
+        &src,
+        Instruction::AccountLeaf {
+            key: nunny::vec![U4::new(1).unwrap()],
+            nonce: Some(U256::one() << 64),
+            balance: None,
+            has_code: false,
+            has_storage: false,
+            storage_root: None,
+            incarnation: None,
+        },
+        account_leaf,
+    );
+
+    do_test(b"\xC2\x41\x05", U256::from(5), cbor_bignum);
+}
+
+#[test]
+fn header_versions() {
+    do_test(b"\x01", Version { major: 1, minor: 0 }, header);
+    do_test(b"\xFF\x01\x00", Version { major: 1, minor: 0 }, header);
+    assert!(parse_complete(b"\xFF\x01\x02", header).is_err());
+    assert!(parse_complete(b"\xFF\x02\x00", header).is_err());
+    assert!(parse_complete(b"\xFF\x01", header).is_err());
+
+    let version = read_version(b"\xFF\x01\x00\x03").unwrap();
+    assert_eq!((version.major(), version.minor()), (1, 0));
+    assert_eq!(read_version(b"\x00\x03").unwrap().major(), 0);
+    assert!(read_version(b"\x03").is_err());
+
+    let mut src = b"\xFF\x01\x00".to_vec();
+    src.push(0x03); // hash
+    src.extend([0xAB; 32]);
+    assert!(parse(&src).is_ok());
+}
+
+#[test]
+fn header_magic() {
+    let mut src = MAGIC.to_vec();
+    src.push(0x01); // version
+    src.push(0x03); // hash
+    src.extend([0xAB; 32]);
+    assert!(parse(&src).is_ok());
+    assert_eq!(read_version(&src).unwrap(), Version { major: 1, minor: 0 });
+
+    // legacy witnesses don't have the magic bytes
+    assert_eq!(parse(&src[MAGIC.len()..]).unwrap(), parse(&src).unwrap());
+
+    let e = parse(b"ZKWX\x01").unwrap_err().to_string();
+    assert!(e.contains("bad magic bytes"), "{e}");
+    let e = parse(b"Z").unwrap_err().to_string();
+    assert!(e.contains("bad magic bytes"), "{e}");
+
+    // neither JSON nor gzip are mistaken for witnesses
+    for not_a_witness in [&br#"{"jsonrpc":"2.0"}"#[..], b"\x1F\x8B\x08\x00"] {
+        let e = parse(not_a_witness).unwrap_err().to_string();
+        assert!(e.contains("is this a witness?"), "{e}");
+    }
+}
+
+#[test]
+fn peek_first_instructions() {
+    let mut src = vec![0x01]; // header
+    for byte in 0..5 {
+        src.push(0x03); // hash
+        src.extend([byte; 32]);
+    }
+    let instructions = parse(&src).unwrap();
+
+    let (version, peeked) = peek_witness(&src, 3).unwrap();
+    assert_eq!(version, Version { major: 1, minor: 0 });
+    assert_eq!(peeked, instructions[..3]);
+    assert_eq!(peek_witness(&src, 10).unwrap().1, instructions[..]);
+
+    // Garbage after the peeked instructions isn't parsed.
+    src.truncate(1 + 3 * 33 + 1);
+    assert!(parse(&src).is_err());
+    assert_eq!(peek_witness(&src, 3).unwrap().1, instructions[..3]);
+}
+
+#[test]
+fn headerless_stream() {
+    let mut src = vec![0x03]; // hash
+    src.extend([0xAB; 32]);
+    let e = parse(&src).unwrap_err();
+    assert!(e.to_string().contains("is the header missing?"));
+
+    let mut src = vec![0x01, 0x03]; // header, then hash
+    src.extend([0xAB; 32]);
+    assert!(parse(&src).is_ok());
+}
+
+#[test]
+fn lenient_unknown_opcodes() {
+    let mut src = vec![0x01]; // header
+    src.extend(b"\x09\x42\xAB\xCD"); // unknown opcode, with a byte string
+    src.push(0x03); // hash
+    src.extend([0xAB; 32]);
+
+    assert!(parse(&src).is_err());
+
+    let (instructions, skipped) = parse_lenient(&src).unwrap();
+    assert_eq!(
+        instructions,
+        nunny::vec![Instruction::Hash {
+            raw_hash: [0xAB; 32]
+        }]
+    );
+    assert_eq!(skipped, [0x09]);
+}
+
+#[test]
+fn lenient_malformed_known_opcode() {
+    let mut src = vec![0x01, 0x03]; // header, then a truncated hash
+    src.extend([0xAB; 2]);
+
+    let e = parse_lenient(&src).unwrap_err().to_string();
+    assert!(e.starts_with("parse error at offset"), "{e}");
+    assert!(!e.contains("unknown opcode"), "{e}");
+}
+
+#[test]
+fn chunked_key() {
+    let nibbles = nunny::Vec::new([1, 2, 3, 4].map(|it| U4::new(it).unwrap()).to_vec()).unwrap();
+    do_test(b"\x43\x00\x12\x34", nibbles.clone(), key);
+    do_test(b"\x5F\x42\x00\x12\x41\x34\xFF", nibbles, key);
+    assert!(parse_complete(b"\x5F\x42\x00\x12", key).is_err());
+}
+
+#[test]
+fn empty_code() {
+    do_test(
+        b"\x04\x43\x60\x00\x56",
+        Instruction::Code {
+            raw_code: nunny::vec![0x60, 0x00, 0x56],
+        },
+        instruction,
+    );
+    do_test(b"\x04\x40", Instruction::EmptyCode, instruction);
+}
+
+#[test]
+fn code_with_hash_test_cases() {
+    // a CBOR byte string containing the STOP opcode
+    let code = b"\x41\x00";
+    let hash = keccak_hash::keccak([0x00]).0;
+
+    let mut src = code.to_vec();
+    src.extend(hash);
+    do_test(
+        &src,
+        Instruction::CodeWithHash {
+            raw_code: nunny::vec![0x00],
+            raw_hash: hash,
+        },
+        code_with_hash,
+    );
+
+    let mut src = code.to_vec();
+    src.extend([0xAB; 32]);
+    let e = parse_complete(&src, code_with_hash).unwrap_err();
+    assert!(e.to_string().contains("code hash mismatch"));
+}
+
+#[test]
+fn account_leaf_storage_root() {
+    let key = || nunny::vec![U4::new(1).unwrap()];
+
+    // storage follows as a separate node
+    do_test(
+        b"\x81\x01\x02",
+        Instruction::AccountLeaf {
+            key: key(),
+            nonce: None,
+            balance: None,
+            has_code: false,
+            has_storage: true,
+            storage_root: None,
+            incarnation: None,
+        },
+        account_leaf,
+    );
+
+    // storage root is inlined
+    let mut src = b"\x81\x01\x12".to_vec();
+    src.extend([0xAB; 32]);
+    do_test(
+        &src,
+        Instruction::AccountLeaf {
+            key: key(),
+            nonce: None,
+            balance: None,
+            has_code: false,
+            has_storage: true,
+            storage_root: Some([0xAB; 32]),
+            incarnation: None,
+        },
+        account_leaf,
+    );
+
+    // an inline storage root requires storage
+    let mut src = b"\x81\x01\x10".to_vec();
+    src.extend([0xAB; 32]);
+    assert!(parse_complete(&src, account_leaf).is_err());
+}
+
+#[test]
+fn account_leaf_flags() {
+    for (flags, has_code, has_storage) in [
+        (0b00, false, false),
+        (0b01, true, false),
+        (0b10, false, true),
+        (0b11, true, true),
+    ] {
+        let mut src = vec![0x81, 0x01, flags];
+        if has_code {
+            src.push(0x05); // code length
+        }
+        do_test(
+            &src,
+            Instruction::AccountLeaf {
+                key: nunny::vec![U4::new(1).unwrap()],
+                nonce: None,
+                balance: None,
+                has_code,
+                has_storage,
+                storage_root: None,
+                incarnation: None,
+            },
+            account_leaf,
+        );
+    }
+
+    // A CBOR `true` (0xF5) isn't a valid flags byte.
+    assert!(parse_complete(b"\x81\x01\xF5", account_leaf).is_err());
+}
+
+#[test]
+fn account_leaf_incarnation() {
+    // an incarnation follows the inline storage root
+    let mut src = b"\x81\x01\x32".to_vec();
+    src.extend([0xAB; 32]);
+    src.push(0x07);
+    do_test(
+        &src,
+        Instruction::AccountLeaf {
+            key: nunny::vec![U4::new(1).unwrap()],
+            nonce: None,
+            balance: None,
+            has_code: false,
+            has_storage: true,
+            storage_root: Some([0xAB; 32]),
+            incarnation: Some(7),
+        },
+        account_leaf,
+    );
+}
+
+#[test]
+fn disassemble_test_cases() {
+    let mut src = vec![0x01]; // header
+    src.extend(b"\x01\x82\x00\x11"); // extension
+    src.extend(b"\x00\x81\x01\x83\x01\x02\x03"); // leaf
+    src.extend(b"\x05\x81\x01\x0c\x05\x82\x03\x18\xe8"); // account leaf
+    src.extend(b"\x03"); // hash
+    src.extend([0xAB; 32]);
+    assert_eq!(
+        disassemble(&src).unwrap(),
+        [
+            "Extension(key=0x11)".to_string(),
+            "Leaf(key=0x1, value_len=3)".to_string(),
+            "AccountLeaf(key=0x1, nonce=5, balance=1000, code=false, storage=false)".to_string(),
+            format!("Hash(hash=0x{})", "ab".repeat(32)),
+        ]
+    );
+}
+
+#[test]
+fn chunked_witness() {
+    let mut src = vec![0x01]; // header
+    src.extend(b"\x01\x82\x00\x11"); // extension
+    src.extend(b"\x00\x81\x01\x83\x01\x02\x03"); // leaf
+    src.extend(b"\x03"); // hash
+    src.extend([0xAB; 32]);
+    let expected = parse(&src).unwrap();
+
+    assert_eq!(parse_chunks(&[&src]).unwrap(), expected);
+    for boundaries in [vec![1], vec![2, 7], vec![3, 4, 12, 20]] {
+        let mut chunks = vec![];
+        let mut rest = &src[..];
+        let mut consumed = 0;
+        for boundary in boundaries {
+            let (chunk, tail) = rest.split_at(boundary - consumed);
+            chunks.push(chunk.to_vec());
+            rest = tail;
+            consumed = boundary;
+        }
+        chunks.push(rest.to_vec());
+        assert_eq!(parse_chunks(&chunks).unwrap(), expected);
+    }
+    let bytes = src.chunks(1).collect::<Vec<_>>();
+    assert_eq!(parse_chunks(&bytes).unwrap(), expected);
+
+    // truncate the leaf, which starts at offset 5
+    let e = parse_chunks(&[&src[..3], &src[3..10]]).unwrap_err();
+    assert!(e.to_string().starts_with("parse error at offset 5:"), "{e}");
+}
+
+#[test]
+fn chunked_witness_bad_opcode() {
+    let mut src = vec![0x01]; // header
+    src.extend(b"\x01\x82\x00\x11"); // extension
+    src.push(0x42); // not an opcode
+    src.extend(b"\x03"); // hash
+    src.extend([0xAB; 32]);
+
+    // the bad opcode is reported in the chunk it's in, rather than being
+    // retried with each of the following chunks
+    let chunks = src.chunks(6).collect::<Vec<_>>();
+    let e = parse_chunks(&chunks).unwrap_err().to_string();
+    assert!(e.starts_with("parse error at offset 5:"), "{e}");
+    assert!(e.contains("unrecognised opcode"), "{e}");
+
+    // whereas an instruction which is merely cut short is retried
+    assert_eq!(
+        parse_chunks(&[&src[..3], &src[3..5]]).unwrap(),
+        parse(&src[..5]).unwrap()
+    );
+}
+
+#[test]
+fn lint_witness_test_cases() {
+    let mut src = vec![0x01]; // header
+    src.extend(b"\x00\x81\x01\x83\x01\x02\x03"); // leaf
+    lint_witness(&src, None).unwrap();
+
+    // truncate the leaf's CBOR value
+    src.pop();
+    let e = lint_witness(&src, None).unwrap_err();
+    assert!(e.to_string().contains("parse error at offset"));
+}
+
+#[test]
+fn lint_witness_max_balance() {
+    let mut src = vec![0x01]; // header
+    src.extend(b"\x05\x81\x01\x08\x82\x03\x18\xe8"); // account leaf, balance 1000
+    lint_witness(&src, None).unwrap();
+    lint_witness(&src, Some(U256::from(1000))).unwrap();
+
+    let e = lint_witness(&src, Some(U256::from(999))).unwrap_err();
+    assert!(
+        e.to_string()
+            .contains("balance 1000 exceeds the maximum of 999"),
+        "{e}"
+    );
+}
+
+#[test]
+fn witness_fixtures() {
+    for name in ["leaf_only", "account_with_storage", "branch"] {
+        let witness = crate::test_utils::load_witness(name);
+        parse(&witness).unwrap_or_else(|e| panic!("couldn't parse fixture {name}: {e}"));
+    }
+}
+
+#[test]
+fn encode_round_trip() {
+    for name in ["leaf_only", "account_with_storage", "branch"] {
+        let witness = crate::test_utils::load_witness(name);
+        let instructions = parse(&witness).unwrap();
+        let encoded = encode(read_version(&witness).unwrap(), &instructions);
+        assert_eq!(parse(&encoded).unwrap(), instructions, "{name}");
+    }
+
+    let nibble = U4::new(0xF).unwrap();
+    let instructions = nunny::vec![
+        Instruction::AccountLeaf {
+            key: nunny::vec![nibble, nibble, nibble],
+            nonce: Some(U256::MAX),
+            balance: Some(U256::from(0x0100)),
+            has_code: true,
+            has_storage: true,
+            storage_root: Some([0xAB; 32]),
+            incarnation: Some(300),
+        },
+        Instruction::Branch { mask: 0xFFFF },
+        Instruction::EmptyCode,
+        Instruction::NewTrie,
+    ];
+    let version = Version { major: 1, minor: 0 };
+    assert_eq!(
+        parse(&encode(version, &instructions)).unwrap(),
+        instructions
+    );
+}
+
+#[test]
+fn compressed_witness() {
+    for name in ["leaf_only", "account_with_storage", "branch"] {
+        let witness = crate::test_utils::load_witness(name);
+        let compressed = zstd::encode_all(witness.as_slice(), 0).unwrap();
+        assert!(compressed.starts_with(&ZSTD_MAGIC));
+        assert_eq!(
+            parse_compressed(&compressed).unwrap(),
+            parse(&witness).unwrap()
+        );
+        assert_eq!(
+            parse_compressed(&witness).unwrap(),
+            parse(&witness).unwrap()
+        );
+
+        let len = witness.len() as u64;
+        assert!(parse_compressed_with_limit(&compressed, len).is_ok());
+        let e = parse_compressed_with_limit(&compressed, len - 1).unwrap_err();
+        assert!(e.to_string().contains("decompresses to more than"), "{e}");
+    }
+}
+
+#[test]
+fn witness_footer() {
+    let mut src = vec![0x01]; // header
+    src.extend(b"\x00\x81\x01\x83\x01\x02\x03"); // leaf
+    let instructions = parse(&src).unwrap();
+    assert_eq!(
+        parse_with_footer(&src).unwrap(),
+        (instructions.clone(), None)
+    );
+
+    src.push(FOOTER);
+    src.extend([0xAB; 32]);
+    assert!(parse(&src).is_err());
+    assert_eq!(
+        parse_with_footer(&src).unwrap(),
+        (instructions, Some(vec![0xAB; 32]))
+    );
+}